@@ -0,0 +1,135 @@
+//! Throughput of the event stores under concurrent reads and writes, run with
+//! `cargo bench --bench store`.
+
+use std::time::{Duration, Instant};
+
+use baybridge::{
+    client::{Event, SetEvent},
+    crypto::{CryptoKey, Signed, encode::encode_verifying_key},
+    models::{Name, Value},
+    server::{
+        data_controller::DataController, memory_store::MemoryStore, sqlite_store::SqliteStore,
+    },
+};
+
+const KEYSPACES: usize = 16;
+const NAMES: usize = 64;
+const TASKS: usize = 16;
+const OPERATIONS_PER_TASK: usize = 500;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    let stores = [
+        (
+            "sqlite file",
+            DataController::new(SqliteStore::new(&dir.path().join("bench.sqlite"))?),
+        ),
+        (
+            "sqlite memory",
+            DataController::new(SqliteStore::in_memory()?),
+        ),
+        ("memory", DataController::new(MemoryStore::new())),
+    ];
+    for (label, controller) in stores {
+        let mut keys = (0..KEYSPACES)
+            .map(|_| CryptoKey::generate())
+            .collect::<Vec<_>>();
+        for (priority, key) in keys.iter_mut().enumerate() {
+            for name in 0..NAMES {
+                controller
+                    .insert_event(set_event(key, name, priority as u64))
+                    .await?;
+            }
+        }
+        let keys = keys.iter().map(|key| key.verifying()).collect::<Vec<_>>();
+
+        let reads = run_tasks(TASKS, |task| {
+            let controller = controller.clone();
+            let keys = keys.clone();
+            async move {
+                for i in 0..OPERATIONS_PER_TASK {
+                    let key = encode_verifying_key(&keys[(task + i) % KEYSPACES]);
+                    let name = format!("name{}", i % NAMES);
+                    controller.events_by_key_and_name(key, name).await?;
+                }
+                Ok(())
+            }
+        })
+        .await?;
+
+        let writes = run_tasks(TASKS, |_| {
+            let controller = controller.clone();
+            async move {
+                let mut key = CryptoKey::generate();
+                for i in 0..OPERATIONS_PER_TASK {
+                    controller
+                        .insert_event(set_event(&mut key, i % NAMES, i as u64))
+                        .await?;
+                }
+                Ok(())
+            }
+        })
+        .await?;
+
+        let mixed = run_tasks(TASKS, |task| {
+            let controller = controller.clone();
+            let keys = keys.clone();
+            async move {
+                let mut key = CryptoKey::generate();
+                for i in 0..OPERATIONS_PER_TASK {
+                    if i % 4 == 0 {
+                        controller
+                            .insert_event(set_event(&mut key, i % NAMES, i as u64))
+                            .await?;
+                    } else {
+                        let key = encode_verifying_key(&keys[(task + i) % KEYSPACES]);
+                        let name = format!("name{}", i % NAMES);
+                        controller.events_by_key_and_name(key, name).await?;
+                    }
+                }
+                Ok(())
+            }
+        })
+        .await?;
+
+        println!(
+            "{label:>14}: {:>9.0} reads/s {:>9.0} writes/s {:>9.0} mixed ops/s",
+            per_second(reads),
+            per_second(writes),
+            per_second(mixed)
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs `tasks` copies of a workload at once, returning how long they took together
+async fn run_tasks<F, Fut>(tasks: usize, workload: F) -> anyhow::Result<Duration>
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let start = Instant::now();
+    let handles = (0..tasks)
+        .map(|task| tokio::spawn(workload(task)))
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.await??;
+    }
+    Ok(start.elapsed())
+}
+
+fn per_second(elapsed: Duration) -> f64 {
+    (TASKS * OPERATIONS_PER_TASK) as f64 / elapsed.as_secs_f64()
+}
+
+fn set_event(key: &mut CryptoKey, name: usize, priority: u64) -> Signed<Event> {
+    key.sign(Event::Set(SetEvent::plain(
+        Name::new(format!("name{name}")),
+        Value::new(format!("value at {priority}").into_bytes()),
+        priority,
+        None,
+    )))
+}
@@ -0,0 +1,12 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub struct WriteConflict;
+
+impl fmt::Display for WriteConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Write conflicts with a newer event")
+    }
+}
+
+impl std::error::Error for WriteConflict {}
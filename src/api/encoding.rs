@@ -0,0 +1,104 @@
+use anyhow::{Result, anyhow};
+use bincode::config::standard;
+use serde::{Serialize, de::DeserializeOwned};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const BINCODE_CONTENT_TYPE: &str = "application/x-bincode";
+
+/// Most memory bincode may claim while decoding a request body. It counts the lengths of
+/// strings and collections against this before allocating them, so a small body cannot claim a
+/// huge allocation.
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Largest response body read after decompression, and most memory bincode may claim while
+/// decoding it. A response can hold every event of a peer.
+pub const MAX_RESPONSE_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Accept header sent by clients that prefer bincode but can read JSON from older nodes
+pub const PREFER_BINCODE_ACCEPT: &str = "application/x-bincode, application/json;q=0.5";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Bincode,
+}
+
+impl WireFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON_CONTENT_TYPE,
+            WireFormat::Bincode => BINCODE_CONTENT_TYPE,
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<WireFormat> {
+        let media_type = content_type.split(';').next()?.trim();
+        if media_type.eq_ignore_ascii_case(JSON_CONTENT_TYPE) {
+            Some(WireFormat::Json)
+        } else if media_type.eq_ignore_ascii_case(BINCODE_CONTENT_TYPE) {
+            Some(WireFormat::Bincode)
+        } else {
+            None
+        }
+    }
+
+    /// Picks the supported format with the highest quality value in an Accept header,
+    /// defaulting to JSON so clients that do not negotiate keep working
+    pub fn from_accept(accept: &str) -> WireFormat {
+        accept
+            .split(',')
+            .filter_map(|media_range| {
+                let mut parts = media_range.split(';');
+                let media_type = parts.next()?.trim();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .filter_map(|q| q.trim().parse::<f32>().ok())
+                    .next()
+                    .unwrap_or(1.0);
+                let format = match media_type {
+                    "*/*" | "application/*" => WireFormat::Json,
+                    media_type => WireFormat::from_content_type(media_type)?,
+                };
+                Some((format, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .fold(
+                None,
+                |best: Option<(WireFormat, f32)>, candidate| match best {
+                    Some(best) if best.1 >= candidate.1 => Some(best),
+                    _ => Some(candidate),
+                },
+            )
+            .map(|(format, _)| format)
+            .unwrap_or(WireFormat::Json)
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(value).map_err(Into::into),
+            WireFormat::Bincode => {
+                bincode::serde::encode_to_vec(value, standard()).map_err(Into::into)
+            }
+        }
+    }
+
+    /// Decodes a request body
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        self.decode_with_limit::<T, MAX_BODY_SIZE>(bytes)
+    }
+
+    /// Decodes a message, failing if bincode claims more than `LIMIT` bytes of memory
+    pub fn decode_with_limit<T: DeserializeOwned, const LIMIT: usize>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<T> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(bytes).map_err(Into::into),
+            WireFormat::Bincode => {
+                bincode::serde::decode_from_slice(bytes, standard().with_limit::<LIMIT>())
+                    .map(|(value, _)| value)
+                    .map_err(|e| anyhow!(e))
+            }
+        }
+    }
+}
@@ -1,4 +1,25 @@
+mod conflict;
+mod encoding;
+mod namespace;
+mod policy;
+mod rejection;
+mod snapshot;
 mod sync;
 
+pub use conflict::WriteConflict;
+pub use encoding::BINCODE_CONTENT_TYPE;
+pub use encoding::JSON_CONTENT_TYPE;
+pub use encoding::MAX_BODY_SIZE;
+pub use encoding::MAX_RESPONSE_SIZE;
+pub use encoding::PREFER_BINCODE_ACCEPT;
+pub use encoding::WireFormat;
+pub use namespace::MAX_KEYS_PER_QUERY;
+pub use namespace::NamespaceOrder;
+pub use namespace::NamespaceQuery;
+pub use policy::NodePolicy;
+pub use rejection::INSUFFICIENT_WORK;
+pub use rejection::Rejected;
+pub use snapshot::SNAPSHOT_CONTENT_TYPE;
+pub use snapshot::Snapshot;
 pub use sync::StateHash;
 pub use sync::SyncEvents;
@@ -0,0 +1,95 @@
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use ed25519_dalek::VerifyingKey;
+use serde::Deserialize;
+
+use crate::crypto::encode::decode_verifying_key;
+
+/// Most keys named in one namespace query, keeping its URL short and its database query
+/// within SQLite's limit on bound parameters
+pub const MAX_KEYS_PER_QUERY: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamespaceOrder {
+    /// Highest event priority first
+    #[default]
+    Priority,
+    /// Most recently received by the node first
+    Recency,
+}
+
+impl NamespaceOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NamespaceOrder::Priority => "priority",
+            NamespaceOrder::Recency => "recency",
+        }
+    }
+}
+
+impl FromStr for NamespaceOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(order: &str) -> Result<Self> {
+        match order {
+            "priority" => Ok(NamespaceOrder::Priority),
+            "recency" => Ok(NamespaceOrder::Recency),
+            order => bail!("Unknown namespace order {order}, expected priority or recency"),
+        }
+    }
+}
+
+/// Query parameters for `/namespace/:name`, applied per contributing keyspace
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NamespaceQuery {
+    /// Comma-separated encoded verifying keys to include, all keyspaces if omitted
+    pub keys: Option<String>,
+    pub limit: Option<usize>,
+    pub order: Option<NamespaceOrder>,
+    pub min_priority: Option<u64>,
+    /// Only events this node received at or after this unix timestamp
+    pub since: Option<u64>,
+    #[serde(default)]
+    pub count: bool,
+}
+
+impl NamespaceQuery {
+    pub fn verifying_keys(&self) -> Result<Option<Vec<VerifyingKey>>> {
+        let Some(keys) = &self.keys else {
+            return Ok(None);
+        };
+        let keys = keys.split(',').collect::<Vec<_>>();
+        if keys.len() > MAX_KEYS_PER_QUERY {
+            bail!("At most {MAX_KEYS_PER_QUERY} keys per namespace query");
+        }
+        keys.into_iter()
+            .map(decode_verifying_key)
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    pub fn query_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = Vec::new();
+        if let Some(keys) = &self.keys {
+            pairs.push(("keys", keys.clone()));
+        }
+        if let Some(limit) = self.limit {
+            pairs.push(("limit", limit.to_string()));
+        }
+        if let Some(order) = self.order {
+            pairs.push(("order", order.as_str().to_string()));
+        }
+        if let Some(min_priority) = self.min_priority {
+            pairs.push(("min_priority", min_priority.to_string()));
+        }
+        if let Some(since) = self.since {
+            pairs.push(("since", since.to_string()));
+        }
+        if self.count {
+            pairs.push(("count", "true".to_string()));
+        }
+        pairs
+    }
+}
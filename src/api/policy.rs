@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+/// Requirements a node advertises for the events it accepts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NodePolicy {
+    /// Minimum proof-of-work stamp, in bits, on small events; larger events need more
+    pub min_stamp_work: Option<u32>,
+}
@@ -0,0 +1,17 @@
+use std::fmt;
+
+/// Why a node refuses an event stamped with less work than it requires, so that clients can
+/// tell when to fetch its policy again
+pub const INSUFFICIENT_WORK: &str = "Insufficient proof of work";
+
+/// A node refused an event, so sending it again will not change the outcome
+#[derive(Debug)]
+pub struct Rejected(pub &'static str);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Event rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
@@ -0,0 +1,135 @@
+use std::io::Read;
+
+use anyhow::{Result, anyhow, bail};
+use bincode::config::standard;
+
+use crate::{client::Event, crypto::Signed};
+
+use super::StateHash;
+
+pub const SNAPSHOT_CONTENT_TYPE: &str = "application/x-baybridge-snapshot";
+
+const SNAPSHOT_MAGIC: &[u8] = b"baybridge-snapshot-v1\n";
+
+/// Largest decompressed snapshot a node will read
+const MAX_SNAPSHOT_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Most memory bincode may claim while decoding the events of a snapshot
+const MAX_DECODED_SIZE: usize = MAX_SNAPSHOT_SIZE as usize;
+
+/// All current events of a node at a state hash, for bootstrapping a new peer in one request.
+///
+/// Encoded as the magic, the state hash, a blake3 checksum of the compressed events, then the
+/// events encoded with bincode and compressed with zstd. Since the state hash is the hash of
+/// the encoded events, decoding checks both the transfer and the contents.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub state_hash: StateHash,
+    pub events: Vec<Signed<Event>>,
+}
+
+impl Snapshot {
+    /// A snapshot of events ordered like `EventStore::signed_events`
+    pub fn new(events: Vec<Signed<Event>>) -> Result<Snapshot> {
+        let encoded = bincode::encode_to_vec(&events, standard())?;
+        Ok(Snapshot {
+            state_hash: StateHash {
+                hash: blake3::hash(&encoded),
+            },
+            events,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let encoded = bincode::encode_to_vec(&self.events, standard())?;
+        let compressed = zstd::encode_all(encoded.as_slice(), 0)?;
+        let mut snapshot = Vec::with_capacity(SNAPSHOT_MAGIC.len() + 64 + compressed.len());
+        snapshot.extend_from_slice(SNAPSHOT_MAGIC);
+        snapshot.extend_from_slice(self.state_hash.hash.as_bytes());
+        snapshot.extend_from_slice(blake3::hash(&compressed).as_bytes());
+        snapshot.extend_from_slice(&compressed);
+        Ok(snapshot)
+    }
+
+    pub fn decode(snapshot: &[u8]) -> Result<Snapshot> {
+        let rest = snapshot
+            .strip_prefix(SNAPSHOT_MAGIC)
+            .ok_or_else(|| anyhow!("Not a baybridge snapshot"))?;
+        if rest.len() < 64 {
+            bail!("Snapshot is truncated");
+        }
+        let (state_hash, rest) = rest.split_at(32);
+        let (checksum, compressed) = rest.split_at(32);
+        if blake3::hash(compressed).as_bytes() != checksum {
+            bail!("Snapshot checksum does not match its contents");
+        }
+        let mut encoded = Vec::new();
+        zstd::Decoder::new(compressed)?
+            .take(MAX_SNAPSHOT_SIZE + 1)
+            .read_to_end(&mut encoded)?;
+        if encoded.len() as u64 > MAX_SNAPSHOT_SIZE {
+            bail!("Snapshot is larger than {MAX_SNAPSHOT_SIZE} bytes");
+        }
+        let state_hash = StateHash {
+            hash: blake3::Hash::from_bytes(state_hash.try_into()?),
+        };
+        if blake3::hash(&encoded) != state_hash.hash {
+            bail!("Snapshot events do not match its state hash");
+        }
+        let (events, _) =
+            bincode::decode_from_slice(&encoded, standard().with_limit::<MAX_DECODED_SIZE>())?;
+        Ok(Snapshot { state_hash, events })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        client::SetEvent,
+        crypto::CryptoKey,
+        models::{Name, Value},
+    };
+
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let mut key = CryptoKey::generate();
+        let events = ["first", "second"]
+            .map(|name| {
+                key.sign(Event::Set(SetEvent::plain(
+                    Name::new(name.to_string()),
+                    Value::new(name.as_bytes().to_vec()),
+                    1,
+                    None,
+                )))
+            })
+            .to_vec();
+        Snapshot::new(events).unwrap()
+    }
+
+    #[test]
+    fn decodes_encoded_snapshots() {
+        let snapshot = snapshot();
+        let decoded = Snapshot::decode(&snapshot.encode().unwrap()).unwrap();
+        assert_eq!(decoded.state_hash, snapshot.state_hash);
+        assert_eq!(
+            bincode::encode_to_vec(&decoded.events, standard()).unwrap(),
+            bincode::encode_to_vec(&snapshot.events, standard()).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_snapshots() {
+        let encoded = snapshot().encode().unwrap();
+        assert!(Snapshot::decode(&encoded[..SNAPSHOT_MAGIC.len() + 63]).is_err());
+
+        let mut corrupted = encoded.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(Snapshot::decode(&corrupted).is_err());
+
+        // A valid checksum over contents that do not hash to the claimed state
+        let mut wrong_state = encoded;
+        wrong_state[SNAPSHOT_MAGIC.len()] ^= 1;
+        assert!(Snapshot::decode(&wrong_state).is_err());
+    }
+}
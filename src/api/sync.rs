@@ -8,7 +8,7 @@ pub struct SyncEvents {
     pub events: Vec<Signed<Event>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub struct StateHash {
     pub hash: blake3::Hash,
 }
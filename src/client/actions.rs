@@ -1,26 +1,60 @@
-use std::{collections::HashMap, time::UNIX_EPOCH};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
 use crate::{
+    api::{
+        INSUFFICIENT_WORK, MAX_KEYS_PER_QUERY, NamespaceOrder, NamespaceQuery, Rejected,
+        WriteConflict,
+    },
     configuration::Configuration,
-    connectors::http::NamespaceResponse,
-    crdt::merge_events,
+    connectors::{connection::Connection, http::NamespaceResponse},
+    crdt::{CrdtKind, CrdtValue, combine_replicas, latest_event, merge_events, merge_replica},
     crypto::{
-        CryptoKey, Signed,
+        CryptoKey, PrivateKey, Signed, Signer,
+        delegation::Delegation,
         encode::{decode_verifying_key, encode_verifying_key},
+        envelope,
+        work::{MAX_STAMP_WORK, mint_stamp},
     },
-    models::{ContentBlock, Name, NamespaceValues, Value},
+    models::{Compression, ContentBlock, Encryption, Name, NamespaceValues, Value},
+    time::unix_timestamp,
 };
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow, bail};
 use bon::bon;
 use ed25519_dalek::VerifyingKey;
 use futures::future::join_all;
 use itertools::Itertools;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::{debug, warn};
+
+use super::{
+    AliasBook, CrdtEvent, CrdtState, DeletionEvent, EndorsementEvent, Event, LwwEntry,
+    NO_PREVIOUS_EVENT, REVOCATION_NAME, RevocationEvent, SUCCESSION_NAME, SetEvent,
+    SuccessionEvent, TrustPolicy,
+    aliases::published_alias_name,
+    content::{CHUNK_SIZE, ContentPointer, TREE_FANOUT, decode_block, encode_block},
+    endorsement_name,
+    replica::{Freshness, Replica},
+};
+
+/// Longest succession chain followed before giving up
+const MAX_SUCCESSION_DEPTH: usize = 32;
 
-use super::{DeletionEvent, Event, SetEvent};
+const MAX_CAS_ATTEMPTS: usize = 8;
 
 pub struct Actions {
     pub config: Configuration,
+    // Loaded on first use, since loading may prompt for a passphrase
+    identity: OnceCell<CryptoKey>,
+    private_key: OnceCell<PrivateKey>,
+    signer: Option<Arc<dyn Signer>>,
+    delegation: Option<Signed<Delegation>>,
+    replica: OnceCell<Option<Replica>>,
+    // Stamp work each server requires by url, kept until it rejects a write for too little
+    stamp_policies: Mutex<HashMap<String, Option<u32>>>,
 }
 
 pub enum Expiry {
@@ -28,10 +62,84 @@ pub enum Expiry {
     Ttl(Duration),
 }
 
+pub struct Reading {
+    pub value: Value,
+    pub freshness: Freshness,
+}
+
 #[bon]
 impl Actions {
     pub fn new(config: Configuration) -> Actions {
-        Actions { config }
+        Actions {
+            config,
+            identity: OnceCell::new(),
+            private_key: OnceCell::new(),
+            signer: None,
+            delegation: None,
+            replica: OnceCell::new(),
+            stamp_policies: Mutex::default(),
+        }
+    }
+
+    /// Acts as the given identity instead of loading the configured one for each action
+    pub fn with_identity(config: Configuration, identity: CryptoKey) -> Actions {
+        Actions {
+            config,
+            identity: OnceCell::new_with(Some(identity)),
+            private_key: OnceCell::new(),
+            signer: None,
+            delegation: None,
+            replica: OnceCell::new(),
+            stamp_policies: Mutex::default(),
+        }
+    }
+
+    /// Signs events with an external signer, such as an ssh-agent, instead of the identity's key.
+    /// Encrypted values still need the identity's key or the private passphrase.
+    pub fn with_signer(mut self, signer: impl Signer + 'static) -> Actions {
+        self.signer = Some(Arc::new(signer));
+        self
+    }
+
+    /// Writes sets and deletes into the keyspace of the owner who signed the delegation
+    pub fn with_delegation(mut self, delegation: Signed<Delegation>) -> Actions {
+        self.delegation = Some(delegation);
+        self
+    }
+
+    async fn crypto_key(&self) -> Result<&CryptoKey> {
+        self.identity
+            .get_or_try_init(|| async {
+                if self.signer.is_none() {
+                    return CryptoKey::from_config(&self.config).await;
+                }
+                // Never generate a local key in place of the external signer's
+                let identity = self.config.identity_name();
+                CryptoKey::from_identity(&self.config, &identity)
+                    .await
+                    .context("Encrypted values need the identity's key or the private passphrase")
+            })
+            .await
+    }
+
+    /// The local replica, opened on first use. Without one the client only works online.
+    async fn replica(&self) -> Option<&Replica> {
+        self.replica
+            .get_or_init(|| async {
+                Replica::open(&self.config)
+                    .await
+                    .inspect_err(|e| warn!("Not keeping a local replica: {e}"))
+                    .ok()
+            })
+            .await
+            .as_ref()
+    }
+
+    async fn signer(&self) -> Result<Arc<dyn Signer>> {
+        match &self.signer {
+            Some(signer) => Ok(signer.clone()),
+            None => Ok(Arc::new(self.crypto_key().await?.clone())),
+        }
     }
 
     #[builder]
@@ -41,14 +149,24 @@ impl Actions {
         value: Value,
         expiry: Option<Expiry>,
         priority: Option<u64>,
+        compression: Option<Compression>,
+        /// Encrypts the value so only these keys (and the writer) can read it
+        #[builder(default)]
+        recipients: Vec<VerifyingKey>,
+        /// Encrypts the value so only the writer can read it
+        #[builder(default)]
+        private: bool,
+        /// Replaces the name with its HMAC so replicas cannot read it
+        #[builder(default)]
+        hide_name: bool,
+        /// Fails with `WriteConflict` unless the latest event for the name has this hash
+        expected_previous: Option<[u8; 32]>,
     ) -> Result<()> {
-        let mut crypto_key = CryptoKey::from_config(&self.config).await;
-
-        let now = SystemTime::now();
-        let since_epoch = now
-            .duration_since(UNIX_EPOCH)
-            .expect("Error finding current epoch for expiry cleanup");
-        let unix_timestamp = since_epoch.as_secs();
+        if private && !recipients.is_empty() {
+            bail!("A value cannot be both private and encrypted to recipients");
+        }
+        let signer = self.signer().await?;
+        let unix_timestamp = unix_timestamp();
 
         let priority = match priority {
             Some(priority) => priority,
@@ -63,76 +181,893 @@ impl Actions {
             None => None,
         };
 
+        let compression = compression.unwrap_or_default();
+        let value = value.compress(compression)?;
+        let name = if hide_name {
+            self.private_key().await?.hide_name(&name)
+        } else {
+            name
+        };
+        let (value, encryption) = if private {
+            let encrypted = self.private_key().await?.encrypt(&value)?;
+            (encrypted, Encryption::Private)
+        } else if recipients.is_empty() {
+            (value, Encryption::None)
+        } else {
+            let recipients = recipients
+                .into_iter()
+                .chain(std::iter::once(signer.verifying_key()))
+                .unique()
+                .collect::<Vec<_>>();
+            let sealed = envelope::seal(value.as_bytes(), &recipients)?;
+            (Value::new(sealed), Encryption::Recipients)
+        };
+
         let event = Event::Set(SetEvent {
             name,
             value,
             priority,
             expires_at,
+            compression,
+            encryption,
+            expected_previous,
         });
-        let signed = crypto_key.sign(event);
+        let signed = self.sign_delegated(signer.as_ref(), event).await?;
+        self.publish(signed).await
+    }
 
-        let set_futures = self
-            .config
-            .get_connections()
+    pub async fn delete(&self, name: Name) -> Result<()> {
+        let signer = self.signer().await?;
+
+        // Outrank the event being deleted even if it was written within the same second
+        let previous_priority = self
+            .fetch_events(&self.keyspace().await?, &name)
+            .await
+            .iter()
+            .map(|event| event.inner.priority())
+            .max();
+        let priority = next_priority(previous_priority)?;
+        let event = Event::Delete(DeletionEvent { name, priority });
+        let signed = self.sign_delegated(signer.as_ref(), event).await?;
+        self.publish(signed).await
+    }
+
+    async fn sign_delegated(&self, signer: &dyn Signer, event: Event) -> Result<Signed<Event>> {
+        let signed = signer.sign(event).await?;
+        Ok(match &self.delegation {
+            Some(delegation) => signed.with_delegation(delegation.clone()),
+            None => signed,
+        })
+    }
+
+    /// Signs a certificate letting another key write to this identity's keyspace
+    #[builder]
+    pub async fn delegate(
+        &self,
+        delegate: &VerifyingKey,
+        /// Restricts the delegate to names starting with this prefix
+        name_prefix: Option<String>,
+        /// Unix timestamp after which the delegate's writes are rejected
+        expires_at: Option<u64>,
+    ) -> Result<Signed<Delegation>> {
+        self.signer()
+            .await?
+            .sign(Delegation {
+                delegate: delegate.to_bytes(),
+                name_prefix,
+                expires_at,
+            })
+            .await
+    }
+
+    /// Endorses a new key as the successor of this identity's keyspace
+    pub async fn succeed(&self, successor: &VerifyingKey) -> Result<()> {
+        let event = Event::Succession(SuccessionEvent {
+            successor: successor.to_bytes(),
+            priority: unix_timestamp(),
+        });
+        let signed = self.signer().await?.sign(event).await?;
+        self.publish(signed).await
+    }
+
+    /// Marks this identity's events with a priority after `revoked_after` (default now) as untrusted
+    pub async fn revoke(&self, revoked_after: Option<u64>) -> Result<()> {
+        let priority = unix_timestamp();
+        let event = Event::Revocation(RevocationEvent {
+            revoked_after: revoked_after.unwrap_or(priority),
+            priority,
+        });
+        let signed = self.signer().await?.sign(event).await?;
+        self.publish(signed).await
+    }
+
+    /// Vouches for a key, admitting it to namespaces read with a policy trusting this identity
+    pub async fn endorse(&self, endorsed: &VerifyingKey) -> Result<()> {
+        self.publish_endorsement(endorsed, false).await
+    }
+
+    pub async fn withdraw_endorsement(&self, endorsed: &VerifyingKey) -> Result<()> {
+        self.publish_endorsement(endorsed, true).await
+    }
+
+    async fn publish_endorsement(&self, endorsed: &VerifyingKey, withdrawn: bool) -> Result<()> {
+        let signer = self.signer().await?;
+        // Outrank the previous endorsement even within the same second
+        let previous_priority = self
+            .fetch_events(&signer.verifying_key(), &endorsement_name(endorsed))
+            .await
+            .iter()
+            .map(|event| event.inner.priority())
+            .max();
+        let priority = next_priority(previous_priority)?;
+        let event = Event::Endorsement(EndorsementEvent::new(endorsed, priority, withdrawn));
+        let signed = signer.sign(event).await?;
+        self.publish(signed).await
+    }
+
+    async fn endorsements_of(
+        &self,
+        verifying_key: &VerifyingKey,
+        endorsers: &[VerifyingKey],
+    ) -> Vec<Signed<Event>> {
+        let queries = endorsers
+            .chunks(MAX_KEYS_PER_QUERY)
+            .map(|keys| NamespaceQuery {
+                keys: Some(encode_keys(keys)),
+                ..Default::default()
+            })
+            .collect_vec();
+        let name = endorsement_name(verifying_key);
+        let namespace_futures = queries
+            .iter()
+            .cartesian_product(self.config.get_connections())
+            .map(|(query, conn)| conn.namespace(name.as_str(), query));
+        join_all(namespace_futures)
+            .await
+            .into_iter()
+            .filter_map(Result::ok)
+            .flat_map(|response| response.events.into_iter())
+            .collect()
+    }
+
+    /// Events written while no server is reachable are queued in the local replica, except
+    /// conditional writes, which only a server can check
+    async fn publish(&self, signed: Signed<Event>) -> Result<()> {
+        self.flush().await?;
+        let accepted = self.send(signed.clone()).await?;
+        if !accepted && matches!(&signed.inner, Event::Set(set) if set.expected_previous.is_some())
+        {
+            bail!(
+                "No server reachable to check the conditional write to {}",
+                signed.inner.name()
+            );
+        }
+        let Some(replica) = self.replica().await else {
+            return Ok(());
+        };
+        if accepted {
+            replica.insert(&signed).await
+        } else {
+            warn!(
+                "No server reachable, queueing {} until one is",
+                signed.inner.name()
+            );
+            replica.enqueue(&signed).await
+        }
+    }
+
+    /// Conditional writes that now conflict are dropped
+    pub async fn flush(&self) -> Result<usize> {
+        let Some(replica) = self.replica().await else {
+            return Ok(0);
+        };
+        let mut flushed = 0;
+        for (id, signed) in replica.queued().await? {
+            match self.send(signed.clone()).await {
+                Ok(true) => {
+                    flushed += 1;
+                    replica.dequeue(id).await?;
+                }
+                Ok(false) => break,
+                Err(e) if e.is::<WriteConflict>() => {
+                    warn!(
+                        "Dropping queued write to {}, which conflicts with a newer one",
+                        signed.inner.name()
+                    );
+                    replica.discard(id).await?;
+                }
+                Err(e) if is_rejection(&e) => {
+                    warn!(
+                        "Dropping queued write to {}, which servers reject: {e}",
+                        signed.inner.name()
+                    );
+                    replica.discard(id).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if flushed > 0 {
+            debug!("Sent {} queued events", flushed);
+        }
+        Ok(flushed)
+    }
+
+    /// Returns whether any server accepted the event, failing if none did and one rejected it
+    async fn send(&self, signed: Signed<Event>) -> Result<bool> {
+        let connections = self.config.get_connections();
+        let mut results = self
+            .send_to(connections.iter().collect(), signed.clone())
+            .await?;
+        let underworked = (0..connections.len())
+            .filter(|index| results[*index].as_ref().is_err_and(is_insufficient_work))
+            .collect::<Vec<_>>();
+        if !underworked.is_empty() {
+            // These servers raised the work they require since their policies were fetched
+            {
+                let mut stamp_policies = self.stamp_policies.lock().unwrap();
+                for index in &underworked {
+                    stamp_policies.remove(connections[*index].url());
+                }
+            }
+            let retried = self
+                .send_to(
+                    underworked
+                        .iter()
+                        .map(|index| &connections[*index])
+                        .collect(),
+                    signed,
+                )
+                .await?;
+            for (index, result) in underworked.into_iter().zip(retried) {
+                results[index] = result;
+            }
+        }
+        // Once one server has taken a conditional write it is committed, and sync brings the
+        // servers that refused it up to date, so retrying would apply the update again
+        if results.iter().any(Result::is_ok) {
+            return Ok(true);
+        }
+        let conflicted = results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .any(|e| e.is::<WriteConflict>());
+        if conflicted {
+            return Err(WriteConflict.into());
+        }
+        match results
+            .into_iter()
+            .filter_map(Result::err)
+            .find(is_rejection)
+        {
+            Some(rejection) => Err(rejection.context("No server accepted the event")),
+            None => Ok(false),
+        }
+    }
+
+    /// Stamps an event with the work the servers require and sends it to each of them
+    async fn send_to(
+        &self,
+        connections: Vec<&Connection>,
+        signed: Signed<Event>,
+    ) -> Result<Vec<Result<()>>> {
+        let signed = self.stamp(&connections, signed).await?;
+        let set_futures = connections
             .iter()
             .map(|connection| connection.set(signed.clone()));
-        join_all(set_futures).await;
+        Ok(join_all(set_futures).await)
+    }
 
-        Ok(())
+    async fn stamp(
+        &self,
+        connections: &[&Connection],
+        signed: Signed<Event>,
+    ) -> Result<Signed<Event>> {
+        let min_work_futures = connections
+            .iter()
+            .map(|connection| self.min_stamp_work(connection));
+        let (work, excessive): (Vec<_>, Vec<_>) = join_all(min_work_futures)
+            .await
+            .into_iter()
+            .flatten()
+            .map(|min_stamp_work| signed.inner.required_stamp_work(min_stamp_work))
+            .partition(|work| *work <= MAX_STAMP_WORK);
+        if let Some(excessive) = excessive.into_iter().max() {
+            // Servers demanding more reject the event, but the others can still accept it
+            warn!(
+                "Not minting the {} bits of stamp work a server requires, more than {}",
+                excessive, MAX_STAMP_WORK
+            );
+        }
+        let Some(work) = work.into_iter().max() else {
+            return Ok(signed);
+        };
+        let event_hash = signed.inner.hash();
+        debug!("Minting a stamp with {} bits of work", work);
+        let stamp = tokio::task::spawn_blocking(move || mint_stamp(&event_hash, work)).await?;
+        Ok(signed.with_stamp(stamp))
     }
 
-    pub async fn delete(&self, name: Name) -> Result<()> {
-        let mut crypto_key = CryptoKey::from_config(&self.config).await;
+    /// The stamp work a server requires, from its policy fetched on the first write to it
+    async fn min_stamp_work(&self, connection: &Connection) -> Option<u32> {
+        if let Some(min_stamp_work) = self.stamp_policies.lock().unwrap().get(connection.url()) {
+            return *min_stamp_work;
+        }
+        let min_stamp_work = connection.policy().await.ok()?.min_stamp_work;
+        self.stamp_policies
+            .lock()
+            .unwrap()
+            .insert(connection.url().to_string(), min_stamp_work);
+        min_stamp_work
+    }
 
-        let event = Event::Delete(DeletionEvent { name, priority: 0 });
-        let signed = crypto_key.sign(event);
+    /// The keyspace written to: the delegating owner's if acting under a delegation
+    async fn keyspace(&self) -> Result<VerifyingKey> {
+        match &self.delegation {
+            Some(delegation) => delegation
+                .verifying_key()
+                .ok_or_else(|| anyhow!("Delegation is signed with an invalid key")),
+            None => Ok(self.signer().await?.verifying_key()),
+        }
+    }
 
-        let set_futures = self
+    /// Adds to a counter, creating it if needed. Counters are grow-only unless created with
+    /// `decrementable` or a negative amount.
+    #[builder]
+    pub async fn increment(
+        &self,
+        name: Name,
+        #[builder(default = 1)] amount: i64,
+        #[builder(default)] decrementable: bool,
+    ) -> Result<()> {
+        self.update_crdt(name, |state| {
+            let state = match state {
+                Some(state) => state,
+                None if decrementable || amount < 0 => CrdtState::Counter {
+                    increments: 0,
+                    decrements: 0,
+                },
+                None => CrdtState::GrowCounter(0),
+            };
+            match state {
+                CrdtState::GrowCounter(_) if amount < 0 => {
+                    bail!("Cannot decrement a grow-only counter")
+                }
+                CrdtState::GrowCounter(count) => Ok(CrdtState::GrowCounter(
+                    count.saturating_add(amount.unsigned_abs()),
+                )),
+                CrdtState::Counter {
+                    increments,
+                    decrements,
+                } if amount < 0 => Ok(CrdtState::Counter {
+                    increments,
+                    decrements: decrements.saturating_add(amount.unsigned_abs()),
+                }),
+                CrdtState::Counter {
+                    increments,
+                    decrements,
+                } => Ok(CrdtState::Counter {
+                    increments: increments.saturating_add(amount.unsigned_abs()),
+                    decrements,
+                }),
+                state => bail!("Name holds a {}, not a counter", state.kind()),
+            }
+        })
+        .await
+    }
+
+    pub async fn set_add(&self, name: Name, element: String) -> Result<()> {
+        self.update_crdt(name, |state| {
+            let (mut adds, removes) = match state {
+                Some(CrdtState::OrSet { adds, removes }) => (adds, removes),
+                Some(state) => bail!("Name holds a {}, not a set", state.kind()),
+                None => Default::default(),
+            };
+            adds.entry(element).or_default().insert(rand::random());
+            Ok(CrdtState::OrSet { adds, removes })
+        })
+        .await
+    }
+
+    /// Removes an element as added by any contributor so far; concurrent adds survive
+    pub async fn set_remove(&self, name: Name, element: String) -> Result<()> {
+        let observed_tags = self
+            .crdt_states(&name)
+            .await
+            .into_iter()
+            .filter_map(|(state, _)| match state {
+                CrdtState::OrSet { mut adds, .. } => adds.remove(&element),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        self.update_crdt(name, |state| {
+            let (adds, mut removes) = match state {
+                Some(CrdtState::OrSet { adds, removes }) => (adds, removes),
+                Some(state) => bail!("Name holds a {}, not a set", state.kind()),
+                None => Default::default(),
+            };
+            removes.extend(observed_tags);
+            Ok(CrdtState::OrSet { adds, removes })
+        })
+        .await
+    }
+
+    pub async fn map_put(&self, name: Name, key: String, value: Value) -> Result<()> {
+        self.update_map(name, key, Some(value)).await
+    }
+
+    pub async fn map_remove(&self, name: Name, key: String) -> Result<()> {
+        self.update_map(name, key, None).await
+    }
+
+    async fn update_map(&self, name: Name, key: String, value: Option<Value>) -> Result<()> {
+        // Outrank the newest entry any contributor has written for the key
+        let newest_timestamp = self
+            .crdt_states(&name)
+            .await
+            .iter()
+            .filter_map(|(state, _)| match state {
+                CrdtState::LwwMap(entries) => entries.get(&key).map(|entry| entry.timestamp),
+                _ => None,
+            })
+            .max();
+        let timestamp = next_priority(newest_timestamp)?;
+        self.update_crdt(name, |state| {
+            let mut entries = match state {
+                Some(CrdtState::LwwMap(entries)) => entries,
+                Some(state) => bail!("Name holds a {}, not a map", state.kind()),
+                None => Default::default(),
+            };
+            entries.insert(key, LwwEntry { value, timestamp });
+            Ok(CrdtState::LwwMap(entries))
+        })
+        .await
+    }
+
+    async fn update_crdt<F>(&self, name: Name, update: F) -> Result<()>
+    where
+        F: FnOnce(Option<CrdtState>) -> Result<CrdtState>,
+    {
+        let keyspace = self.keyspace().await?;
+        let events = self.fetch_events(&keyspace, &name).await;
+        let (state, previous_priority) = match merge_replica(&events) {
+            Some((state, priority)) => (Some(state), Some(priority)),
+            None => (None, None),
+        };
+        let state = update(state)?;
+        let priority = next_priority(previous_priority)?;
+        let event = Event::Crdt(CrdtEvent {
+            name,
+            priority,
+            state,
+        });
+        let signer = self.signer().await?;
+        let signed = self.sign_delegated(signer.as_ref(), event).await?;
+        self.publish(signed).await
+    }
+
+    #[builder]
+    pub async fn crdt_value(
+        &self,
+        name: &Name,
+        from: Option<&str>,
+        /// Only combines states of this kind, rather than failing when contributors disagree
+        kind: Option<CrdtKind>,
+    ) -> Result<CrdtValue> {
+        let states = match from {
+            Some(verifying_key) => {
+                let verifying_key = self.resolve_verifying_key(verifying_key).await?;
+                let events = self.fetch_events(&verifying_key, name).await;
+                merge_replica(&events).into_iter().collect()
+            }
+            None => self.crdt_states(name).await,
+        };
+        combine_replicas(states.into_iter().map(|(state, _)| state).collect(), kind)?
+            .ok_or_else(|| anyhow!("No CRDT value found for {}", name))
+    }
+
+    async fn crdt_states(&self, name: &Name) -> Vec<(CrdtState, u64)> {
+        let query = NamespaceQuery::default();
+        let namespace_futures = self
             .config
             .get_connections()
             .iter()
-            .map(|conn| conn.set(signed.clone()));
-        join_all(set_futures).await;
-        Ok(())
+            .map(|conn| conn.namespace(name.as_str(), &query));
+        let events = join_all(namespace_futures)
+            .await
+            .into_iter()
+            .filter_map(Result::ok)
+            .flat_map(|response| response.events.into_iter())
+            .filter_map(|event| Some((event.verified_keyspace()?, event)))
+            .into_group_map();
+        events
+            .values()
+            .filter_map(|events| merge_replica(events))
+            .sorted_by_key(|(_, priority)| std::cmp::Reverse(*priority))
+            .collect()
     }
 
-    pub async fn get(&self, verifying_key_string: &str, name: &Name) -> Result<Value> {
-        let verifying_key = decode_verifying_key(verifying_key_string)?;
-        let relevant_events_futures = self
+    /// `update` gets None for an unset or deleted name, and is retried with the latest value if
+    /// another write lands in between
+    pub async fn cas<F>(&self, name: Name, mut update: F) -> Result<Value>
+    where
+        F: FnMut(Option<Value>) -> Result<Value>,
+    {
+        let keyspace = self.keyspace().await?;
+        for attempt in 1..=MAX_CAS_ATTEMPTS {
+            let events = self.fetch_events(&keyspace, &name).await;
+            let previous = latest_event(&events);
+            let current = match previous.map(|previous| &previous.inner) {
+                Some(Event::Set(event)) => Some(self.decode_value(event).await?),
+                _ => None,
+            };
+            let value = update(current)?;
+
+            // The new event must outrank the one it replaces even within the same second
+            let priority = next_priority(previous.map(|previous| previous.inner.priority()))?;
+            let expected_previous = previous
+                .map(|previous| previous.inner.hash())
+                .unwrap_or(NO_PREVIOUS_EVENT);
+            let result = self
+                .set()
+                .name(name.clone())
+                .value(value.clone())
+                .priority(priority)
+                .expected_previous(expected_previous)
+                .call()
+                .await;
+            match result {
+                Ok(()) => return Ok(value),
+                Err(e) if e.is::<WriteConflict>() => {
+                    debug!("Conflict writing {} on attempt {}, retrying", name, attempt)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(anyhow!(
+            "Gave up writing {} after {} conflicting attempts",
+            name,
+            MAX_CAS_ATTEMPTS
+        ))
+    }
+
+    #[builder]
+    pub async fn get(
+        &self,
+        verifying_key: &str,
+        name: &Name,
+        /// Reads from the newest key that succeeded this one instead
+        #[builder(default)]
+        follow_succession: bool,
+    ) -> Result<Value> {
+        let reading = self
+            .read()
+            .verifying_key(verifying_key)
+            .name(name)
+            .follow_succession(follow_succession)
+            .call()
+            .await?;
+        Ok(reading.value)
+    }
+
+    #[builder]
+    pub async fn read(
+        &self,
+        verifying_key: &str,
+        name: &Name,
+        /// Reads from the newest key that succeeded this one instead
+        #[builder(default)]
+        follow_succession: bool,
+    ) -> Result<Reading> {
+        if let Err(e) = self.flush().await {
+            warn!("Failed to send queued events: {e}");
+        }
+        let verifying_key = self.resolve_verifying_key(verifying_key).await?;
+        let verifying_key = if follow_succession {
+            self.latest_successor(&verifying_key).await?
+        } else {
+            verifying_key
+        };
+        let revoked_after = self.revoked_after(&verifying_key).await;
+        let (events, freshness) = self.fetch_events_with_freshness(&verifying_key, name).await;
+        let combined_events = events
+            .into_iter()
+            .filter(|event| is_trusted(event, revoked_after))
+            .collect();
+        // TODO: filter by ttl
+        match merge_events(combined_events) {
+            Some(event) => Ok(Reading {
+                value: self.decode_value(&event).await?,
+                freshness,
+            }),
+            None => Err(anyhow::anyhow!("Value not found")),
+        }
+    }
+
+    /// The latest set event of every name in a keyspace, sorted by name, leaving out deleted
+    /// names and writes made after the key was revoked
+    pub async fn list(&self, verifying_key: &VerifyingKey) -> Result<Vec<(Name, SetEvent)>> {
+        let revoked_after = self.revoked_after(verifying_key).await;
+        let keyspace_futures = self
             .config
             .get_connections()
             .iter()
-            .map(|conn| conn.get(&verifying_key, name))
+            .map(|conn| conn.keyspace(verifying_key));
+        let responses = join_all(keyspace_futures)
+            .await
+            .into_iter()
+            .filter_map(Result::ok)
             .collect::<Vec<_>>();
-        let combined_events = join_all(relevant_events_futures)
+        let remote = (!responses.is_empty()).then(|| {
+            responses
+                .into_iter()
+                .flat_map(|response| response.events.into_iter())
+                .filter(|event| event.verify_event(verifying_key))
+                .collect()
+        });
+        let (events, freshness) = self.combine_with_replica(verifying_key, None, remote).await;
+        if freshness != Freshness::Live && events.is_empty() {
+            bail!("No server returned the keyspace");
+        }
+        let events = events
+            .into_iter()
+            .filter(|event| is_trusted(event, revoked_after))
+            .into_group_map_by(|event| event.inner.name().to_string());
+        Ok(events
+            .into_iter()
+            .filter_map(|(name, events)| Some((Name::new(name), merge_events(events)?)))
+            .sorted_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()))
+            .collect())
+    }
+
+    /// Decodes a verifying key, or looks it up if it is an alias in the local alias book
+    pub async fn resolve_verifying_key(&self, alias_or_key: &str) -> Result<VerifyingKey> {
+        AliasBook::load(&self.config).await?.resolve(alias_or_key)
+    }
+
+    /// Publishes an alias as a signed value in this identity's keyspace so others can fetch it
+    pub async fn publish_alias(&self, alias: &str, verifying_key: &VerifyingKey) -> Result<()> {
+        self.set()
+            .name(published_alias_name(alias))
+            .value(Value::new(encode_verifying_key(verifying_key).into_bytes()))
+            .call()
+            .await
+    }
+
+    /// Reads an alias published by another key, verified against that key's signature
+    pub async fn fetch_alias(&self, owner: &str, alias: &str) -> Result<VerifyingKey> {
+        let value = self
+            .get()
+            .verifying_key(owner)
+            .name(&published_alias_name(alias))
+            .call()
+            .await?;
+        decode_verifying_key(std::str::from_utf8(value.as_bytes())?)
+    }
+
+    /// Current and superseded events retained by the servers for an address, oldest first,
+    /// keeping only those signed for the keyspace
+    pub async fn history(&self, verifying_key: &str, name: &Name) -> Result<Vec<Signed<Event>>> {
+        let verifying_key = self.resolve_verifying_key(verifying_key).await?;
+        let history_futures = self
+            .config
+            .get_connections()
+            .iter()
+            .map(|conn| conn.history(&verifying_key, name));
+        let events = join_all(history_futures)
             .await
             .into_iter()
             .filter_map(Result::ok)
             .flat_map(|events| events.events.into_iter())
+            .filter(|event| event.verify_event(&verifying_key))
+            .unique_by(|event| event.signature().map(|signature| signature.to_bytes()))
+            .sorted_by_key(|event| event.inner.priority())
             .collect();
-        // TODO: filter by ttl and verify events
-        let value = merge_events(combined_events);
-        match value {
-            Some(value) => Ok(value),
-            None => Err(anyhow::anyhow!("Value not found")),
+        Ok(events)
+    }
+
+    async fn fetch_events(&self, verifying_key: &VerifyingKey, name: &Name) -> Vec<Signed<Event>> {
+        self.fetch_events_with_freshness(verifying_key, name)
+            .await
+            .0
+    }
+
+    async fn fetch_events_with_freshness(
+        &self,
+        verifying_key: &VerifyingKey,
+        name: &Name,
+    ) -> (Vec<Signed<Event>>, Freshness) {
+        let relevant_events_futures = self
+            .config
+            .get_connections()
+            .iter()
+            .map(|conn| conn.get(verifying_key, name))
+            .collect::<Vec<_>>();
+        let responses = join_all(relevant_events_futures)
+            .await
+            .into_iter()
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+        let remote = (!responses.is_empty()).then(|| {
+            responses
+                .into_iter()
+                .flat_map(|events| events.events.into_iter())
+                .filter(|event| event.verify_event(verifying_key))
+                .collect()
+        });
+        self.combine_with_replica(verifying_key, Some(name), remote)
+            .await
+    }
+
+    async fn combine_with_replica(
+        &self,
+        verifying_key: &VerifyingKey,
+        name: Option<&Name>,
+        remote: Option<Vec<Signed<Event>>>,
+    ) -> (Vec<Signed<Event>>, Freshness) {
+        let freshness = match remote {
+            Some(_) => Freshness::Live,
+            None => Freshness::Stale { synced_at: None },
+        };
+        let Some(replica) = self.replica().await else {
+            return (remote.unwrap_or_default(), freshness);
+        };
+        match replica.combine(verifying_key, name, remote.clone()).await {
+            Ok(combined) => combined,
+            Err(e) => {
+                warn!("Failed to read the local replica: {e}");
+                (remote.unwrap_or_default(), freshness)
+            }
+        }
+    }
+
+    /// Follows succession records from a key to the newest key that replaced it
+    pub async fn latest_successor(&self, verifying_key: &VerifyingKey) -> Result<VerifyingKey> {
+        let mut current = *verifying_key;
+        let mut visited = HashSet::from([current]);
+        for _ in 0..MAX_SUCCESSION_DEPTH {
+            let revoked_after = self.revoked_after(&current).await;
+            let successor = self
+                .fetch_events(&current, &SUCCESSION_NAME)
+                .await
+                .into_iter()
+                .filter(|event| is_trusted(event, revoked_after))
+                .filter_map(|event| match event.inner {
+                    Event::Succession(succession) => Some(succession),
+                    _ => None,
+                })
+                .max_by_key(|succession| succession.priority)
+                .and_then(|succession| succession.successor());
+            match successor {
+                Some(successor) if visited.insert(successor) => current = successor,
+                _ => return Ok(current),
+            }
+        }
+        Err(anyhow!(
+            "Succession chain is longer than {MAX_SUCCESSION_DEPTH} keys"
+        ))
+    }
+
+    /// The earliest revocation published by a key, if any
+    async fn revoked_after(&self, verifying_key: &VerifyingKey) -> Option<u64> {
+        self.fetch_events(verifying_key, &REVOCATION_NAME)
+            .await
+            .into_iter()
+            .filter_map(|event| match event.inner {
+                Event::Revocation(revocation) => Some(revocation.revoked_after),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// The earliest revocation published by each of the keys that has one, fetched in batches
+    async fn revocations_of(&self, verifying_keys: &[VerifyingKey]) -> HashMap<VerifyingKey, u64> {
+        let queries = verifying_keys
+            .chunks(MAX_KEYS_PER_QUERY)
+            .map(|keys| NamespaceQuery {
+                keys: Some(encode_keys(keys)),
+                ..Default::default()
+            })
+            .collect_vec();
+        let namespace_futures = queries
+            .iter()
+            .cartesian_product(self.config.get_connections())
+            .map(|(query, conn)| conn.namespace(REVOCATION_NAME.as_str(), query));
+        let mut revocations = HashMap::new();
+        for response in join_all(namespace_futures).await.into_iter().flatten() {
+            for event in response.events {
+                if let Event::Revocation(revocation) = &event.inner
+                    && let Some(keyspace) = event.verified_keyspace()
+                {
+                    revocations
+                        .entry(keyspace)
+                        .and_modify(|revoked_after: &mut u64| {
+                            *revoked_after = (*revoked_after).min(revocation.revoked_after)
+                        })
+                        .or_insert(revocation.revoked_after);
+                }
+            }
         }
+        revocations
+    }
+
+    /// Decrypts and decompresses the value of a set event
+    pub async fn decode_value(&self, event: &SetEvent) -> Result<Value> {
+        let value = match event.encryption {
+            Encryption::None => event.value.clone(),
+            Encryption::Recipients => self.crypto_key().await?.decrypt(&event.value)?,
+            Encryption::Private => self.private_key().await?.decrypt(&event.value)?,
+        };
+        value.decompress(event.compression)
+    }
+
+    /// Derived once, since deriving from a passphrase runs Argon2
+    async fn private_key(&self) -> Result<&PrivateKey> {
+        self.private_key
+            .get_or_try_init(|| async {
+                match self.config.private_passphrase() {
+                    Some(passphrase) => {
+                        PrivateKey::from_passphrase(passphrase, &self.whoami().await?)
+                    }
+                    None => self.crypto_key().await?.private_key(),
+                }
+            })
+            .await
+    }
+
+    /// The name under which a value set with `hide_name` is stored
+    pub async fn hidden_name(&self, name: &Name) -> Result<Name> {
+        Ok(self.private_key().await?.hide_name(name))
     }
 
     pub async fn get_by_key(&self, verifying_key: &VerifyingKey, name: &Name) -> Result<Value> {
-        self.get(&encode_verifying_key(verifying_key), name).await
+        self.get()
+            .verifying_key(&encode_verifying_key(verifying_key))
+            .name(name)
+            .call()
+            .await
     }
 
     pub async fn get_mine(&self, name: &Name) -> Result<Value> {
-        let verifying_key = self.whoami().await;
+        let verifying_key = self.whoami().await?;
         self.get_by_key(&verifying_key, name).await
     }
 
-    pub async fn namespace(&self, name: &str) -> Result<NamespaceValues> {
+    #[builder]
+    pub async fn namespace(
+        &self,
+        name: &str,
+        keys: Option<Vec<VerifyingKey>>,
+        limit: Option<usize>,
+        order: Option<NamespaceOrder>,
+        min_priority: Option<u64>,
+        /// Only values the servers received at or after this unix timestamp
+        since: Option<u64>,
+        /// Skips keyspaces the policy does not admit
+        trust: Option<&TrustPolicy>,
+    ) -> Result<NamespaceValues> {
+        if keys
+            .as_ref()
+            .is_some_and(|keys| keys.len() > MAX_KEYS_PER_QUERY)
+        {
+            bail!("At most {MAX_KEYS_PER_QUERY} keys per namespace query");
+        }
+        let order = order.unwrap_or_default();
+        let trust = trust.filter(|trust| !trust.is_open());
+        let query = NamespaceQuery {
+            keys: keys.as_deref().map(encode_keys),
+            // Keyspaces the policy rejects must not use up the limit on the server
+            limit: limit.filter(|_| trust.is_none()),
+            order: Some(order),
+            min_priority,
+            since,
+            count: false,
+        };
         let namespace_futures = self
             .config
             .get_connections()
             .iter()
-            .map(|conn| conn.namespace(name));
+            .map(|conn| conn.namespace(name, &query));
         let namespace_responses = join_all(namespace_futures)
             .await
             .into_iter()
@@ -142,31 +1077,110 @@ impl Actions {
             Some(response) => Ok(response),
             None => Err(anyhow::anyhow!("Namespace not found")),
         })?;
-        let event_mapping: HashMap<VerifyingKey, Vec<Signed<Event>>> = merged_namespace
-            .events
-            .into_iter()
-            .map(|event| (event.verifying_key(), event))
-            .into_group_map();
-        let value_mapping = event_mapping
-            .iter()
-            .map(|(k, v)| {
-                let value = merge_events(v.clone());
-                match value {
-                    Some(value) => Ok((*k, value)),
-                    None => Err(anyhow::anyhow!("Value not found")),
+        // Group by keyspace in the order the servers returned them, which is the requested order
+        let mut event_mapping: Vec<(VerifyingKey, Vec<Signed<Event>>)> = Vec::new();
+        let mut group_index = HashMap::new();
+        for event in merged_namespace.events {
+            let Some(keyspace) = event.verified_keyspace() else {
+                continue;
+            };
+            let index = *group_index.entry(keyspace).or_insert_with(|| {
+                event_mapping.push((keyspace, Vec::new()));
+                event_mapping.len() - 1
+            });
+            event_mapping[index].1.push(event);
+        }
+        if order == NamespaceOrder::Priority {
+            // Responses from several servers are concatenated, so restore the order across them
+            event_mapping.sort_by_key(|(_, events)| {
+                std::cmp::Reverse(events.iter().map(|event| event.inner.priority()).max())
+            });
+        }
+
+        let keyspaces = event_mapping.iter().map(|(key, _)| *key).collect_vec();
+        let revocations = self.revocations_of(&keyspaces).await;
+
+        let mut value_mapping = Vec::new();
+        for (verifying_key, events) in event_mapping {
+            if limit.is_some_and(|limit| value_mapping.len() >= limit) {
+                break;
+            }
+            if let Some(trust) = trust {
+                let endorsements = if trust.needs_endorsements(&verifying_key) {
+                    self.endorsements_of(&verifying_key, &trust.trusted).await
+                } else {
+                    Vec::new()
+                };
+                if !trust.admits(&verifying_key, &endorsements) {
+                    debug!(
+                        "Skipping untrusted value from {}",
+                        encode_verifying_key(&verifying_key)
+                    );
+                    continue;
                 }
-            })
-            .filter_map(Result::ok)
-            .collect();
+            }
+            let revoked_after = revocations.get(&verifying_key).copied();
+            let events = events
+                .into_iter()
+                .filter(|event| is_trusted(event, revoked_after))
+                .collect();
+            let Some(event) = merge_events(events) else {
+                continue;
+            };
+            match self.decode_value(&event).await {
+                Ok(value) => value_mapping.push((verifying_key, value)),
+                Err(e) => debug!(
+                    "Skipping value from {}: {:?}",
+                    encode_verifying_key(&verifying_key),
+                    e
+                ),
+            }
+        }
         Ok(NamespaceValues {
             namespace: merged_namespace.namespace,
             mapping: value_mapping,
         })
     }
 
-    pub async fn whoami(&self) -> VerifyingKey {
-        let crypto_key = CryptoKey::from_config(&self.config).await;
-        crypto_key.verifying()
+    /// With several servers this is the largest count any of them reports
+    #[builder]
+    pub async fn namespace_count(
+        &self,
+        name: &str,
+        keys: Option<Vec<VerifyingKey>>,
+        min_priority: Option<u64>,
+        /// Only keyspaces with values the servers received at or after this unix timestamp
+        since: Option<u64>,
+    ) -> Result<usize> {
+        if keys
+            .as_ref()
+            .is_some_and(|keys| keys.len() > MAX_KEYS_PER_QUERY)
+        {
+            bail!("At most {MAX_KEYS_PER_QUERY} keys per namespace query");
+        }
+        let query = NamespaceQuery {
+            keys: keys.as_deref().map(encode_keys),
+            min_priority,
+            since,
+            count: true,
+            ..Default::default()
+        };
+        let count_futures = self
+            .config
+            .get_connections()
+            .iter()
+            .map(|conn| conn.namespace(name, &query));
+        join_all(count_futures)
+            .await
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter_map(|response| response.count)
+            .max()
+            .ok_or_else(|| anyhow!("Namespace not found"))
+    }
+
+    pub async fn whoami(&self) -> Result<VerifyingKey> {
+        Ok(self.signer().await?.verifying_key())
     }
 
     pub async fn get_immutable(&self, hash: &blake3::Hash) -> Result<ContentBlock> {
@@ -183,6 +1197,82 @@ impl Actions {
             .ok_or_else(|| anyhow::anyhow!("Immutable content not found"))
     }
 
+    pub async fn set_content(&self, content: &[u8]) -> Result<ContentPointer> {
+        let mut level = Vec::new();
+        for chunk in content.chunks(CHUNK_SIZE) {
+            let block = ContentBlock {
+                data: chunk.to_vec(),
+                references: Vec::new(),
+            };
+            level.push(self.set_immutable(block).await?);
+        }
+        if level.is_empty() {
+            let block = ContentBlock {
+                data: Vec::new(),
+                references: Vec::new(),
+            };
+            level.push(self.set_immutable(block).await?);
+        }
+        while level.len() > 1 {
+            let mut parents = Vec::new();
+            for children in level.chunks(TREE_FANOUT) {
+                let block = ContentBlock {
+                    data: Vec::new(),
+                    references: children.to_vec(),
+                };
+                parents.push(self.set_immutable(block).await?);
+            }
+            level = parents;
+        }
+        Ok(ContentPointer {
+            root: level[0],
+            size: content.len() as u64,
+        })
+    }
+
+    pub async fn get_content(&self, pointer: &ContentPointer) -> Result<Vec<u8>> {
+        let content = self.get_tree(&pointer.root).await?;
+        if content.len() as u64 != pointer.size {
+            bail!(
+                "Content tree {} holds {} bytes, expected {}",
+                pointer.root,
+                content.len(),
+                pointer.size
+            );
+        }
+        Ok(content)
+    }
+
+    pub async fn get_tree(&self, root: &blake3::Hash) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        let mut pending = vec![*root];
+        while let Some(hash) = pending.pop() {
+            let block = self.get_cached_block(&hash).await?;
+            if block.references.is_empty() {
+                content.extend(block.data);
+            } else {
+                pending.extend(block.references.into_iter().rev());
+            }
+        }
+        Ok(content)
+    }
+
+    async fn get_cached_block(&self, hash: &blake3::Hash) -> Result<ContentBlock> {
+        let path = self.config.block_cache_dir().join(hash.to_string());
+        if let Ok(encoded) = tokio::fs::read(&path).await
+            && let Ok(block) = decode_block(hash, &encoded)
+        {
+            return Ok(block);
+        }
+        let block = self.get_immutable(hash).await?;
+        let encoded = encode_block(&block);
+        // Blocks are fetched from untrusted servers, so only keep one that matches its hash
+        let block = decode_block(hash, &encoded)?;
+        tokio::fs::create_dir_all(self.config.block_cache_dir()).await?;
+        tokio::fs::write(&path, &encoded).await?;
+        Ok(block)
+    }
+
     pub async fn set_immutable(&self, data: ContentBlock) -> Result<blake3::Hash> {
         let set_futures = self
             .config
@@ -197,3 +1287,76 @@ impl Actions {
             .ok_or_else(|| anyhow::anyhow!("Failed to set immutable content"))
     }
 }
+
+/// A priority or timestamp outranking `previous` even within the same second, and no earlier
+/// than now
+fn next_priority(previous: Option<u64>) -> Result<u64> {
+    let next = match previous {
+        Some(previous) => previous
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("{previous} cannot be outranked"))?,
+        None => 0,
+    };
+    Ok(next.max(unix_timestamp()))
+}
+
+/// Whether a server refused a request, which sending it again will not change, rather than
+/// being unreachable or failing on its side
+fn is_rejection(error: &anyhow::Error) -> bool {
+    error.is::<Rejected>()
+        || error
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status)
+            .is_some_and(|status| status.is_client_error())
+}
+
+fn is_insufficient_work(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<Rejected>()
+        .is_some_and(|rejected| rejected.0 == INSUFFICIENT_WORK)
+}
+
+/// Whether an event was written before its key was revoked
+fn is_trusted(event: &Signed<Event>, revoked_after: Option<u64>) -> bool {
+    revoked_after.is_none_or(|revoked_after| event.inner.priority() <= revoked_after)
+}
+
+fn encode_keys(keys: &[VerifyingKey]) -> String {
+    keys.iter().map(encode_verifying_key).join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connectors::local::LocalConnection, server::node::Node};
+
+    #[tokio::test]
+    async fn refetches_stamp_policies_after_insufficient_work() {
+        let node = Node::in_memory().unwrap().with_min_stamp_work(Some(4));
+        let connection = LocalConnection::new(node);
+        let url = connection.url().to_string();
+        let base_dir = tempfile::tempdir().unwrap();
+        let actions = Actions::with_identity(
+            Configuration::new(
+                base_dir.path().to_path_buf(),
+                vec![Connection::Local(connection)],
+            ),
+            CryptoKey::generate(),
+        );
+        // As if the server required no work when its policy was fetched
+        actions
+            .stamp_policies
+            .lock()
+            .unwrap()
+            .insert(url.clone(), None);
+
+        actions
+            .set()
+            .name(Name::new("greeting".to_string()))
+            .value(Value::new(b"hello".to_vec()))
+            .call()
+            .await
+            .unwrap();
+        assert_eq!(actions.stamp_policies.lock().unwrap()[&url], Some(4));
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, anyhow, bail};
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    configuration::{Configuration, is_valid_identity_name},
+    crypto::encode::{decode_verifying_key, encode_verifying_key},
+    models::Name,
+};
+
+/// Names of published aliases in the owner's keyspace start with this prefix
+pub const ALIAS_NAME_PREFIX: &str = "_baybridge.alias.";
+
+pub fn published_alias_name(alias: &str) -> Name {
+    Name::new(format!("{ALIAS_NAME_PREFIX}{alias}"))
+}
+
+/// Local contacts book mapping human-readable aliases to verifying keys
+#[derive(Default, Serialize, Deserialize)]
+pub struct AliasBook {
+    aliases: BTreeMap<String, String>,
+}
+
+impl AliasBook {
+    pub async fn load(config: &Configuration) -> Result<AliasBook> {
+        let path = config.aliases_path();
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse alias book {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AliasBook::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self, config: &Configuration) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(config.aliases_path(), contents).await?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, alias: &str, verifying_key: &VerifyingKey) -> Result<()> {
+        if !is_valid_identity_name(alias) {
+            bail!("Invalid alias {alias}");
+        }
+        // Encoded keys use the same characters as alias names, so an alias could shadow a key
+        if decode_verifying_key(alias).is_ok() {
+            bail!("Alias {alias} would shadow a verifying key");
+        }
+        self.aliases
+            .insert(alias.to_string(), encode_verifying_key(verifying_key));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, alias: &str) -> Result<()> {
+        self.aliases
+            .remove(alias)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("Alias {alias} does not exist"))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases
+            .iter()
+            .map(|(alias, verifying_key)| (alias.as_str(), verifying_key.as_str()))
+    }
+
+    /// Decodes the argument as an encoded verifying key, falling back to looking it up as an
+    /// alias, so an alias book from before aliases were checked cannot shadow keys
+    pub fn resolve(&self, alias_or_key: &str) -> Result<VerifyingKey> {
+        if let Ok(verifying_key) = decode_verifying_key(alias_or_key) {
+            return Ok(verifying_key);
+        }
+        match self.aliases.get(alias_or_key) {
+            Some(verifying_key) => decode_verifying_key(verifying_key),
+            None => bail!("{alias_or_key} is neither an alias nor a verifying key"),
+        }
+    }
+
+    pub fn alias_of(&self, verifying_key: &VerifyingKey) -> Option<&str> {
+        let encoded = encode_verifying_key(verifying_key);
+        self.aliases
+            .iter()
+            .find(|(_, verifying_key)| **verifying_key == encoded)
+            .map(|(alias, _)| alias.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CryptoKey;
+
+    #[test]
+    fn aliases_never_shadow_keys() {
+        let key = CryptoKey::generate().verifying();
+        let other = CryptoKey::generate().verifying();
+        let encoded = encode_verifying_key(&key);
+        let mut book = AliasBook::default();
+        assert!(book.add(&encoded, &other).is_err());
+        book.add("friend", &other).unwrap();
+        assert_eq!(book.resolve("friend").unwrap(), other);
+
+        // Alias books written before aliases were checked may hold one
+        book.aliases
+            .insert(encoded.clone(), encode_verifying_key(&other));
+        assert_eq!(book.resolve(&encoded).unwrap(), key);
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::{Result, anyhow};
+use bincode::config::standard;
+
+use crate::{
+    api::MAX_BODY_SIZE,
+    models::{ContentBlock, Value},
+};
+
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+pub const TREE_FANOUT: usize = 1024;
+
+const CONTENT_POINTER_HEADER: &str = "baybridge-content-tree-v1";
+
+/// Value stored in place of content too large to inline: the root of a tree of immutable
+/// blocks, where leaves hold data and interior blocks only reference their children
+#[derive(Clone, Copy, Debug)]
+pub struct ContentPointer {
+    pub root: blake3::Hash,
+    pub size: u64,
+}
+
+impl ContentPointer {
+    pub fn to_value(&self) -> Value {
+        Value::new(format!("{CONTENT_POINTER_HEADER}\n{}\n{}", self.root, self.size).into_bytes())
+    }
+
+    pub fn from_value(value: &Value) -> Option<ContentPointer> {
+        let contents = std::str::from_utf8(value.as_bytes()).ok()?;
+        let mut lines = contents.split('\n');
+        if lines.next()? != CONTENT_POINTER_HEADER {
+            return None;
+        }
+        let root = blake3::Hash::from_hex(lines.next()?).ok()?;
+        let size = lines.next()?.parse().ok()?;
+        Some(ContentPointer { root, size })
+    }
+}
+
+pub fn block_hash(block: &ContentBlock) -> blake3::Hash {
+    blake3::hash(&bincode::encode_to_vec(block, standard()).unwrap())
+}
+
+pub fn encode_block(block: &ContentBlock) -> Vec<u8> {
+    bincode::encode_to_vec(block, standard()).unwrap()
+}
+
+/// Decodes a block, checking it has the expected hash
+pub fn decode_block(hash: &blake3::Hash, encoded: &[u8]) -> Result<ContentBlock> {
+    if blake3::hash(encoded) != *hash {
+        return Err(anyhow!("Block {hash} does not match its hash"));
+    }
+    Ok(bincode::decode_from_slice(encoded, standard().with_limit::<MAX_BODY_SIZE>())?.0)
+}
@@ -1,9 +1,22 @@
-use bincode::{Decode, Encode};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::LazyLock,
+};
+
+use anyhow::{Result, anyhow};
+use bincode::{Decode, Encode, config::standard};
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, Signature, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    crypto::{Signable, Signed},
-    models::{Name, Value},
+    api::MAX_BODY_SIZE,
+    crypto::{
+        Signable, Signed,
+        encode::encode_verifying_key,
+        work::{required_stamp_work, stamp_work},
+    },
+    models::{Compression, Encryption, Name, Value},
+    time::unix_timestamp,
 };
 
 #[derive(Clone, Encode, Decode, Deserialize, Serialize)]
@@ -12,8 +25,33 @@ pub struct SetEvent {
     pub value: Value,
     pub priority: u64,
     pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub encryption: Encryption,
+    /// Only accept this write if the address's latest event has this hash, or if the address
+    /// is empty when it is `NO_PREVIOUS_EVENT`
+    #[serde(default)]
+    pub expected_previous: Option<[u8; 32]>,
+}
+
+impl SetEvent {
+    /// A set stored as given, without compression, encryption or an expected previous event
+    pub fn plain(name: Name, value: Value, priority: u64, expires_at: Option<u64>) -> SetEvent {
+        SetEvent {
+            name,
+            value,
+            priority,
+            expires_at,
+            compression: Compression::None,
+            encryption: Encryption::None,
+            expected_previous: None,
+        }
+    }
 }
 
+pub const NO_PREVIOUS_EVENT: [u8; 32] = [0; 32];
+
 impl Signable for SetEvent {}
 
 #[derive(Clone, Encode, Decode, Deserialize, Serialize)]
@@ -24,19 +62,227 @@ pub struct DeletionEvent {
 
 impl Signable for DeletionEvent {}
 
+/// Names under this prefix hold baybridge's own records rather than values
+pub const RESERVED_NAME_PREFIX: &str = "_baybridge.";
+
+/// How far ahead of this node's clock the priority of a delegated write without an expiry may be
+const MAX_CLOCK_SKEW: u64 = 10 * 60;
+
+/// Reserved name holding a keyspace's succession record
+pub static SUCCESSION_NAME: LazyLock<Name> =
+    LazyLock::new(|| Name::new("_baybridge.succession".to_string()));
+
+/// Reserved name holding a keyspace's revocation record
+pub static REVOCATION_NAME: LazyLock<Name> =
+    LazyLock::new(|| Name::new("_baybridge.revocation".to_string()));
+
+/// Signed by an old key to endorse the key that replaces it
+#[derive(Clone, Encode, Decode, Deserialize, Serialize)]
+pub struct SuccessionEvent {
+    pub successor: [u8; PUBLIC_KEY_LENGTH],
+    pub priority: u64,
+}
+
+impl Signable for SuccessionEvent {}
+
+impl SuccessionEvent {
+    pub fn successor(&self) -> Option<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.successor).ok()
+    }
+}
+
+/// Signed by a key to mark its events with a priority after `revoked_after` as untrusted
+#[derive(Clone, Encode, Decode, Deserialize, Serialize)]
+pub struct RevocationEvent {
+    pub revoked_after: u64,
+    pub priority: u64,
+}
+
+impl Signable for RevocationEvent {}
+
+pub const ENDORSEMENT_NAME_PREFIX: &str = "_baybridge.endorsement.";
+
+/// Names that only hold successions, revocations or endorsements, never sets, deletes or CRDTs
+fn is_reserved_name(name: &str) -> bool {
+    name == SUCCESSION_NAME.as_str()
+        || name == REVOCATION_NAME.as_str()
+        || name.starts_with(ENDORSEMENT_NAME_PREFIX)
+}
+
+/// The name under which endorsements of a key are stored, one per endorsing keyspace
+pub fn endorsement_name(endorsed: &VerifyingKey) -> Name {
+    Name::new(format!(
+        "{ENDORSEMENT_NAME_PREFIX}{}",
+        encode_verifying_key(endorsed)
+    ))
+}
+
+#[derive(Clone, Encode, Decode, Deserialize, Serialize)]
+pub struct EndorsementEvent {
+    pub name: Name,
+    pub endorsed: [u8; PUBLIC_KEY_LENGTH],
+    pub priority: u64,
+    pub withdrawn: bool,
+}
+
+impl Signable for EndorsementEvent {}
+
+impl EndorsementEvent {
+    pub fn new(endorsed: &VerifyingKey, priority: u64, withdrawn: bool) -> EndorsementEvent {
+        EndorsementEvent {
+            name: endorsement_name(endorsed),
+            endorsed: endorsed.to_bytes(),
+            priority,
+            withdrawn,
+        }
+    }
+
+    /// The endorsed key, if it is valid and the event is stored under its endorsement name
+    pub fn endorsed(&self) -> Option<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.endorsed)
+            .ok()
+            .filter(|endorsed| endorsement_name(endorsed).as_str() == self.name.as_str())
+    }
+}
+
+/// Each keyspace is one replica and publishes its whole state, so a newer event from a keyspace
+/// includes everything in its older ones
+#[derive(Clone, Debug, Encode, Decode, Deserialize, Serialize)]
+pub enum CrdtState {
+    GrowCounter(u64),
+    Counter { increments: u64, decrements: u64 },
+    /// Observed-remove set: an element is present while it has an add tag that was not removed
+    OrSet {
+        adds: BTreeMap<String, BTreeSet<[u8; 16]>>,
+        removes: BTreeSet<[u8; 16]>,
+    },
+    LwwMap(BTreeMap<String, LwwEntry>),
+}
+
+#[derive(Clone, Debug, Encode, Decode, Deserialize, Serialize)]
+pub struct LwwEntry {
+    /// None marks a removed key
+    pub value: Option<Value>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Encode, Decode, Deserialize, Serialize)]
+pub struct CrdtEvent {
+    pub name: Name,
+    pub priority: u64,
+    pub state: CrdtState,
+}
+
+impl Signable for CrdtEvent {}
+
 #[derive(Clone, Encode, Decode, Deserialize, Serialize)]
 pub enum Event {
     Set(SetEvent),
     Delete(DeletionEvent),
+    Succession(SuccessionEvent),
+    Revocation(RevocationEvent),
+    Crdt(CrdtEvent),
+    Endorsement(EndorsementEvent),
+}
+
+impl Signable for Event {
+    /// Sets without compression, encryption or an expected previous event are signed in the
+    /// layout from before those fields existed, so nodes from before them can still verify
+    /// them and signatures made before them stay valid. Deletions have not changed.
+    fn signing_bytes(&self) -> Vec<u8> {
+        match self {
+            Event::Set(SetEvent {
+                name,
+                value,
+                priority,
+                expires_at,
+                compression: Compression::None,
+                encryption: Encryption::None,
+                expected_previous: None,
+            }) => bincode::encode_to_vec((0u32, name, value, priority, expires_at), standard())
+                .unwrap(),
+            event => bincode::encode_to_vec(event, standard()).unwrap(),
+        }
+    }
+}
+
+/// Tag in front of events encoded with `Signed::<Event>::to_bytes`. Events stored before the
+/// tag was introduced start with the index of their variant instead, 0 for sets or 1 for
+/// deletions, and have the layout they were written in.
+const EVENT_ENCODING_VERSION: u8 = 2;
+
+/// Events stored before the encoding was versioned
+#[derive(Decode)]
+struct LegacySignedEvent {
+    inner: LegacyEvent,
+    verifying_key: [u8; PUBLIC_KEY_LENGTH],
+    signature: Vec<u8>,
+}
+
+#[derive(Decode)]
+enum LegacyEvent {
+    Set {
+        name: Name,
+        value: Value,
+        priority: u64,
+        expires_at: Option<u64>,
+    },
+    Delete(DeletionEvent),
 }
 
-impl Signable for Event {}
+impl From<LegacyEvent> for Event {
+    fn from(event: LegacyEvent) -> Event {
+        match event {
+            LegacyEvent::Set {
+                name,
+                value,
+                priority,
+                expires_at,
+            } => Event::Set(SetEvent::plain(name, value, priority, expires_at)),
+            LegacyEvent::Delete(event) => Event::Delete(event),
+        }
+    }
+}
 
 impl Event {
     pub fn name(&self) -> &Name {
         match self {
             Event::Set(event) => &event.name,
             Event::Delete(event) => &event.name,
+            Event::Crdt(event) => &event.name,
+            Event::Endorsement(event) => &event.name,
+            Event::Succession(_) => &SUCCESSION_NAME,
+            Event::Revocation(_) => &REVOCATION_NAME,
+        }
+    }
+
+    /// The kind of an event encoded with `Signed::<Event>::to_bytes` or stored before the
+    /// encoding was versioned, read from its variant index without decoding the rest
+    pub fn encoded_kind(bytes: &[u8]) -> Option<&'static str> {
+        let variant = match bytes {
+            [EVENT_ENCODING_VERSION, variant, ..] => *variant,
+            [variant @ (0 | 1), ..] => *variant,
+            _ => return None,
+        };
+        match variant {
+            0 => Some("set"),
+            1 => Some("delete"),
+            2 => Some("succession"),
+            3 => Some("revocation"),
+            4 => Some("crdt"),
+            5 => Some("endorsement"),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Set(_) => "set",
+            Event::Delete(_) => "delete",
+            Event::Succession(_) => "succession",
+            Event::Revocation(_) => "revocation",
+            Event::Crdt(_) => "crdt",
+            Event::Endorsement(_) => "endorsement",
         }
     }
 
@@ -44,20 +290,129 @@ impl Event {
         match self {
             Event::Set(event) => event.priority,
             Event::Delete(event) => event.priority,
+            Event::Succession(event) => event.priority,
+            Event::Revocation(event) => event.priority,
+            Event::Crdt(event) => event.priority,
+            Event::Endorsement(event) => event.priority,
+        }
+    }
+
+    /// Orders events at the same address, a higher rank superseding a lower one. Revocations
+    /// rank by how early they revoke, so a compromised key cannot replace a revocation with a
+    /// later one. Ranks stay within the range of a signed 64-bit SQLite integer, so priorities
+    /// beyond it all rank the same.
+    pub fn rank(&self) -> u64 {
+        match self {
+            Event::Revocation(event) => i64::MAX as u64 - event.revoked_after.min(i64::MAX as u64),
+            event => event.priority().min(i64::MAX as u64),
         }
     }
 
     pub fn expires_at(&self) -> Option<u64> {
         match self {
             Event::Set(event) => event.expires_at,
-            Event::Delete(_) => None,
+            Event::Delete(_)
+            | Event::Succession(_)
+            | Event::Revocation(_)
+            | Event::Crdt(_)
+            | Event::Endorsement(_) => None,
         }
     }
 
+    /// Hash of the event contents, independent of who signed it
+    pub fn hash(&self) -> [u8; 32] {
+        let serialized = bincode::encode_to_vec(self, standard()).unwrap();
+        *blake3::hash(&serialized).as_bytes()
+    }
+
+    /// Work a proof-of-work stamp on this event needs, scaled up from the minimum by its size
+    pub fn required_stamp_work(&self, min_stamp_work: u32) -> u32 {
+        let size = bincode::encode_to_vec(self, standard()).unwrap().len();
+        required_stamp_work(min_stamp_work, size)
+    }
+
     pub fn value(&self) -> Option<Value> {
         match self {
             Event::Set(event) => Some(event.value.clone()),
-            Event::Delete(_) => None,
+            Event::Delete(_)
+            | Event::Succession(_)
+            | Event::Revocation(_)
+            | Event::Crdt(_)
+            | Event::Endorsement(_) => None,
+        }
+    }
+}
+
+impl Signed<Event> {
+    /// Encodes the event for storage, tagged with the version of its layout
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![EVENT_ENCODING_VERSION];
+        bincode::encode_into_std_write(self, &mut bytes, standard()).unwrap();
+        bytes
+    }
+
+    /// Decodes an event encoded with `to_bytes`, or stored before the encoding was versioned
+    pub fn from_bytes(bytes: &[u8]) -> Result<Signed<Event>> {
+        let config = standard().with_limit::<MAX_BODY_SIZE>();
+        match bytes.split_first() {
+            Some((&EVENT_ENCODING_VERSION, encoded)) => {
+                Ok(bincode::decode_from_slice(encoded, config)?.0)
+            }
+            Some((0 | 1, _)) => {
+                let (legacy, _): (LegacySignedEvent, _) =
+                    bincode::decode_from_slice(bytes, config)?;
+                Ok(Signed::new(
+                    legacy.inner.into(),
+                    VerifyingKey::from_bytes(&legacy.verifying_key)?,
+                    Signature::from_slice(&legacy.signature)?,
+                ))
+            }
+            Some((version, _)) => Err(anyhow!("Unsupported event encoding version {version}")),
+            None => Err(anyhow!("Stored event is empty")),
+        }
+    }
+
+    /// Whether the event carries a stamp with the work required by a node's minimum
+    pub fn meets_stamp_work(&self, min_stamp_work: u32) -> bool {
+        self.stamp().is_some_and(|stamp| {
+            stamp_work(&self.inner.hash(), stamp) >= self.inner.required_stamp_work(min_stamp_work)
+        })
+    }
+
+    /// The keyspace the event belongs to if its keys are valid and it verifies for it
+    pub fn verified_keyspace(&self) -> Option<VerifyingKey> {
+        self.keyspace()
+            .filter(|keyspace| self.verify_event(keyspace))
+    }
+
+    /// Checks the event belongs to the keyspace, that sets, deletes and CRDT updates stay off
+    /// reserved names, and that delegated writes are sets, deletes or CRDT updates outside the
+    /// reserved prefix, within the delegation's name prefix and expiry, and with a priority no
+    /// later than now so a delegate cannot outrank the owner's future writes
+    pub fn verify_event(&self, keyspace: &VerifyingKey) -> bool {
+        if !self.verify(keyspace) {
+            return false;
+        }
+        if matches!(
+            self.inner,
+            Event::Set(_) | Event::Delete(_) | Event::Crdt(_)
+        ) && is_reserved_name(self.inner.name().as_str())
+        {
+            return false;
+        }
+        match self.delegation() {
+            None => true,
+            Some(delegation) => {
+                let name = self.inner.name().as_str();
+                let priority = self.inner.priority();
+                let now = unix_timestamp();
+                matches!(
+                    self.inner,
+                    Event::Set(_) | Event::Delete(_) | Event::Crdt(_)
+                ) && !name.starts_with(RESERVED_NAME_PREFIX)
+                    && priority <= now.saturating_add(MAX_CLOCK_SKEW)
+                    && delegation.inner.allows(name, priority)
+            }
         }
     }
 }
@@ -66,3 +421,168 @@ impl Event {
 pub struct RelevantEvents {
     pub events: Vec<Signed<Event>>,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+    use crate::crypto::{CryptoKey, delegation::Delegation};
+
+    // Layout of set events before the encoding was versioned
+    #[derive(Encode)]
+    enum OldEvent {
+        Set(OldSetEvent),
+    }
+
+    #[derive(Encode)]
+    struct OldSetEvent {
+        name: Name,
+        value: Value,
+        priority: u64,
+        expires_at: Option<u64>,
+    }
+
+    #[derive(Encode)]
+    struct OldSignedEvent {
+        inner: OldEvent,
+        verifying_key: [u8; PUBLIC_KEY_LENGTH],
+        signature: Vec<u8>,
+    }
+
+    /// A set of `foo` to `bar` as stored before the encoding was versioned, with its signer
+    pub(crate) fn legacy_set_event() -> (Vec<u8>, VerifyingKey) {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let verifying_key = signing_key.verifying_key();
+        let inner = OldEvent::Set(OldSetEvent {
+            name: Name::new("foo".to_string()),
+            value: Value::new(b"bar".to_vec()),
+            priority: 1,
+            expires_at: None,
+        });
+        let signature = signing_key.sign(&bincode::encode_to_vec(&inner, standard()).unwrap());
+        let stored = bincode::encode_to_vec(
+            OldSignedEvent {
+                inner,
+                verifying_key: verifying_key.to_bytes(),
+                signature: signature.to_bytes().to_vec(),
+            },
+            standard(),
+        )
+        .unwrap();
+        (stored, verifying_key)
+    }
+
+    #[test]
+    fn decodes_and_verifies_events_stored_before_versioning() {
+        let (stored, verifying_key) = legacy_set_event();
+        assert_eq!(Event::encoded_kind(&stored), Some("set"));
+        let event = Signed::<Event>::from_bytes(&stored).unwrap();
+        assert!(event.verify_event(&verifying_key));
+        assert_eq!(event.inner.value().unwrap().as_bytes(), b"bar");
+
+        let reencoded = event.to_bytes();
+        assert_eq!(reencoded[0], EVENT_ENCODING_VERSION);
+        assert!(
+            Signed::<Event>::from_bytes(&reencoded)
+                .unwrap()
+                .verify_event(&verifying_key)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_encoding_versions() {
+        assert!(Signed::<Event>::from_bytes(&[EVENT_ENCODING_VERSION + 1, 0]).is_err());
+        assert!(Signed::<Event>::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn rejects_writes_at_reserved_names() {
+        let mut key = CryptoKey::generate();
+        let verifying_key = key.verifying();
+        let deletion = |name: &Name| {
+            Event::Delete(DeletionEvent {
+                name: name.clone(),
+                priority: u64::MAX,
+            })
+        };
+        let endorsed = endorsement_name(&verifying_key);
+        for name in [&*REVOCATION_NAME, &*SUCCESSION_NAME, &endorsed] {
+            assert!(!key.sign(deletion(name)).verify_event(&verifying_key));
+        }
+        let alias = Name::new("_baybridge.alias.friend".to_string());
+        assert!(key.sign(deletion(&alias)).verify_event(&verifying_key));
+        let revocation = Event::Revocation(RevocationEvent {
+            revoked_after: 1,
+            priority: 2,
+        });
+        assert!(key.sign(revocation).verify_event(&verifying_key));
+    }
+
+    #[test]
+    fn limits_delegated_writes() {
+        let mut owner = CryptoKey::generate();
+        let mut delegate = CryptoKey::generate();
+        let delegation = owner.sign(Delegation {
+            delegate: delegate.verifying().to_bytes(),
+            name_prefix: None,
+            expires_at: None,
+        });
+        let mut delegated_set = |name: &str, priority| {
+            delegate
+                .sign(Event::Set(SetEvent::plain(
+                    Name::new(name.to_string()),
+                    Value::new(b"value".to_vec()),
+                    priority,
+                    None,
+                )))
+                .with_delegation(delegation.clone())
+        };
+        let keyspace = owner.verifying();
+        assert!(delegated_set("name", 1).verify_event(&keyspace));
+        assert!(!delegated_set("_baybridge.alias.friend", 1).verify_event(&keyspace));
+        assert!(!delegated_set("name", u64::MAX).verify_event(&keyspace));
+    }
+
+    #[test]
+    fn refuses_events_with_invalid_keys() {
+        let mut owner = CryptoKey::generate();
+        let mut delegate = CryptoKey::generate();
+        let set = Event::Set(SetEvent::plain(
+            Name::new("name".to_string()),
+            Value::new(b"value".to_vec()),
+            1,
+            None,
+        ));
+        let delegation = owner.sign(Delegation {
+            delegate: delegate.verifying().to_bytes(),
+            name_prefix: None,
+            expires_at: None,
+        });
+        // Not every 32 bytes are a point on the curve
+        let invalid_key = (0..=u8::MAX)
+            .map(|byte| [byte; PUBLIC_KEY_LENGTH])
+            .find(|bytes| VerifyingKey::from_bytes(bytes).is_err())
+            .unwrap();
+        let replace_key = |event: Signed<Event>, key: &VerifyingKey| {
+            let mut bytes = event.to_bytes();
+            let start = bytes
+                .windows(PUBLIC_KEY_LENGTH)
+                .position(|window| window == key.as_bytes())
+                .unwrap();
+            bytes[start..start + PUBLIC_KEY_LENGTH].copy_from_slice(&invalid_key);
+            Signed::<Event>::from_bytes(&bytes).unwrap()
+        };
+
+        let event = replace_key(owner.sign(set.clone()), &owner.verifying());
+        assert!(event.keyspace().is_none());
+        assert!(event.verified_keyspace().is_none());
+
+        // The delegate's key follows the owner's inside the embedded delegation
+        let delegated = delegate.sign(set).with_delegation(delegation);
+        assert_eq!(delegated.verified_keyspace(), Some(owner.verifying()));
+        let event = replace_key(delegated, &delegate.verifying());
+        assert!(event.verifying_key().is_none());
+        assert!(event.verified_keyspace().is_none());
+    }
+}
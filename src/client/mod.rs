@@ -1,9 +1,33 @@
 mod actions;
+mod aliases;
+pub mod content;
 mod events;
+mod replica;
+mod trust;
 
 pub use actions::Actions;
 pub use actions::Expiry;
+pub use actions::Reading;
+pub use aliases::ALIAS_NAME_PREFIX;
+pub use aliases::AliasBook;
+pub use events::CrdtEvent;
+pub use events::CrdtState;
 pub use events::DeletionEvent;
+pub use events::ENDORSEMENT_NAME_PREFIX;
+pub use events::EndorsementEvent;
 pub use events::Event;
+pub use events::LwwEntry;
+pub use events::NO_PREVIOUS_EVENT;
+pub use events::RESERVED_NAME_PREFIX;
+pub use events::REVOCATION_NAME;
 pub use events::RelevantEvents;
+pub use events::RevocationEvent;
+pub use events::SUCCESSION_NAME;
 pub use events::SetEvent;
+pub use events::SuccessionEvent;
+pub use events::endorsement_name;
+pub use replica::Freshness;
+pub use trust::TrustPolicy;
+
+#[cfg(test)]
+pub(crate) use events::tests::legacy_set_event;
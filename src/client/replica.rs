@@ -0,0 +1,109 @@
+use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+use itertools::Itertools;
+
+use crate::{
+    configuration::Configuration,
+    crypto::{Signed, encode::encode_verifying_key},
+    models::Name,
+    server::{data_controller::DataController, sqlite_store::SqliteStore},
+    time::unix_timestamp,
+};
+
+use super::Event;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    Live,
+    /// Only the local replica was read, last synced with a server at `synced_at`
+    Stale { synced_at: Option<u64> },
+}
+
+#[derive(Clone)]
+pub struct Replica {
+    data: DataController,
+}
+
+impl Replica {
+    pub async fn open(config: &Configuration) -> Result<Replica> {
+        let path = config.replica_database_path();
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let store = SqliteStore::new(&path)?;
+        Ok(Replica {
+            data: DataController::new(store),
+        })
+    }
+
+    /// `remote` is `None` when no server answered
+    pub async fn combine(
+        &self,
+        verifying_key: &VerifyingKey,
+        name: Option<&Name>,
+        remote: Option<Vec<Signed<Event>>>,
+    ) -> Result<(Vec<Signed<Event>>, Freshness)> {
+        let encoded_verifying_key = encode_verifying_key(verifying_key);
+        let freshness = match &remote {
+            Some(events) => {
+                self.data.insert_events(events.clone()).await?;
+                self.data
+                    .set_synced_at(encoded_verifying_key.clone(), unix_timestamp())
+                    .await?;
+                Freshness::Live
+            }
+            None => Freshness::Stale {
+                synced_at: self
+                    .data
+                    .get_synced_at(encoded_verifying_key.clone())
+                    .await?,
+            },
+        };
+        let local = match name {
+            Some(name) => {
+                self.data
+                    .events_by_key_and_name(encoded_verifying_key, name.to_string())
+                    .await?
+            }
+            None => self.data.events_by_key(encoded_verifying_key).await?,
+        };
+        // Servers drop expired events, but the replica keeps them until superseded
+        let now = unix_timestamp();
+        let local = local.into_iter().filter(|event| {
+            event
+                .inner
+                .expires_at()
+                .is_none_or(|expires_at| expires_at > now)
+        });
+        let events = remote
+            .unwrap_or_default()
+            .into_iter()
+            .chain(local)
+            .unique_by(|event| event.signature().map(|signature| signature.to_bytes()))
+            .collect();
+        Ok((events, freshness))
+    }
+
+    pub async fn insert(&self, event: &Signed<Event>) -> Result<()> {
+        self.data.insert_events(vec![event.clone()]).await?;
+        Ok(())
+    }
+
+    pub async fn enqueue(&self, event: &Signed<Event>) -> Result<()> {
+        self.insert(event).await?;
+        self.data.enqueue_event(event).await
+    }
+
+    pub async fn queued(&self) -> Result<Vec<(i64, Signed<Event>)>> {
+        self.data.queued_events().await
+    }
+
+    pub async fn dequeue(&self, id: i64) -> Result<()> {
+        self.data.dequeue_event(id).await
+    }
+
+    /// Drops a queued event no server will accept, so it no longer shadows theirs
+    pub async fn discard(&self, id: i64) -> Result<()> {
+        self.data.discard_queued_event(id).await
+    }
+}
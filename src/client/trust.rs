@@ -0,0 +1,133 @@
+use ed25519_dalek::VerifyingKey;
+use itertools::Itertools;
+
+use crate::{
+    crdt::latest_event,
+    crypto::{Signed, work::key_work},
+};
+
+use super::Event;
+
+/// Decides which keyspaces are read from a namespace. A key is admitted if it is trusted, if
+/// `endorsements` is set and a trusted key endorses it, or if it has at least `min_key_work`
+/// bits of key work. A policy without trusted keys or minimum work admits every key.
+#[derive(Clone, Debug, Default)]
+pub struct TrustPolicy {
+    pub trusted: Vec<VerifyingKey>,
+    pub endorsements: bool,
+    pub min_key_work: Option<u32>,
+}
+
+impl TrustPolicy {
+    pub fn is_open(&self) -> bool {
+        self.trusted.is_empty() && self.min_key_work.is_none()
+    }
+
+    pub fn admits_outright(&self, verifying_key: &VerifyingKey) -> bool {
+        self.is_open()
+            || self.trusted.contains(verifying_key)
+            || self
+                .min_key_work
+                .is_some_and(|min_key_work| key_work(verifying_key) >= min_key_work)
+    }
+
+    pub fn needs_endorsements(&self, verifying_key: &VerifyingKey) -> bool {
+        self.endorsements && !self.trusted.is_empty() && !self.admits_outright(verifying_key)
+    }
+
+    pub fn admits(&self, verifying_key: &VerifyingKey, endorsements: &[Signed<Event>]) -> bool {
+        self.admits_outright(verifying_key)
+            || (self.endorsements && self.is_endorsed(verifying_key, endorsements))
+    }
+
+    fn is_endorsed(&self, verifying_key: &VerifyingKey, endorsements: &[Signed<Event>]) -> bool {
+        endorsements
+            .iter()
+            .filter_map(|event| Some((event.verified_keyspace()?, event.clone())))
+            .filter(|(keyspace, _)| self.trusted.contains(keyspace))
+            .filter(|(_, event)| match &event.inner {
+                Event::Endorsement(endorsement) => {
+                    endorsement.endorsed().as_ref() == Some(verifying_key)
+                }
+                _ => false,
+            })
+            .into_group_map()
+            .values()
+            .filter_map(|events| latest_event(events))
+            .any(|event| matches!(&event.inner, Event::Endorsement(endorsement) if !endorsement.withdrawn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::EndorsementEvent, crypto::CryptoKey};
+
+    fn endorse(
+        endorser: &mut CryptoKey,
+        endorsed: &VerifyingKey,
+        priority: u64,
+        withdrawn: bool,
+    ) -> Signed<Event> {
+        endorser.sign(Event::Endorsement(EndorsementEvent::new(
+            endorsed, priority, withdrawn,
+        )))
+    }
+
+    #[test]
+    fn open_policies_admit_every_key() {
+        let key = CryptoKey::generate().verifying();
+        let policy = TrustPolicy {
+            endorsements: true,
+            ..Default::default()
+        };
+        assert!(policy.is_open());
+        assert!(policy.admits(&key, &[]));
+        assert!(!policy.needs_endorsements(&key));
+    }
+
+    #[test]
+    fn admits_trusted_keys_and_keys_with_enough_work() {
+        let trusted = CryptoKey::generate().verifying();
+        let stranger = CryptoKey::generate().verifying();
+        let policy = TrustPolicy {
+            trusted: vec![trusted],
+            ..Default::default()
+        };
+        assert!(policy.admits(&trusted, &[]));
+        assert!(!policy.admits(&stranger, &[]));
+
+        let worked = CryptoKey::generate_with_work(4).verifying();
+        let policy = TrustPolicy {
+            min_key_work: Some(4),
+            ..policy
+        };
+        assert!(policy.admits_outright(&worked));
+    }
+
+    #[test]
+    fn admits_keys_whose_latest_endorsement_stands() {
+        let mut endorser = CryptoKey::generate();
+        let mut stranger = CryptoKey::generate();
+        let endorsed = CryptoKey::generate().verifying();
+        let policy = TrustPolicy {
+            trusted: vec![endorser.verifying()],
+            endorsements: true,
+            ..Default::default()
+        };
+        assert!(policy.needs_endorsements(&endorsed));
+
+        let endorsement = endorse(&mut endorser, &endorsed, 1, false);
+        let withdrawal = endorse(&mut endorser, &endorsed, 2, true);
+        assert!(policy.admits(&endorsed, std::slice::from_ref(&endorsement)));
+        assert!(!policy.admits(&endorsed, &[endorsement.clone(), withdrawal]));
+        let untrusted = endorse(&mut stranger, &endorsed, 1, false);
+        assert!(!policy.admits(&endorsed, &[untrusted]));
+
+        let ignoring_endorsements = TrustPolicy {
+            endorsements: false,
+            ..policy
+        };
+        assert!(!ignoring_endorsements.admits(&endorsed, &[endorsement]));
+    }
+}
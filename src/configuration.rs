@@ -1,12 +1,30 @@
-use anyhow::Result;
-use std::path::PathBuf;
-use tracing::debug;
+use anyhow::{Result, bail};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use tracing::{debug, info, warn};
 
-use crate::connectors::{connection::Connection, http::HttpConnection};
+use crate::{
+    client::TrustPolicy,
+    connectors::{connection::Connection, http::HttpConnection},
+};
+
+pub const DEFAULT_IDENTITY: &str = "default";
+
+pub fn is_valid_identity_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
 
 pub struct Configuration {
     base_dir: PathBuf,
     connections: Vec<Connection>,
+    identity: Option<String>,
+    private_passphrase: Option<String>,
+    key_passphrase: Option<String>,
+    history_retention: Option<Duration>,
+    protected_namespaces: HashMap<String, TrustPolicy>,
+    min_stamp_work: Option<u32>,
 }
 
 impl Default for Configuration {
@@ -24,23 +42,147 @@ impl Configuration {
         Configuration {
             base_dir,
             connections,
+            identity: None,
+            private_passphrase: None,
+            key_passphrase: None,
+            history_retention: None,
+            protected_namespaces: HashMap::new(),
+            min_stamp_work: None,
         }
     }
 
+    /// Use a named identity instead of the one selected with `identity use`
+    pub fn with_identity(mut self, identity: Option<String>) -> Result<Configuration> {
+        if let Some(identity) = &identity
+            && !is_valid_identity_name(identity)
+        {
+            bail!("Invalid identity name {identity}");
+        }
+        self.identity = identity;
+        Ok(self)
+    }
+
+    /// Passphrase for the encrypted signing key file, prompted for when not set
+    pub fn with_key_passphrase(mut self, passphrase: Option<String>) -> Configuration {
+        self.key_passphrase = passphrase;
+        self
+    }
+
+    /// Derive the key for private values from a passphrase instead of the signing key
+    pub fn with_private_passphrase(mut self, passphrase: Option<String>) -> Configuration {
+        self.private_passphrase = passphrase;
+        self
+    }
+
+    /// Keep superseded events on the server for this long so they appear in the audit log
+    pub fn with_history_retention(mut self, history_retention: Option<Duration>) -> Configuration {
+        self.history_retention = history_retention;
+        self
+    }
+
+    pub fn with_protected_namespace(mut self, name: String, policy: TrustPolicy) -> Configuration {
+        self.protected_namespaces.insert(name, policy);
+        self
+    }
+
+    /// Reject events without a proof-of-work stamp of at least this many bits, more for large ones
+    pub fn with_min_stamp_work(mut self, min_stamp_work: Option<u32>) -> Configuration {
+        self.min_stamp_work = min_stamp_work;
+        self
+    }
+
     pub async fn init(&self) -> Result<()> {
         debug!("Creating base directory: {:?}", self.base_dir);
         tokio::fs::create_dir_all(&self.base_dir).await?;
+        tokio::fs::create_dir_all(self.identities_dir()).await?;
+
+        // Installs from before named identities kept a single key in the base directory
+        let legacy_key_path = self.base_dir.join("private_signing_key");
+        let default_key_path = self.identities_dir().join(DEFAULT_IDENTITY);
+        if legacy_key_path.exists() && !default_key_path.exists() {
+            info!(
+                "Moving signing key {} to identity {}",
+                legacy_key_path.display(),
+                DEFAULT_IDENTITY
+            );
+            tokio::fs::rename(&legacy_key_path, &default_key_path).await?;
+        }
         Ok(())
     }
 
+    pub fn identities_dir(&self) -> PathBuf {
+        self.base_dir.join("identities")
+    }
+
+    pub fn current_identity_path(&self) -> PathBuf {
+        self.base_dir.join("current_identity")
+    }
+
+    /// The identity given explicitly, else the one selected with `identity use`, else the default
+    pub fn identity_name(&self) -> String {
+        if let Some(identity) = &self.identity {
+            return identity.clone();
+        }
+        let selected = std::fs::read_to_string(self.current_identity_path())
+            .ok()
+            .map(|name| name.trim().to_string());
+        match selected {
+            Some(name) if is_valid_identity_name(&name) => name,
+            Some(name) => {
+                warn!("Ignoring invalid selected identity {name:?}");
+                DEFAULT_IDENTITY.to_string()
+            }
+            None => DEFAULT_IDENTITY.to_string(),
+        }
+    }
+
+    pub fn identity_key_path(&self, identity: &str) -> Result<PathBuf> {
+        if !is_valid_identity_name(identity) {
+            bail!("Invalid identity name {identity}");
+        }
+        Ok(self.identities_dir().join(identity))
+    }
+
     pub fn signing_key_path(&self) -> PathBuf {
-        self.base_dir.join("private_signing_key")
+        self.identities_dir().join(self.identity_name())
+    }
+
+    pub fn aliases_path(&self) -> PathBuf {
+        self.base_dir.join("aliases.json")
     }
 
     pub fn get_connections(&self) -> &Vec<Connection> {
         &self.connections
     }
 
+    pub fn private_passphrase(&self) -> Option<&str> {
+        self.private_passphrase.as_deref()
+    }
+
+    pub fn key_passphrase(&self) -> Option<&str> {
+        self.key_passphrase.as_deref()
+    }
+
+    pub fn history_retention(&self) -> Option<Duration> {
+        self.history_retention
+    }
+
+    pub fn protected_namespaces(&self) -> &HashMap<String, TrustPolicy> {
+        &self.protected_namespaces
+    }
+
+    pub fn min_stamp_work(&self) -> Option<u32> {
+        self.min_stamp_work
+    }
+
+    pub fn block_cache_dir(&self) -> PathBuf {
+        self.base_dir.join("block_cache")
+    }
+
+    pub fn replica_database_path(&self) -> PathBuf {
+        self.base_dir.join("replica.sqlite")
+    }
+
     pub fn server_database_path(&self) -> PathBuf {
         self.base_dir.join("server.sqlite")
     }
@@ -49,3 +191,34 @@ impl Configuration {
         self.base_dir.join("immutable_store")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CryptoKey;
+
+    #[tokio::test]
+    async fn moves_legacy_signing_keys_to_the_default_identity() {
+        let base_dir = tempfile::tempdir().unwrap();
+        let config = Configuration::new(base_dir.path().to_path_buf(), vec![]);
+        let legacy_key = CryptoKey::generate();
+        legacy_key
+            .save(&base_dir.path().join("private_signing_key"), None)
+            .await
+            .unwrap();
+
+        config.init().await.unwrap();
+        assert!(!base_dir.path().join("private_signing_key").exists());
+        let crypto_key = CryptoKey::from_config(&config).await.unwrap();
+        assert_eq!(crypto_key.verifying(), legacy_key.verifying());
+
+        // A later legacy key never replaces the identity it was migrated to
+        CryptoKey::generate()
+            .save(&base_dir.path().join("private_signing_key"), None)
+            .await
+            .unwrap();
+        config.init().await.unwrap();
+        let crypto_key = CryptoKey::from_config(&config).await.unwrap();
+        assert_eq!(crypto_key.verifying(), legacy_key.verifying());
+    }
+}
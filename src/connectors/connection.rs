@@ -1,5 +1,5 @@
 use crate::{
-    api::{StateHash, SyncEvents},
+    api::{NamespaceQuery, NodePolicy, Snapshot, StateHash, SyncEvents},
     client::{Event, RelevantEvents},
     crypto::Signed,
     models::{ContentBlock, Name},
@@ -7,58 +7,109 @@ use crate::{
 use anyhow::Result;
 use ed25519_dalek::VerifyingKey;
 
-use super::http::{HttpConnection, NamespaceResponse};
+use super::{
+    http::{HttpConnection, NamespaceResponse},
+    local::LocalConnection,
+};
 
 pub enum Connection {
     Http(HttpConnection),
+    Local(LocalConnection),
 }
 
 impl Connection {
     pub fn url(&self) -> &str {
         match self {
             Connection::Http(http) => http.url().as_str(),
+            Connection::Local(local) => local.url(),
+        }
+    }
+
+    pub fn is_circuit_open(&self) -> bool {
+        match self {
+            Connection::Http(http) => http.is_circuit_open(),
+            Connection::Local(_) => false,
         }
     }
 
     pub async fn set(&self, payload: Signed<Event>) -> Result<()> {
         match self {
             Connection::Http(http) => http.set(payload).await,
+            Connection::Local(local) => local.set(payload).await,
         }
     }
 
     pub async fn get(&self, verifying_key: &VerifyingKey, name: &Name) -> Result<RelevantEvents> {
         match self {
             Connection::Http(http) => http.get(verifying_key, name).await,
+            Connection::Local(local) => local.get(verifying_key, name).await,
         }
     }
 
-    pub async fn namespace(&self, name: &str) -> Result<NamespaceResponse> {
+    pub async fn keyspace(&self, verifying_key: &VerifyingKey) -> Result<RelevantEvents> {
         match self {
-            Connection::Http(http) => http.namespace(name).await,
+            Connection::Http(http) => http.keyspace(verifying_key).await,
+            Connection::Local(local) => local.keyspace(verifying_key).await,
+        }
+    }
+
+    pub async fn history(
+        &self,
+        verifying_key: &VerifyingKey,
+        name: &Name,
+    ) -> Result<RelevantEvents> {
+        match self {
+            Connection::Http(http) => http.history(verifying_key, name).await,
+            Connection::Local(local) => local.history(verifying_key, name).await,
+        }
+    }
+
+    pub async fn namespace(&self, name: &str, query: &NamespaceQuery) -> Result<NamespaceResponse> {
+        match self {
+            Connection::Http(http) => http.namespace(name, query).await,
+            Connection::Local(local) => local.namespace(name, query).await,
+        }
+    }
+
+    pub async fn policy(&self) -> Result<NodePolicy> {
+        match self {
+            Connection::Http(http) => http.policy().await,
+            Connection::Local(local) => local.policy().await,
         }
     }
 
     pub async fn state_hash(&self) -> Result<StateHash> {
         match self {
             Connection::Http(http) => http.state_hash().await,
+            Connection::Local(local) => local.state_hash().await,
         }
     }
 
     pub async fn sync_events(&self) -> Result<SyncEvents> {
         match self {
             Connection::Http(http) => http.sync_events().await,
+            Connection::Local(local) => local.sync_events().await,
+        }
+    }
+
+    pub async fn snapshot(&self, state_hash: &StateHash) -> Result<Snapshot> {
+        match self {
+            Connection::Http(http) => http.snapshot(state_hash).await,
+            Connection::Local(local) => local.snapshot(state_hash).await,
         }
     }
 
     pub async fn get_immutable(&self, hash: &blake3::Hash) -> Result<ContentBlock> {
         match self {
             Connection::Http(http) => http.get_immutable(hash).await,
+            Connection::Local(local) => local.get_immutable(hash).await,
         }
     }
 
     pub async fn set_immutable(&self, data: ContentBlock) -> Result<blake3::Hash> {
         match self {
             Connection::Http(http) => http.set_immutable(data).await,
+            Connection::Local(local) => local.set_immutable(data).await,
         }
     }
 }
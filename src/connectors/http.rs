@@ -1,24 +1,37 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use crate::{
-    api::{StateHash, SyncEvents},
+    api::{
+        INSUFFICIENT_WORK, MAX_RESPONSE_SIZE, NamespaceQuery, NodePolicy, PREFER_BINCODE_ACCEPT,
+        Rejected, Snapshot, StateHash, SyncEvents, WireFormat, WriteConflict,
+    },
     client::{Event, RelevantEvents},
     crypto::{Signed, encode::encode_verifying_key},
     models::{ContentBlock, Name},
 };
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use ed25519_dalek::VerifyingKey;
 use failsafe::futures::CircuitBreaker;
-use serde::{Deserialize, Serialize};
+use reqwest::{
+    StatusCode,
+    header::{ACCEPT, CONTENT_TYPE},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use tracing::debug;
 
 #[derive(Deserialize, Serialize)]
 pub struct NamespaceResponse {
     pub namespace: String,
     pub events: Vec<Signed<Event>>,
+    /// Number of matching keyspaces, only set for count queries
+    #[serde(default)]
+    pub count: Option<usize>,
 }
 
 impl NamespaceResponse {
     pub fn merge(&mut self, mut other: NamespaceResponse) {
-        self.events.append(&mut other.events)
+        self.events.append(&mut other.events);
+        self.count = self.count.max(other.count);
     }
 
     pub fn merge_vec(namespace_responses: Vec<NamespaceResponse>) -> Option<NamespaceResponse> {
@@ -41,6 +54,8 @@ pub struct HttpConnection {
         >,
         (),
     >,
+    // Cleared once the server rejects a bincode request body, for nodes that only speak JSON
+    binary_body_supported: AtomicBool,
 }
 
 impl HttpConnection {
@@ -54,6 +69,7 @@ impl HttpConnection {
             url,
             client,
             circuit_breaker,
+            binary_body_supported: AtomicBool::new(true),
         }
     }
 
@@ -61,63 +77,195 @@ impl HttpConnection {
         &self.url
     }
 
+    /// Whether the circuit breaker is refusing requests after repeated failures
+    pub fn is_circuit_open(&self) -> bool {
+        !self.circuit_breaker.is_call_permitted()
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, url: url::Url) -> Result<T> {
+        debug!("Sending request to {}", url.as_str());
+        let request_future = self
+            .client
+            .get(url.as_str())
+            .header(ACCEPT, PREFER_BINCODE_ACCEPT)
+            .send();
+        let response = self.circuit_breaker.call(request_future).await?;
+        decode_response(response).await
+    }
+
+    async fn post<T: Serialize>(&self, url: url::Url, payload: &T) -> Result<reqwest::Response> {
+        if self.binary_body_supported.load(Ordering::Relaxed) {
+            let response = self
+                .post_encoded(url.clone(), payload, WireFormat::Bincode)
+                .await?;
+            if response.status() != StatusCode::UNSUPPORTED_MEDIA_TYPE {
+                return Ok(response);
+            }
+            debug!("{} does not accept bincode, falling back to JSON", self.url);
+            self.binary_body_supported.store(false, Ordering::Relaxed);
+        }
+        self.post_encoded(url, payload, WireFormat::Json).await
+    }
+
+    async fn post_encoded<T: Serialize>(
+        &self,
+        url: url::Url,
+        payload: &T,
+        format: WireFormat,
+    ) -> Result<reqwest::Response> {
+        let body = format.encode(payload)?;
+        let request_future = self
+            .client
+            .post(url.as_str())
+            .header(CONTENT_TYPE, format.content_type())
+            .header(ACCEPT, PREFER_BINCODE_ACCEPT)
+            .body(body)
+            .send();
+        let response = self.circuit_breaker.call(request_future).await?;
+        Ok(response)
+    }
+
     pub async fn set(&self, payload: Signed<Event>) -> Result<()> {
-        let verifying_key_string = encode_verifying_key(&payload.verifying_key());
+        let keyspace = payload
+            .keyspace()
+            .ok_or_else(|| anyhow!("Event is signed with an invalid key"))?;
+        let verifying_key_string = encode_verifying_key(&keyspace);
         let url = self.url.join(&format!("keyspace/{verifying_key_string}"))?;
         debug!("Setting {} on {}", payload.inner.name(), url.as_str());
-        let request_future = self.client.post(url.as_str()).json(&payload).send();
-        self.circuit_breaker.call(request_future).await?;
+        let response = self.post(url, &payload).await?;
+        if response.status() == StatusCode::CONFLICT {
+            return Err(WriteConflict.into());
+        }
+        if let Err(e) = response.error_for_status_ref() {
+            if response.status() == StatusCode::FORBIDDEN
+                && response
+                    .text()
+                    .await
+                    .is_ok_and(|reason| reason == INSUFFICIENT_WORK)
+            {
+                return Err(Rejected(INSUFFICIENT_WORK).into());
+            }
+            return Err(e.into());
+        }
         Ok(())
     }
 
     pub async fn get(&self, verifying_key: &VerifyingKey, name: &Name) -> Result<RelevantEvents> {
         let verifying_key_string = encode_verifying_key(verifying_key);
-        let url = self
-            .url
-            .join(&format!("keyspace/{verifying_key_string}/{name}"))?;
-        debug!("Sending request to {}", url.as_str());
-        let request_future = self.client.get(url.as_str()).send();
-        let response = self.circuit_breaker.call(request_future).await?;
-        response.json::<RelevantEvents>().await.map_err(Into::into)
+        let url = self.named_url(&format!("keyspace/{verifying_key_string}/"), name.as_str())?;
+        self.fetch(url).await
     }
 
-    pub async fn namespace(&self, name: &str) -> Result<NamespaceResponse> {
-        let url = self.url.join(&format!("namespace/{name}"))?;
-        debug!("Sending request to {}", url.as_str());
-        let request_future = self.client.get(url.as_str()).send();
-        let response = self.circuit_breaker.call(request_future).await?;
-        response.json().await.map_err(Into::into)
+    pub async fn keyspace(&self, verifying_key: &VerifyingKey) -> Result<RelevantEvents> {
+        let verifying_key_string = encode_verifying_key(verifying_key);
+        let url = self.url.join(&format!("keyspace/{verifying_key_string}"))?;
+        self.fetch(url).await
+    }
+
+    /// Joins a directory path and a name, escaping slashes in the name so it stays one segment
+    fn named_url(&self, directory: &str, name: &str) -> Result<url::Url> {
+        let mut url = self.url.join(directory)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("{} cannot be a base url", self.url))?
+            .pop_if_empty()
+            .push(name);
+        Ok(url)
+    }
+
+    pub async fn history(
+        &self,
+        verifying_key: &VerifyingKey,
+        name: &Name,
+    ) -> Result<RelevantEvents> {
+        let verifying_key_string = encode_verifying_key(verifying_key);
+        let url = self.named_url(
+            &format!("audit/keyspace/{verifying_key_string}/"),
+            name.as_str(),
+        )?;
+        self.fetch(url).await
+    }
+
+    pub async fn namespace(&self, name: &str, query: &NamespaceQuery) -> Result<NamespaceResponse> {
+        let mut url = self.named_url("namespace/", name)?;
+        let query_pairs = query.query_pairs();
+        if !query_pairs.is_empty() {
+            url.query_pairs_mut().extend_pairs(query_pairs);
+        }
+        self.fetch(url).await
+    }
+
+    pub async fn policy(&self) -> Result<NodePolicy> {
+        let url = self.url.join("policy")?;
+        self.fetch(url).await
     }
 
     pub async fn state_hash(&self) -> Result<StateHash> {
         let url = self.url.join("sync/state")?;
-        debug!("Sending request to {}", url.as_str());
-        let request_future = self.client.get(url.as_str()).send();
-        let response = self.circuit_breaker.call(request_future).await?;
-        response.json().await.map_err(Into::into)
+        self.fetch(url).await
     }
 
     pub async fn sync_events(&self) -> Result<SyncEvents> {
         let url = self.url.join("sync/events")?;
-        debug!("Sending request to {}", url.as_str());
-        let request_future = self.client.get(url.as_str()).send();
-        let response = self.circuit_breaker.call(request_future).await?;
-        response.json().await.map_err(Into::into)
+        self.fetch(url).await
+    }
+
+    /// All events of the node at the state hash, failing if its state has changed since
+    pub async fn snapshot(&self, state_hash: &StateHash) -> Result<Snapshot> {
+        let url = self
+            .url
+            .join(&format!("sync/snapshot/{}", state_hash.hash))?;
+        debug!("Fetching snapshot from {}", url.as_str());
+        // Snapshots hold every event, so they may take much longer than other requests
+        let request_future = self
+            .client
+            .get(url.as_str())
+            .timeout(std::time::Duration::from_secs(300))
+            .send();
+        let response = self
+            .circuit_breaker
+            .call(request_future)
+            .await?
+            .error_for_status()?;
+        let snapshot = Snapshot::decode(&read_body(response).await?)?;
+        if snapshot.state_hash != *state_hash {
+            bail!("Snapshot from {} has a different state hash", self.url);
+        }
+        Ok(snapshot)
     }
 
     pub async fn get_immutable(&self, hash: &blake3::Hash) -> Result<ContentBlock> {
         let url = self.url.join(&format!("immutable/{hash}"))?;
-        debug!("Sending request to {}", url.as_str());
-        let request_future = self.client.get(url.as_str()).send();
-        let response = self.circuit_breaker.call(request_future).await?;
-        response.json().await.map_err(Into::into)
+        self.fetch(url).await
     }
 
     pub async fn set_immutable(&self, data: ContentBlock) -> Result<blake3::Hash> {
         let url = self.url.join("immutable")?;
         debug!("Setting immutable data on {}", url.as_str());
-        let request_future = self.client.post(url.as_str()).json(&data).send();
-        let response = self.circuit_breaker.call(request_future).await?;
-        response.json().await.map_err(Into::into)
+        let response = self.post(url, &data).await?;
+        decode_response(response).await
+    }
+}
+
+async fn decode_response<T: DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    let format = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(WireFormat::from_content_type)
+        .unwrap_or(WireFormat::Json);
+    let bytes = read_body(response).await?;
+    format.decode_with_limit::<T, MAX_RESPONSE_SIZE>(&bytes)
+}
+
+/// The body of a response, decompressed chunk by chunk so a small compressed body cannot
+/// expand past `MAX_RESPONSE_SIZE`
+async fn read_body(mut response: reqwest::Response) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+            bail!("Response is larger than {MAX_RESPONSE_SIZE} bytes");
+        }
+        body.extend_from_slice(&chunk);
     }
+    Ok(body)
 }
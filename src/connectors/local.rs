@@ -0,0 +1,178 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Result, anyhow, bail};
+use ed25519_dalek::VerifyingKey;
+use tracing::debug;
+
+use crate::{
+    api::{NamespaceQuery, NodePolicy, Rejected, Snapshot, StateHash, SyncEvents},
+    client::{Event, RelevantEvents},
+    crypto::{Signed, encode::encode_verifying_key},
+    models::{ContentBlock, Name},
+    server::node::Node,
+};
+
+use super::{connection::Connection, http::NamespaceResponse};
+
+static NEXT_NODE_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone)]
+pub struct LocalConnection {
+    url: String,
+    node: Node,
+}
+
+impl LocalConnection {
+    pub fn new(node: Node) -> LocalConnection {
+        // Peers are told apart by url when syncing, so each node gets its own
+        let id = NEXT_NODE_ID.fetch_add(1, Ordering::Relaxed);
+        LocalConnection {
+            url: format!("local://node-{id}"),
+            node,
+        }
+    }
+
+    pub fn in_memory() -> Result<LocalConnection> {
+        Ok(LocalConnection::new(Node::in_memory()?))
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    /// Writes an event like `POST /keyspace/:verifying_key`, failing with `Rejected` where
+    /// that answers with a client error
+    pub async fn set(&self, payload: Signed<Event>) -> Result<()> {
+        let checked = match payload.keyspace() {
+            Some(keyspace) => self.node.check_event(&keyspace, &payload),
+            None => Err("Invalid key"),
+        };
+        if let Err(reason) = checked {
+            debug!("{} refused {}: {}", self.url, payload.inner.name(), reason);
+            return Err(Rejected(reason).into());
+        }
+        self.node.insert_event(payload).await?;
+        Ok(())
+    }
+
+    pub async fn get(&self, verifying_key: &VerifyingKey, name: &Name) -> Result<RelevantEvents> {
+        let events = self
+            .node
+            .controller()
+            .events_by_key_and_name(encode_verifying_key(verifying_key), name.to_string())
+            .await?;
+        Ok(RelevantEvents { events })
+    }
+
+    pub async fn keyspace(&self, verifying_key: &VerifyingKey) -> Result<RelevantEvents> {
+        let events = self
+            .node
+            .controller()
+            .events_by_key(encode_verifying_key(verifying_key))
+            .await?;
+        Ok(RelevantEvents { events })
+    }
+
+    pub async fn history(
+        &self,
+        verifying_key: &VerifyingKey,
+        name: &Name,
+    ) -> Result<RelevantEvents> {
+        let events = self
+            .node
+            .controller()
+            .history_by_key_and_name(encode_verifying_key(verifying_key), name.to_string())
+            .await?;
+        Ok(RelevantEvents { events })
+    }
+
+    pub async fn namespace(&self, name: &str, query: &NamespaceQuery) -> Result<NamespaceResponse> {
+        let keys = query.verifying_keys()?;
+        self.node
+            .namespace(name.to_string(), keys.as_deref(), query)
+            .await
+    }
+
+    pub async fn policy(&self) -> Result<NodePolicy> {
+        Ok(self.node.policy())
+    }
+
+    pub async fn state_hash(&self) -> Result<StateHash> {
+        self.node.controller().current_state_hash().await
+    }
+
+    pub async fn sync_events(&self) -> Result<SyncEvents> {
+        let events = self.node.controller().signed_events().await?;
+        Ok(SyncEvents { events })
+    }
+
+    pub async fn snapshot(&self, state_hash: &StateHash) -> Result<Snapshot> {
+        self.node
+            .snapshot(state_hash)
+            .await?
+            .ok_or_else(|| anyhow!("State of {} has changed", self.url))
+    }
+
+    pub async fn get_immutable(&self, hash: &blake3::Hash) -> Result<ContentBlock> {
+        self.node
+            .immutable_controller()
+            .get(hash)
+            .await
+            .ok_or_else(|| anyhow!("Immutable block {hash} not found"))
+    }
+
+    pub async fn set_immutable(&self, data: ContentBlock) -> Result<blake3::Hash> {
+        Ok(self.node.immutable_controller().set(&data).await)
+    }
+}
+
+/// In-memory nodes that replicate only when told to, for deterministic sync tests
+pub struct LocalCluster {
+    nodes: Vec<LocalConnection>,
+}
+
+impl LocalCluster {
+    pub fn new(size: usize) -> Result<LocalCluster> {
+        let nodes = (0..size)
+            .map(|_| LocalConnection::in_memory())
+            .collect::<Result<_>>()?;
+        Ok(LocalCluster { nodes })
+    }
+
+    pub fn nodes(&self) -> &[LocalConnection] {
+        &self.nodes
+    }
+
+    pub fn connection(&self, index: usize) -> Connection {
+        Connection::Local(self.nodes[index].clone())
+    }
+
+    pub fn connections(&self) -> Vec<Connection> {
+        (0..self.nodes.len())
+            .map(|index| self.connection(index))
+            .collect()
+    }
+
+    pub async fn sync(&self, to: usize, from: usize) -> Result<()> {
+        if to == from {
+            bail!("Node {to} cannot sync with itself");
+        }
+        self.nodes[to].node.sync_with(&self.connection(from)).await
+    }
+
+    /// Has every node import from every other, in order, so all hold the same events
+    pub async fn sync_all(&self) -> Result<()> {
+        for to in 0..self.nodes.len() {
+            for from in 0..self.nodes.len() {
+                if to != from {
+                    self.sync(to, from).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
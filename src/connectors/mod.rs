@@ -1,2 +1,3 @@
 pub mod connection;
 pub mod http;
+pub mod local;
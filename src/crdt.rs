@@ -1,8 +1,404 @@
-use crate::{client::Event, crypto::Signed, models::Value};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
 
-pub fn merge_events(events: Vec<Signed<Event>>) -> Option<Value> {
+use anyhow::{Result, bail};
+use itertools::Itertools;
+use tracing::debug;
+
+use crate::{
+    client::{CrdtState, Event, LwwEntry, SetEvent},
+    crypto::Signed,
+    models::Value,
+};
+
+/// The event that determines an address's state: the highest priority, with ties broken by
+/// event hash so every node picks the same one
+pub fn latest_event(events: &[Signed<Event>]) -> Option<&Signed<Event>> {
     events
         .iter()
-        .max_by_key(|event| event.inner.priority())
-        .and_then(|event| event.inner.value())
+        .max_by_key(|event| (event.inner.priority(), event.inner.hash()))
+}
+
+pub fn merge_events(events: Vec<Signed<Event>>) -> Option<SetEvent> {
+    latest_event(&events).and_then(|event| match &event.inner {
+        Event::Set(event) => Some(event.clone()),
+        Event::Delete(_)
+        | Event::Succession(_)
+        | Event::Revocation(_)
+        | Event::Crdt(_)
+        | Event::Endorsement(_) => None,
+    })
+}
+
+#[derive(Debug)]
+pub enum CrdtValue {
+    /// Wide enough for the totals of any number of keyspaces without overflowing
+    Counter(i128),
+    Set(BTreeSet<String>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl CrdtState {
+    pub fn value_kind(&self) -> CrdtKind {
+        match self {
+            CrdtState::GrowCounter(_) | CrdtState::Counter { .. } => CrdtKind::Counter,
+            CrdtState::OrSet { .. } => CrdtKind::Set,
+            CrdtState::LwwMap(_) => CrdtKind::Map,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CrdtState::GrowCounter(_) => "grow-only counter",
+            CrdtState::Counter { .. } => "counter",
+            CrdtState::OrSet { .. } => "set",
+            CrdtState::LwwMap(_) => "map",
+        }
+    }
+
+    /// Merges another state published by the same keyspace, keeping everything either has seen
+    pub fn merge(&mut self, other: &CrdtState) -> Result<()> {
+        match (self, other) {
+            (CrdtState::GrowCounter(count), CrdtState::GrowCounter(other_count)) => {
+                *count = (*count).max(*other_count);
+            }
+            (
+                CrdtState::Counter {
+                    increments,
+                    decrements,
+                },
+                CrdtState::Counter {
+                    increments: other_increments,
+                    decrements: other_decrements,
+                },
+            ) => {
+                *increments = (*increments).max(*other_increments);
+                *decrements = (*decrements).max(*other_decrements);
+            }
+            (
+                CrdtState::OrSet { adds, removes },
+                CrdtState::OrSet {
+                    adds: other_adds,
+                    removes: other_removes,
+                },
+            ) => {
+                for (element, tags) in other_adds {
+                    adds.entry(element.clone())
+                        .or_default()
+                        .extend(tags.iter().copied());
+                }
+                removes.extend(other_removes.iter().copied());
+            }
+            (CrdtState::LwwMap(entries), CrdtState::LwwMap(other_entries)) => {
+                merge_lww_entries(entries, other_entries);
+            }
+            (state, other) => bail!("Cannot merge a {} into a {}", other.kind(), state.kind()),
+        }
+        Ok(())
+    }
+}
+
+/// Events of a different kind than the newest are skipped
+pub fn merge_replica(events: &[Signed<Event>]) -> Option<(CrdtState, u64)> {
+    let mut crdt_events = events
+        .iter()
+        .filter_map(|event| match &event.inner {
+            Event::Crdt(event) => Some(event),
+            _ => None,
+        })
+        .sorted_by_key(|event| std::cmp::Reverse(event.priority));
+    let newest = crdt_events.next()?;
+    let mut state = newest.state.clone();
+    for event in crdt_events {
+        if state.merge(&event.state).is_err() {
+            debug!("Skipping {} state for {}", event.state.kind(), event.name);
+        }
+    }
+    Some((state, newest.priority))
+}
+
+/// The kinds of value a CRDT can hold, with both kinds of counter counting as one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CrdtKind {
+    Counter,
+    Set,
+    Map,
+}
+
+impl FromStr for CrdtKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> Result<Self> {
+        match kind {
+            "counter" => Ok(CrdtKind::Counter),
+            "set" => Ok(CrdtKind::Set),
+            "map" => Ok(CrdtKind::Map),
+            kind => bail!("Unknown CRDT kind {kind}, expected counter, set or map"),
+        }
+    }
+}
+
+/// Counters add up the keyspaces' totals, while sets and maps merge as if from a single
+/// replica. Without a `kind`, states of several kinds are an error, since any keyspace can
+/// publish a state of another kind under the name.
+pub fn combine_replicas(
+    states: Vec<CrdtState>,
+    kind: Option<CrdtKind>,
+) -> Result<Option<CrdtValue>> {
+    let kind = match kind {
+        Some(kind) => kind,
+        None => {
+            let kinds = states
+                .iter()
+                .map(CrdtState::value_kind)
+                .unique()
+                .collect_vec();
+            match kinds.as_slice() {
+                [] => return Ok(None),
+                [kind] => *kind,
+                _ => bail!("Contributors hold CRDTs of several kinds, choose one to read"),
+            }
+        }
+    };
+    let mut states = states
+        .into_iter()
+        .filter(|state| state.value_kind() == kind);
+    let Some(first) = states.next() else {
+        return Ok(None);
+    };
+    let mut total = counter_total(&first);
+    let mut combined = first;
+    for state in states {
+        match kind {
+            CrdtKind::Counter => total += counter_total(&state),
+            CrdtKind::Set | CrdtKind::Map => combined
+                .merge(&state)
+                .expect("States of the same kind always merge"),
+        }
+    }
+    Ok(Some(match combined {
+        CrdtState::GrowCounter(_) | CrdtState::Counter { .. } => CrdtValue::Counter(total),
+        CrdtState::OrSet { adds, removes } => CrdtValue::Set(
+            adds.into_iter()
+                .filter(|(_, tags)| tags.iter().any(|tag| !removes.contains(tag)))
+                .map(|(element, _)| element)
+                .collect(),
+        ),
+        CrdtState::LwwMap(entries) => CrdtValue::Map(
+            entries
+                .into_iter()
+                .filter_map(|(key, entry)| entry.value.map(|value| (key, value)))
+                .collect(),
+        ),
+    }))
+}
+
+fn counter_total(state: &CrdtState) -> i128 {
+    match state {
+        CrdtState::GrowCounter(count) => i128::from(*count),
+        CrdtState::Counter {
+            increments,
+            decrements,
+        } => i128::from(*increments) - i128::from(*decrements),
+        CrdtState::OrSet { .. } | CrdtState::LwwMap(_) => 0,
+    }
+}
+
+fn merge_lww_entries(
+    entries: &mut BTreeMap<String, LwwEntry>,
+    others: &BTreeMap<String, LwwEntry>,
+) {
+    for (key, other) in others {
+        match entries.get(key) {
+            Some(entry) if lww_order(entry) >= lww_order(other) => {}
+            _ => {
+                entries.insert(key.clone(), other.clone());
+            }
+        }
+    }
+}
+
+/// Newest timestamp wins, with ties broken by the value's hash so replicas agree
+fn lww_order(entry: &LwwEntry) -> (u64, [u8; 32]) {
+    let value_hash = match &entry.value {
+        Some(value) => *blake3::hash(value.as_bytes()).as_bytes(),
+        None => [0; 32],
+    };
+    (entry.timestamp, value_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::CrdtEvent, crypto::CryptoKey, models::Name};
+
+    fn crdt_event(key: &mut CryptoKey, priority: u64, state: CrdtState) -> Signed<Event> {
+        key.sign(Event::Crdt(CrdtEvent {
+            name: Name::new("crdt".to_string()),
+            priority,
+            state,
+        }))
+    }
+
+    fn or_set(adds: &[(&str, u8)], removes: &[u8]) -> CrdtState {
+        let mut added: BTreeMap<String, BTreeSet<[u8; 16]>> = BTreeMap::new();
+        for (element, tag) in adds {
+            added
+                .entry(element.to_string())
+                .or_default()
+                .insert([*tag; 16]);
+        }
+        CrdtState::OrSet {
+            adds: added,
+            removes: removes.iter().map(|tag| [*tag; 16]).collect(),
+        }
+    }
+
+    fn lww_map(entries: &[(&str, Option<&str>, u64)]) -> CrdtState {
+        CrdtState::LwwMap(
+            entries
+                .iter()
+                .map(|(key, value, timestamp)| {
+                    let entry = LwwEntry {
+                        value: value.map(|value| Value::new(value.as_bytes().to_vec())),
+                        timestamp: *timestamp,
+                    };
+                    (key.to_string(), entry)
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn merges_states_of_one_replica() {
+        let mut counter = CrdtState::Counter {
+            increments: 5,
+            decrements: 1,
+        };
+        let other = CrdtState::Counter {
+            increments: 3,
+            decrements: 2,
+        };
+        counter.merge(&other).unwrap();
+        assert_eq!(counter_total(&counter), 3);
+        assert!(counter.merge(&CrdtState::GrowCounter(1)).is_err());
+
+        let mut set = or_set(&[("a", 1)], &[]);
+        set.merge(&or_set(&[("a", 2), ("b", 3)], &[1])).unwrap();
+        let CrdtState::OrSet { adds, removes } = set else {
+            panic!("Merged into a different kind");
+        };
+        assert_eq!(adds["a"].len(), 2);
+        assert!(removes.contains(&[1; 16]));
+    }
+
+    #[test]
+    fn merges_replica_from_its_newest_kind() {
+        let mut key = CryptoKey::generate();
+        let events = [
+            crdt_event(&mut key, 1, CrdtState::GrowCounter(7)),
+            crdt_event(&mut key, 3, CrdtState::GrowCounter(4)),
+            crdt_event(&mut key, 2, or_set(&[("a", 1)], &[])),
+        ];
+        let (state, priority) = merge_replica(&events).unwrap();
+        assert_eq!(priority, 3);
+        assert_eq!(counter_total(&state), 7);
+        assert!(merge_replica(&[]).is_none());
+    }
+
+    #[test]
+    fn combines_counters_without_overflowing() {
+        let grow_counters = vec![
+            CrdtState::GrowCounter(u64::MAX),
+            CrdtState::GrowCounter(u64::MAX),
+        ];
+        let Some(CrdtValue::Counter(total)) = combine_replicas(grow_counters, None).unwrap() else {
+            panic!("Expected a counter");
+        };
+        assert_eq!(total, 2 * i128::from(u64::MAX));
+
+        let decremented = CrdtState::Counter {
+            increments: 0,
+            decrements: u64::MAX,
+        };
+        let Some(CrdtValue::Counter(total)) =
+            combine_replicas(vec![decremented.clone(), decremented], None).unwrap()
+        else {
+            panic!("Expected a counter");
+        };
+        assert_eq!(total, -2 * i128::from(u64::MAX));
+    }
+
+    #[test]
+    fn combines_sets_and_maps_across_replicas() {
+        let sets = vec![
+            or_set(&[("kept", 1), ("removed", 2)], &[]),
+            or_set(&[("readded", 3)], &[2]),
+        ];
+        let Some(CrdtValue::Set(elements)) = combine_replicas(sets, None).unwrap() else {
+            panic!("Expected a set");
+        };
+        assert_eq!(elements.into_iter().collect_vec(), ["kept", "readded"]);
+
+        let maps = vec![
+            lww_map(&[("newer", Some("old"), 1), ("deleted", Some("value"), 1)]),
+            lww_map(&[("newer", Some("new"), 2), ("deleted", None, 2)]),
+        ];
+        let Some(CrdtValue::Map(entries)) = combine_replicas(maps, None).unwrap() else {
+            panic!("Expected a map");
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries["newer"].as_bytes(), b"new");
+    }
+
+    #[test]
+    fn breaks_timestamp_ties_the_same_way_everywhere() {
+        let first = lww_map(&[("key", Some("a"), 1)]);
+        let second = lww_map(&[("key", Some("b"), 1)]);
+        let mut merged = first.clone();
+        merged.merge(&second).unwrap();
+        let mut reversed = second;
+        reversed.merge(&first).unwrap();
+        let (CrdtState::LwwMap(merged), CrdtState::LwwMap(reversed)) = (merged, reversed) else {
+            panic!("Merged into a different kind");
+        };
+        assert_eq!(
+            merged["key"].value.as_ref().unwrap().as_bytes(),
+            reversed["key"].value.as_ref().unwrap().as_bytes()
+        );
+    }
+
+    #[test]
+    fn combines_only_the_chosen_kind() {
+        // The newest state is a foreign set published under the name of a counter
+        let states = vec![
+            or_set(&[("spam", 1)], &[]),
+            CrdtState::GrowCounter(2),
+            CrdtState::Counter {
+                increments: 5,
+                decrements: 1,
+            },
+        ];
+        assert!(combine_replicas(states.clone(), None).is_err());
+        let Some(CrdtValue::Counter(total)) =
+            combine_replicas(states.clone(), Some(CrdtKind::Counter)).unwrap()
+        else {
+            panic!("Expected a counter");
+        };
+        assert_eq!(total, 6);
+        let Some(CrdtValue::Set(elements)) =
+            combine_replicas(states.clone(), Some(CrdtKind::Set)).unwrap()
+        else {
+            panic!("Expected a set");
+        };
+        assert_eq!(elements.into_iter().collect_vec(), ["spam"]);
+        assert!(
+            combine_replicas(states, Some(CrdtKind::Map))
+                .unwrap()
+                .is_none()
+        );
+        assert!(combine_replicas(Vec::new(), None).unwrap().is_none());
+    }
 }
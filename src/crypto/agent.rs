@@ -0,0 +1,354 @@
+use std::{
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH, Signature, VerifyingKey};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+use tracing::{debug, info, warn};
+
+use super::{CryptoKey, Signer, encode::encode_verifying_key};
+
+// Message numbers from the ssh-agent protocol (draft-miller-ssh-agent)
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+const SSH_ED25519: &[u8] = b"ssh-ed25519";
+
+/// Largest message accepted from the other end of the socket
+const MAX_MESSAGE_LENGTH: usize = 256 * 1024;
+
+/// Signs with an ed25519 key held by an ssh-agent listening on a Unix socket
+pub struct AgentSigner {
+    socket_path: PathBuf,
+    verifying_key: VerifyingKey,
+}
+
+impl AgentSigner {
+    /// Uses the agent's key matching `verifying_key`, or its first ed25519 key if none is given
+    pub async fn connect(
+        socket_path: &Path,
+        verifying_key: Option<&VerifyingKey>,
+    ) -> Result<AgentSigner> {
+        let identities = Self::identities(socket_path).await?;
+        let verifying_key = match verifying_key {
+            Some(verifying_key) => identities
+                .into_iter()
+                .find(|identity| identity == verifying_key)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Agent does not hold key {}",
+                        encode_verifying_key(verifying_key)
+                    )
+                })?,
+            None => identities
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("Agent holds no ed25519 keys"))?,
+        };
+        Ok(AgentSigner {
+            socket_path: socket_path.to_path_buf(),
+            verifying_key,
+        })
+    }
+
+    /// The ed25519 keys held by the agent, skipping keys of other types
+    pub async fn identities(socket_path: &Path) -> Result<Vec<VerifyingKey>> {
+        let response = request(socket_path, &[SSH_AGENTC_REQUEST_IDENTITIES]).await?;
+        let mut reader = Reader::new(&response);
+        if reader.byte()? != SSH_AGENT_IDENTITIES_ANSWER {
+            bail!("Agent refused to list identities");
+        }
+        let count = reader.u32()?;
+        let mut identities = Vec::new();
+        for _ in 0..count {
+            let key_blob = reader.string()?;
+            let _comment = reader.string()?;
+            if let Ok(verifying_key) = decode_key_blob(key_blob) {
+                identities.push(verifying_key);
+            }
+        }
+        Ok(identities)
+    }
+}
+
+#[async_trait]
+impl Signer for AgentSigner {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.verifying_key
+    }
+
+    async fn sign_bytes(&self, message: &[u8]) -> Result<Signature> {
+        let mut body = vec![SSH_AGENTC_SIGN_REQUEST];
+        put_string(&mut body, &encode_key_blob(&self.verifying_key));
+        put_string(&mut body, message);
+        body.extend(0u32.to_be_bytes());
+
+        let response = request(&self.socket_path, &body).await?;
+        let mut reader = Reader::new(&response);
+        if reader.byte()? != SSH_AGENT_SIGN_RESPONSE {
+            bail!("Agent refused to sign");
+        }
+        let mut signature_blob = Reader::new(reader.string()?);
+        if signature_blob.string()? != SSH_ED25519 {
+            bail!("Agent returned a signature that is not ed25519");
+        }
+        let signature: [u8; SIGNATURE_LENGTH] = signature_blob
+            .string()?
+            .try_into()
+            .map_err(|_e| anyhow!("Expected signature to be {SIGNATURE_LENGTH} length"))?;
+        let signature = Signature::from_bytes(&signature);
+        self.verifying_key
+            .verify_strict(message, &signature)
+            .context("Agent returned an invalid signature")?;
+        Ok(signature)
+    }
+}
+
+/// Minimal ssh-agent serving the given keys on a Unix socket, for testing without a real agent
+pub async fn serve(socket_path: &Path, keys: Vec<CryptoKey>) -> Result<()> {
+    // Bind inside a directory only we can enter and move the socket into place once it is
+    // restricted, so nobody else can connect in between
+    let private_dir = socket_path.with_extension("bind");
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", private_dir.display()))?;
+    let listener = bind_private(&private_dir, socket_path).await;
+    tokio::fs::remove_dir_all(&private_dir).await?;
+    let listener = listener
+        .with_context(|| format!("Failed to bind agent socket {}", socket_path.display()))?;
+    info!("Agent listening on {}", socket_path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let keys = keys.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, &keys).await {
+                warn!("Agent connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn bind_private(private_dir: &Path, socket_path: &Path) -> Result<UnixListener> {
+    let bound_path = private_dir.join("agent.sock");
+    let listener = UnixListener::bind(&bound_path)?;
+    tokio::fs::set_permissions(&bound_path, Permissions::from_mode(0o600)).await?;
+    tokio::fs::rename(&bound_path, socket_path).await?;
+    Ok(listener)
+}
+
+async fn serve_connection(mut stream: UnixStream, keys: &[CryptoKey]) -> Result<()> {
+    while let Some(message) = read_message(&mut stream).await? {
+        let response = handle_message(&message, keys).unwrap_or_else(|e| {
+            debug!("Agent request failed: {:?}", e);
+            vec![SSH_AGENT_FAILURE]
+        });
+        write_message(&mut stream, &response).await?;
+    }
+    Ok(())
+}
+
+fn handle_message(message: &[u8], keys: &[CryptoKey]) -> Result<Vec<u8>> {
+    let mut reader = Reader::new(message);
+    match reader.byte()? {
+        SSH_AGENTC_REQUEST_IDENTITIES => {
+            let mut response = vec![SSH_AGENT_IDENTITIES_ANSWER];
+            response.extend((keys.len() as u32).to_be_bytes());
+            for key in keys {
+                put_string(&mut response, &encode_key_blob(&key.verifying()));
+                put_string(
+                    &mut response,
+                    encode_verifying_key(&key.verifying()).as_bytes(),
+                );
+            }
+            Ok(response)
+        }
+        SSH_AGENTC_SIGN_REQUEST => {
+            let verifying_key = decode_key_blob(reader.string()?)?;
+            let data = reader.string()?;
+            let key = keys
+                .iter()
+                .find(|key| key.verifying() == verifying_key)
+                .ok_or_else(|| anyhow!("Unknown key"))?;
+            let mut signature_blob = Vec::new();
+            put_string(&mut signature_blob, SSH_ED25519);
+            put_string(&mut signature_blob, &key.sign_message(data).to_bytes());
+            let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+            put_string(&mut response, &signature_blob);
+            Ok(response)
+        }
+        message_type => bail!("Unsupported agent message {message_type}"),
+    }
+}
+
+async fn request(socket_path: &Path, body: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket_path)
+        .await
+        .with_context(|| format!("Failed to connect to agent {}", socket_path.display()))?;
+    write_message(&mut stream, body).await?;
+    read_message(&mut stream)
+        .await?
+        .ok_or_else(|| anyhow!("Agent closed the connection"))
+}
+
+/// Reads a length-prefixed message, returning None when the peer hangs up between messages
+async fn read_message(stream: &mut UnixStream) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        bail!("Agent message of {length} bytes is too long");
+    }
+    let mut message = vec![0u8; length];
+    stream.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+async fn write_message(stream: &mut UnixStream, body: &[u8]) -> Result<()> {
+    let mut message = Vec::with_capacity(body.len() + 4);
+    put_string(&mut message, body);
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+fn put_string(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend((bytes.len() as u32).to_be_bytes());
+    buffer.extend(bytes);
+}
+
+fn encode_key_blob(verifying_key: &VerifyingKey) -> Vec<u8> {
+    let mut blob = Vec::new();
+    put_string(&mut blob, SSH_ED25519);
+    put_string(&mut blob, verifying_key.as_bytes());
+    blob
+}
+
+fn decode_key_blob(blob: &[u8]) -> Result<VerifyingKey> {
+    let mut reader = Reader::new(blob);
+    if reader.string()? != SSH_ED25519 {
+        bail!("Not an ed25519 key");
+    }
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = reader
+        .string()?
+        .try_into()
+        .map_err(|_e| anyhow!("Expected public key to be {PUBLIC_KEY_LENGTH} length"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// Cursor over the big-endian integers and length-prefixed strings of the agent protocol
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < length {
+            bail!("Truncated agent message");
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn signs_with_served_keys() {
+        let key = CryptoKey::generate();
+        let other = CryptoKey::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.sock");
+        let server = tokio::spawn({
+            let socket_path = socket_path.clone();
+            let keys = vec![key.clone(), other.clone()];
+            async move { serve(&socket_path, keys).await }
+        });
+        while !socket_path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            AgentSigner::identities(&socket_path).await.unwrap(),
+            vec![key.verifying(), other.verifying()]
+        );
+        // The agent only answers once it has cleaned up where it bound the socket
+        assert!(!socket_path.with_extension("bind").exists());
+        let signer = AgentSigner::connect(&socket_path, Some(&other.verifying()))
+            .await
+            .unwrap();
+        let signature = signer.sign_bytes(b"message").await.unwrap();
+        assert!(
+            other
+                .verifying()
+                .verify_strict(b"message", &signature)
+                .is_ok()
+        );
+        let unknown = CryptoKey::generate().verifying();
+        assert!(
+            AgentSigner::connect(&socket_path, Some(&unknown))
+                .await
+                .is_err()
+        );
+
+        server.abort();
+    }
+
+    #[test]
+    fn refuses_unknown_and_malformed_requests() {
+        let keys = vec![CryptoKey::generate()];
+        let mut sign_request = vec![SSH_AGENTC_SIGN_REQUEST];
+        put_string(
+            &mut sign_request,
+            &encode_key_blob(&CryptoKey::generate().verifying()),
+        );
+        put_string(&mut sign_request, b"message");
+        assert!(handle_message(&sign_request, &keys).is_err());
+        assert!(handle_message(&[SSH_AGENTC_SIGN_REQUEST, 0, 0, 0, 9], &keys).is_err());
+        assert!(handle_message(&[SSH_AGENT_FAILURE], &keys).is_err());
+        assert!(handle_message(&[], &keys).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::{Result, anyhow};
+use bincode::{Decode, Encode, config::standard};
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::api::MAX_BODY_SIZE;
+
+use super::{
+    Signable, Signed,
+    encode::{bytes_to_string, string_to_bytes},
+};
+
+/// Certificate signed by a keyspace owner granting another key write access to the keyspace
+#[derive(Clone, Debug, Encode, Decode, Deserialize, Serialize)]
+pub struct Delegation {
+    pub delegate: [u8; PUBLIC_KEY_LENGTH],
+    /// Only names starting with this prefix may be written
+    pub name_prefix: Option<String>,
+    /// Only events with a priority up to this unix timestamp are accepted
+    pub expires_at: Option<u64>,
+}
+
+impl Signable for Delegation {}
+
+impl Delegation {
+    pub fn delegate(&self) -> Option<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.delegate).ok()
+    }
+
+    pub fn allows(&self, name: &str, priority: u64) -> bool {
+        let prefix_allowed = self
+            .name_prefix
+            .as_ref()
+            .is_none_or(|prefix| name.starts_with(prefix));
+        let unexpired = self
+            .expires_at
+            .is_none_or(|expires_at| priority <= expires_at);
+        prefix_allowed && unexpired
+    }
+}
+
+pub fn encode_delegation(delegation: &Signed<Delegation>) -> Result<String> {
+    let bytes = bincode::encode_to_vec(delegation, standard())?;
+    Ok(bytes_to_string(&bytes))
+}
+
+pub fn decode_delegation(encoded: &str) -> Result<Signed<Delegation>> {
+    let bytes = string_to_bytes(encoded.trim())?;
+    bincode::decode_from_slice(&bytes, standard().with_limit::<MAX_BODY_SIZE>())
+        .map(|(delegation, _)| delegation)
+        .map_err(|e| anyhow!("Failed to decode delegation: {e}"))
+}
@@ -0,0 +1,186 @@
+use anyhow::{Result, anyhow, bail};
+use bincode::{Decode, Encode, config::standard};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit, Nonce, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::api::MAX_BODY_SIZE;
+
+const ENVELOPE_VERSION: u8 = 1;
+const WRAP_INFO: &[u8] = b"baybridge envelope v1";
+
+/// Encrypted value format, stored bincode-encoded as the bytes of a value.
+///
+/// The plaintext is encrypted once with XChaCha20-Poly1305 under a random content key.
+/// For each recipient, the content key is wrapped with ChaCha20-Poly1305 under a key
+/// derived with HKDF-SHA256 from X25519(ephemeral secret, recipient), where the recipient's
+/// X25519 public key is the Montgomery form of their ed25519 verifying key. The recipients'
+/// verifying keys are stored in the clear so readers can find their wrapped key.
+#[derive(Encode, Decode)]
+struct Envelope {
+    version: u8,
+    ephemeral_public: [u8; 32],
+    recipients: Vec<WrappedKey>,
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Encode, Decode)]
+struct WrappedKey {
+    recipient: [u8; 32],
+    wrapped_key: Vec<u8>,
+}
+
+fn x25519_public(verifying_key: &VerifyingKey) -> PublicKey {
+    PublicKey::from(verifying_key.to_montgomery().to_bytes())
+}
+
+fn x25519_secret(signing_key: &SigningKey) -> StaticSecret {
+    StaticSecret::from(signing_key.to_scalar_bytes())
+}
+
+fn wrapping_cipher(
+    shared_secret: &[u8; 32],
+    ephemeral_public: &PublicKey,
+    recipient_public: &PublicKey,
+) -> Result<ChaCha20Poly1305> {
+    let info = [
+        WRAP_INFO,
+        ephemeral_public.as_bytes(),
+        recipient_public.as_bytes(),
+    ]
+    .concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(&info, &mut key)
+        .map_err(|e| anyhow!("Failed to derive wrapping key: {e}"))?;
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+pub fn seal(plaintext: &[u8], recipients: &[VerifyingKey]) -> Result<Vec<u8>> {
+    if recipients.is_empty() {
+        bail!("Encrypted values need at least one recipient");
+    }
+
+    let content_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(&content_key)
+        .encrypt(&nonce, plaintext)
+        .map_err(|e| anyhow!("Failed to encrypt value: {e}"))?;
+
+    let ephemeral_secret = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral_secret);
+    let recipients = recipients
+        .iter()
+        .map(|recipient| {
+            let recipient_public = x25519_public(recipient);
+            let shared_secret = ephemeral_secret.diffie_hellman(&recipient_public);
+            let cipher = wrapping_cipher(
+                shared_secret.as_bytes(),
+                &ephemeral_public,
+                &recipient_public,
+            )?;
+            // The wrapping key is unique to this envelope and recipient, so the fixed nonce is never reused
+            let wrapped_key = cipher
+                .encrypt(&Nonce::default(), content_key.as_slice())
+                .map_err(|e| anyhow!("Failed to wrap content key: {e}"))?;
+            Ok(WrappedKey {
+                recipient: recipient.to_bytes(),
+                wrapped_key,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let envelope = Envelope {
+        version: ENVELOPE_VERSION,
+        ephemeral_public: ephemeral_public.to_bytes(),
+        recipients,
+        nonce: nonce.into(),
+        ciphertext,
+    };
+    Ok(bincode::encode_to_vec(&envelope, standard())?)
+}
+
+pub fn open(sealed: &[u8], signing_key: &SigningKey) -> Result<Vec<u8>> {
+    let (envelope, _): (Envelope, usize) =
+        bincode::decode_from_slice(sealed, standard().with_limit::<MAX_BODY_SIZE>())?;
+    if envelope.version != ENVELOPE_VERSION {
+        bail!("Unsupported envelope version {}", envelope.version);
+    }
+
+    let verifying_key = signing_key.verifying_key().to_bytes();
+    let wrapped = envelope
+        .recipients
+        .iter()
+        .find(|wrapped| wrapped.recipient == verifying_key)
+        .ok_or_else(|| anyhow!("Not a recipient of this value"))?;
+
+    let secret = x25519_secret(signing_key);
+    let ephemeral_public = PublicKey::from(envelope.ephemeral_public);
+    let shared_secret = secret.diffie_hellman(&ephemeral_public);
+    let cipher = wrapping_cipher(
+        shared_secret.as_bytes(),
+        &ephemeral_public,
+        &PublicKey::from(&secret),
+    )?;
+    let content_key = cipher
+        .decrypt(&Nonce::default(), wrapped.wrapped_key.as_slice())
+        .map_err(|e| anyhow!("Failed to unwrap content key: {e}"))?;
+
+    XChaCha20Poly1305::new_from_slice(&content_key)?
+        .decrypt(
+            XNonce::from_slice(&envelope.nonce),
+            envelope.ciphertext.as_slice(),
+        )
+        .map_err(|e| anyhow!("Failed to decrypt value: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::generate(&mut OsRng)
+    }
+
+    #[test]
+    fn opens_for_every_recipient() {
+        let alice = signing_key();
+        let sealed = seal(b"secret", &[alice.verifying_key()]).unwrap();
+        assert_eq!(open(&sealed, &alice).unwrap(), b"secret");
+
+        let recipients = [signing_key(), signing_key(), signing_key()];
+        let verifying_keys = recipients.each_ref().map(SigningKey::verifying_key);
+        let sealed = seal(b"shared secret", &verifying_keys).unwrap();
+        for recipient in &recipients {
+            assert_eq!(open(&sealed, recipient).unwrap(), b"shared secret");
+        }
+    }
+
+    #[test]
+    fn refuses_non_recipients() {
+        let sealed = seal(b"secret", &[signing_key().verifying_key()]).unwrap();
+        assert!(open(&sealed, &signing_key()).is_err());
+        assert!(seal(b"secret", &[]).is_err());
+    }
+
+    #[test]
+    fn refuses_tampered_envelopes() {
+        let alice = signing_key();
+        let sealed = seal(b"secret", &[alice.verifying_key()]).unwrap();
+
+        let mut ciphertext = sealed.clone();
+        *ciphertext.last_mut().unwrap() ^= 1;
+        assert!(open(&ciphertext, &alice).is_err());
+
+        // The ephemeral public key follows the version byte
+        let mut header = sealed;
+        header[1] ^= 1;
+        assert!(open(&header, &alice).is_err());
+    }
+}
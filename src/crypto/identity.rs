@@ -0,0 +1,130 @@
+use anyhow::{Result, bail};
+use ed25519_dalek::VerifyingKey;
+
+use crate::configuration::{Configuration, is_valid_identity_name};
+
+use super::{CryptoKey, key::write_key_file};
+
+pub async fn list(config: &Configuration) -> Result<Vec<String>> {
+    let mut entries = tokio::fs::read_dir(config.identities_dir()).await?;
+    let mut identities = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(name) = entry.file_name().to_str()
+            && is_valid_identity_name(name)
+        {
+            identities.push(name.to_string());
+        }
+    }
+    identities.sort();
+    Ok(identities)
+}
+
+pub async fn create(
+    config: &Configuration,
+    identity: &str,
+    min_key_work: u32,
+) -> Result<VerifyingKey> {
+    let path = config.identity_key_path(identity)?;
+    if path.exists() {
+        bail!("Identity {identity} already exists");
+    }
+    let crypto_key =
+        tokio::task::spawn_blocking(move || CryptoKey::generate_with_work(min_key_work)).await?;
+    crypto_key.save(&path, config.key_passphrase()).await?;
+    Ok(crypto_key.verifying())
+}
+
+/// Makes an identity the one used when `--identity` is not given
+pub async fn select(config: &Configuration, identity: &str) -> Result<()> {
+    if !config.identity_key_path(identity)?.exists() {
+        bail!("Identity {identity} does not exist");
+    }
+    tokio::fs::write(config.current_identity_path(), identity).await?;
+    Ok(())
+}
+
+/// The identity's key file contents, still encrypted if it is passphrase-protected
+pub async fn export(config: &Configuration, identity: &str) -> Result<String> {
+    let path = config.identity_key_path(identity)?;
+    if !path.exists() {
+        bail!("Identity {identity} does not exist");
+    }
+    Ok(tokio::fs::read_to_string(path).await?)
+}
+
+pub async fn import(config: &Configuration, identity: &str, contents: &str) -> Result<()> {
+    let path = config.identity_key_path(identity)?;
+    if path.exists() {
+        bail!("Identity {identity} already exists");
+    }
+    if !CryptoKey::is_key_file(contents) {
+        bail!("Not a signing key file");
+    }
+    write_key_file(&path, contents).await
+}
+
+pub async fn remove(config: &Configuration, identity: &str) -> Result<()> {
+    if config.identity_name() == identity {
+        bail!("Cannot remove the identity currently in use");
+    }
+    let path = config.identity_key_path(identity)?;
+    if !path.exists() {
+        bail!("Identity {identity} does not exist");
+    }
+    tokio::fs::remove_file(path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::DEFAULT_IDENTITY;
+
+    async fn config() -> (Configuration, tempfile::TempDir) {
+        let base_dir = tempfile::tempdir().unwrap();
+        let config = Configuration::new(base_dir.path().to_path_buf(), vec![]);
+        config.init().await.unwrap();
+        (config, base_dir)
+    }
+
+    #[tokio::test]
+    async fn refuses_duplicate_identities() {
+        let (config, _base_dir) = config().await;
+        let work = create(&config, "work", 0).await.unwrap();
+        assert!(create(&config, "work", 0).await.is_err());
+        assert!(create(&config, "../work", 0).await.is_err());
+
+        let contents = export(&config, "work").await.unwrap();
+        assert!(import(&config, "work", &contents).await.is_err());
+        import(&config, "copy", &contents).await.unwrap();
+        let copy = CryptoKey::from_identity(&config, "copy").await.unwrap();
+        assert_eq!(copy.verifying(), work);
+        assert_eq!(list(&config).await.unwrap(), vec!["copy", "work"]);
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_imports() {
+        let (config, _base_dir) = config().await;
+        for contents in ["", "not a key", "AAAA"] {
+            assert!(import(&config, "broken", contents).await.is_err());
+        }
+        assert!(!config.identity_key_path("broken").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn never_removes_the_selected_identity() {
+        let (config, _base_dir) = config().await;
+        assert_eq!(config.identity_name(), DEFAULT_IDENTITY);
+        assert!(select(&config, "work").await.is_err());
+        create(&config, "work", 0).await.unwrap();
+        create(&config, "home", 0).await.unwrap();
+
+        select(&config, "work").await.unwrap();
+        assert_eq!(config.identity_name(), "work");
+        assert!(remove(&config, "work").await.is_err());
+        remove(&config, "home").await.unwrap();
+        assert!(remove(&config, "home").await.is_err());
+        assert!(CryptoKey::from_identity(&config, "home").await.is_err());
+        assert_eq!(list(&config).await.unwrap(), vec!["work"]);
+    }
+}
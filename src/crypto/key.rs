@@ -1,18 +1,46 @@
-use crate::configuration::Configuration;
-use anyhow::{Context, Result, anyhow};
-use bincode::config::standard;
-use ed25519_dalek::{SigningKey, VerifyingKey, ed25519::signature::SignerMut};
-use rand::rngs::OsRng;
+use std::path::Path;
+
+use crate::{
+    configuration::{Configuration, DEFAULT_IDENTITY},
+    models::Value,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::Argon2;
+use bincode::{Decode, Encode, config::standard};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore},
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::{RngCore, rngs::OsRng};
 
 use tokio::io::AsyncWriteExt;
-use tracing::debug;
+use tracing::{debug, info};
 
 use super::{
     Signed,
     encode::{bytes_to_string, string_to_bytes},
+    envelope,
+    private::PrivateKey,
     signed::Signable,
+    work::key_work,
 };
 
+/// First line of a key file whose signing key is encrypted with a passphrase
+const ENCRYPTED_KEY_HEADER: &str = "baybridge-encrypted-key-v1";
+
+/// Most memory bincode may claim while decoding an encrypted key file
+const MAX_KEY_FILE_SIZE: usize = 1024;
+
+/// Passphrase-encrypted signing key: the passphrase is stretched with Argon2id over the salt
+/// and the key is sealed with XChaCha20-Poly1305
+#[derive(Encode, Decode)]
+struct EncryptedKeyFile {
+    salt: [u8; 16],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
 fn generate_signing_key() -> SigningKey {
     let mut crypto_secure_rng = OsRng;
     SigningKey::generate(&mut crypto_secure_rng)
@@ -30,6 +58,75 @@ fn encode_signing_key(signing_key: &SigningKey) -> String {
     bytes_to_string(signing_key_bytes.as_slice())
 }
 
+fn passphrase_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive key from passphrase: {e}"))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+fn encrypt_signing_key(signing_key: &SigningKey, passphrase: &str) -> Result<String> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = passphrase_cipher(passphrase, &salt)?
+        .encrypt(&nonce, signing_key.as_bytes().as_slice())
+        .map_err(|e| anyhow!("Failed to encrypt signing key: {e}"))?;
+    let key_file = EncryptedKeyFile {
+        salt,
+        nonce: nonce.into(),
+        ciphertext,
+    };
+    let encoded = bytes_to_string(&bincode::encode_to_vec(&key_file, standard())?);
+    Ok(format!("{ENCRYPTED_KEY_HEADER}\n{encoded}\n"))
+}
+
+fn decrypt_signing_key(encoded: &str, passphrase: &str) -> Result<SigningKey> {
+    let bytes = string_to_bytes(encoded.trim())?;
+    let (key_file, _): (EncryptedKeyFile, usize) =
+        bincode::decode_from_slice(&bytes, standard().with_limit::<MAX_KEY_FILE_SIZE>())?;
+    let plaintext = passphrase_cipher(passphrase, &key_file.salt)?
+        .decrypt(
+            XNonce::from_slice(&key_file.nonce),
+            key_file.ciphertext.as_slice(),
+        )
+        .map_err(|_e| anyhow!("Wrong passphrase for signing key"))?;
+    let bytes: [u8; 32] = plaintext
+        .try_into()
+        .map_err(|_e| anyhow!("Expected decrypted key to be 32 length"))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+pub(crate) async fn write_key_file(path: &Path, contents: &str) -> Result<()> {
+    // Write next to the key and rename so a crash never leaves a truncated key behind
+    let temporary_path = path.with_extension("tmp");
+    let mut options = tokio::fs::OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+
+    #[cfg(unix)]
+    {
+        options.mode(0o600);
+    }
+
+    // TODO: set file permissions safely on Windows
+    let mut file = options.open(&temporary_path).await?;
+
+    // The mode above only applies when the file is created, so tighten a leftover one too
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temporary_path, path).await?;
+    Ok(())
+}
+
+#[derive(Clone)]
 pub struct CryptoKey {
     signing_key: SigningKey,
 }
@@ -40,50 +137,208 @@ impl CryptoKey {
     }
 
     pub fn sign<T: Signable>(&mut self, payload: T) -> Signed<T> {
-        let serialized = bincode::encode_to_vec(&payload, standard()).unwrap();
-        let signature = self.signing_key.sign(&serialized);
+        let signature = self.sign_message(&payload.signing_bytes());
         Signed::new(payload, self.verifying(), signature)
     }
 
-    async fn generate_new(config: &Configuration) -> Self {
-        let signing_key = generate_signing_key();
+    pub fn sign_message(&self, message: &[u8]) -> Signature {
+        self.signing_key.sign(message)
+    }
+
+    pub fn decrypt(&self, value: &Value) -> Result<Value> {
+        envelope::open(value.as_bytes(), &self.signing_key).map(Value::new)
+    }
+
+    pub fn private_key(&self) -> Result<PrivateKey> {
+        PrivateKey::from_signing_key(&self.signing_key)
+    }
+
+    pub fn generate() -> Self {
+        Self {
+            signing_key: generate_signing_key(),
+        }
+    }
+
+    pub fn generate_with_work(min_key_work: u32) -> Self {
+        loop {
+            let crypto_key = Self::generate();
+            if key_work(&crypto_key.verifying()) >= min_key_work {
+                return crypto_key;
+            }
+        }
+    }
+
+    /// Writes the key to a key file, encrypted if a passphrase is given
+    pub async fn save(&self, path: &Path, passphrase: Option<&str>) -> Result<()> {
+        debug!("Saving signing key to {}", path.display());
+        let contents = match passphrase {
+            Some(passphrase) => encrypt_signing_key(&self.signing_key, passphrase)?,
+            None => encode_signing_key(&self.signing_key),
+        };
+        write_key_file(path, &contents).await
+    }
+
+    /// Loads the current identity's key. Only the default identity is generated if it does not
+    /// exist yet, so a mistyped identity name fails instead of creating a new key.
+    pub async fn from_config(config: &Configuration) -> Result<Self> {
+        let identity = config.identity_name();
+        if identity != DEFAULT_IDENTITY {
+            return Self::from_identity(config, &identity).await;
+        }
+        let signing_key_path = config.signing_key_path();
+        match Self::load(config, &signing_key_path).await? {
+            Some(crypto_key) => Ok(crypto_key),
+            None => {
+                let crypto_key = Self::generate();
+                crypto_key
+                    .save(&signing_key_path, config.key_passphrase())
+                    .await?;
+                Ok(crypto_key)
+            }
+        }
+    }
+
+    /// Loads a named identity's key, failing if it does not exist
+    pub async fn from_identity(config: &Configuration, identity: &str) -> Result<Self> {
+        let signing_key_path = config.identity_key_path(identity)?;
+        Self::load(config, &signing_key_path)
+            .await?
+            .ok_or_else(|| anyhow!("Identity {identity} does not exist"))
+    }
+
+    /// Reads a key file, returning None if it does not exist
+    async fn load(config: &Configuration, signing_key_path: &Path) -> Result<Option<Self>> {
+        let contents = match tokio::fs::read_to_string(signing_key_path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read signing key {}", signing_key_path.display())
+                });
+            }
+        };
 
         debug!(
-            "Saving new signing key to {}",
-            config.signing_key_path().display()
+            "Using existing signing key from {}",
+            signing_key_path.display()
         );
+        match contents.strip_prefix(ENCRYPTED_KEY_HEADER) {
+            Some(encoded) => {
+                let passphrase = match config.key_passphrase() {
+                    Some(passphrase) => passphrase.to_string(),
+                    None => rpassword::prompt_password(format!(
+                        "Passphrase for {}: ",
+                        signing_key_path.display()
+                    ))
+                    .context("Failed to prompt for signing key passphrase")?,
+                };
+                let signing_key = decrypt_signing_key(encoded, &passphrase)?;
+                Ok(Some(Self { signing_key }))
+            }
+            None => {
+                let signing_key = decode_signing_key(contents.trim()).with_context(|| {
+                    format!(
+                        "Refusing to replace unreadable signing key {}",
+                        signing_key_path.display()
+                    )
+                })?;
+                let crypto_key = Self { signing_key };
+                if let Some(passphrase) = config.key_passphrase() {
+                    info!(
+                        "Encrypting plaintext signing key {} with passphrase",
+                        signing_key_path.display()
+                    );
+                    crypto_key.save(signing_key_path, Some(passphrase)).await?;
+                }
+                Ok(Some(crypto_key))
+            }
+        }
+    }
+
+    /// Checks that key file contents hold a plaintext or passphrase-encrypted signing key
+    pub fn is_key_file(contents: &str) -> bool {
+        contents.starts_with(ENCRYPTED_KEY_HEADER) || decode_signing_key(contents.trim()).is_ok()
+    }
 
-        let mut options = tokio::fs::OpenOptions::new();
-        options.create(true).truncate(true).write(true);
+    /// Re-encrypts the current identity's key file with a new passphrase, prompting when none is given
+    pub async fn protect(config: &Configuration, passphrase: Option<String>) -> Result<()> {
+        let crypto_key = Self::from_config(config).await?;
+        let passphrase = match passphrase {
+            Some(passphrase) => passphrase,
+            None => {
+                let passphrase = rpassword::prompt_password("New passphrase: ")?;
+                let confirmation = rpassword::prompt_password("Confirm passphrase: ")?;
+                if passphrase != confirmation {
+                    bail!("Passphrases do not match");
+                }
+                passphrase
+            }
+        };
+        if passphrase.is_empty() {
+            bail!("Passphrase must not be empty");
+        }
+        crypto_key
+            .save(&config.signing_key_path(), Some(&passphrase))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(passphrase: Option<&str>) -> (Configuration, tempfile::TempDir) {
+        let base_dir = tempfile::tempdir().unwrap();
+        let config = Configuration::new(base_dir.path().to_path_buf(), vec![])
+            .with_key_passphrase(passphrase.map(str::to_string));
+        std::fs::create_dir_all(config.identities_dir()).unwrap();
+        (config, base_dir)
+    }
 
+    #[tokio::test]
+    async fn reads_encrypted_key_files() {
+        let (config, _base_dir) = config(Some("correct horse"));
+        let path = config.signing_key_path();
+        // A temporary file left behind by an interrupted write keeps its looser mode
+        std::fs::write(path.with_extension("tmp"), "").unwrap();
+        let crypto_key = CryptoKey::generate();
+        crypto_key
+            .save(&path, config.key_passphrase())
+            .await
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with(ENCRYPTED_KEY_HEADER));
+        assert!(CryptoKey::is_key_file(&contents));
         #[cfg(unix)]
         {
-            options.mode(0o600);
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
         }
 
-        // TODO: set file permissions safely on Windows
-        let mut file = options.open(&config.signing_key_path()).await.unwrap();
-        let encoded_key = encode_signing_key(&signing_key);
-        file.write_all(encoded_key.as_bytes()).await.unwrap();
-
-        Self { signing_key }
+        let loaded = CryptoKey::load(&config, &path).await.unwrap().unwrap();
+        assert_eq!(loaded.verifying(), crypto_key.verifying());
     }
 
-    pub async fn from_config(config: &Configuration) -> Self {
-        let signing_key_path = config.signing_key_path();
-        let existing_key = tokio::fs::read_to_string(&signing_key_path)
+    #[tokio::test]
+    async fn refuses_wrong_passphrases() {
+        let (config, _base_dir) = config(Some("wrong horse"));
+        let path = config.signing_key_path();
+        CryptoKey::generate()
+            .save(&path, Some("correct horse"))
             .await
-            .context("No existing key file found")
-            .and_then(|encoded_key| decode_signing_key(&encoded_key));
-        match existing_key {
-            Ok(signing_key) => {
-                debug!(
-                    "Using existing signing key from {}",
-                    signing_key_path.display()
-                );
-                Self { signing_key }
-            }
-            Err(_) => Self::generate_new(config).await,
-        }
+            .unwrap();
+        assert!(CryptoKey::load(&config, &path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn never_replaces_unreadable_key_files() {
+        let (config, _base_dir) = config(None);
+        let path = config.signing_key_path();
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(CryptoKey::load(&config, &path).await.is_err());
+        assert!(CryptoKey::from_config(&config).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a key");
     }
 }
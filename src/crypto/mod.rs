@@ -1,6 +1,16 @@
+#[cfg(unix)]
+pub mod agent;
+pub mod delegation;
 pub mod encode;
+pub mod envelope;
+pub mod identity;
 mod key;
+mod private;
 mod signed;
+mod signer;
+pub mod work;
 
 pub use key::CryptoKey;
+pub use private::PrivateKey;
 pub use signed::{Signable, Signed};
+pub use signer::Signer;
@@ -0,0 +1,136 @@
+use anyhow::{Result, anyhow};
+use argon2::Argon2;
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::models::{Name, Value};
+
+use super::encode::bytes_to_string;
+
+const ENCRYPTION_INFO: &[u8] = b"baybridge private values v1";
+const NAME_INFO: &[u8] = b"baybridge private names v1";
+const NONCE_LENGTH: usize = 24;
+
+/// Symmetric keys for values only their writer can read.
+///
+/// Private values are stored as a 24 byte XChaCha20-Poly1305 nonce followed by the ciphertext.
+/// Hidden names are the base64 HMAC-SHA256 of the name, so replicas cannot learn the key structure.
+pub struct PrivateKey {
+    encryption_key: [u8; 32],
+    name_key: [u8; 32],
+}
+
+impl PrivateKey {
+    fn from_secret(secret: &[u8], salt: Option<&[u8]>) -> Result<Self> {
+        let hkdf = Hkdf::<Sha256>::new(salt, secret);
+        let mut encryption_key = [0u8; 32];
+        let mut name_key = [0u8; 32];
+        hkdf.expand(ENCRYPTION_INFO, &mut encryption_key)
+            .and_then(|_| hkdf.expand(NAME_INFO, &mut name_key))
+            .map_err(|e| anyhow!("Failed to derive private keys: {e}"))?;
+        Ok(PrivateKey {
+            encryption_key,
+            name_key,
+        })
+    }
+
+    pub fn from_signing_key(signing_key: &SigningKey) -> Result<Self> {
+        Self::from_secret(signing_key.as_bytes(), None)
+    }
+
+    /// Derives the keys from a passphrase, salted with the owner's verifying key so the
+    /// same passphrase yields the same keys on every machine using that identity
+    pub fn from_passphrase(passphrase: &str, verifying_key: &VerifyingKey) -> Result<Self> {
+        let mut secret = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), verifying_key.as_bytes(), &mut secret)
+            .map_err(|e| anyhow!("Failed to derive key from passphrase: {e}"))?;
+        Self::from_secret(&secret, Some(verifying_key.as_bytes()))
+    }
+
+    pub fn encrypt(&self, value: &Value) -> Result<Value> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.encryption_key.into())
+            .encrypt(&nonce, value.as_bytes())
+            .map_err(|e| anyhow!("Failed to encrypt private value: {e}"))?;
+        Ok(Value::new([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    pub fn decrypt(&self, value: &Value) -> Result<Value> {
+        let bytes = value.as_bytes();
+        if bytes.len() < NONCE_LENGTH {
+            return Err(anyhow!("Private value is too short"));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        XChaCha20Poly1305::new(&self.encryption_key.into())
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map(Value::new)
+            .map_err(|e| anyhow!("Failed to decrypt private value: {e}"))
+    }
+
+    pub fn hide_name(&self, name: &Name) -> Name {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.name_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(name.as_str().as_bytes());
+        Name::new(bytes_to_string(&mac.finalize().into_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::generate(&mut OsRng)
+    }
+
+    fn name(name: &str) -> Name {
+        Name::new(name.to_string())
+    }
+
+    #[test]
+    fn decrypts_encrypted_values() {
+        let key = PrivateKey::from_signing_key(&signing_key()).unwrap();
+        let value = Value::new(b"secret".to_vec());
+        let encrypted = key.encrypt(&value).unwrap();
+        assert_ne!(encrypted.as_bytes(), value.as_bytes());
+        assert_eq!(key.decrypt(&encrypted).unwrap().as_bytes(), b"secret");
+
+        let other = PrivateKey::from_signing_key(&signing_key()).unwrap();
+        assert!(other.decrypt(&encrypted).is_err());
+        assert!(key.decrypt(&Value::new(vec![0; NONCE_LENGTH - 1])).is_err());
+    }
+
+    #[test]
+    fn derives_the_same_keys_only_from_the_same_passphrase() {
+        let verifying_key = signing_key().verifying_key();
+        let key = PrivateKey::from_passphrase("correct horse", &verifying_key).unwrap();
+        let encrypted = key.encrypt(&Value::new(b"secret".to_vec())).unwrap();
+
+        let again = PrivateKey::from_passphrase("correct horse", &verifying_key).unwrap();
+        assert_eq!(again.decrypt(&encrypted).unwrap().as_bytes(), b"secret");
+        let wrong = PrivateKey::from_passphrase("wrong horse", &verifying_key).unwrap();
+        assert!(wrong.decrypt(&encrypted).is_err());
+        // The verifying key salts the derivation
+        let other_identity =
+            PrivateKey::from_passphrase("correct horse", &signing_key().verifying_key()).unwrap();
+        assert!(other_identity.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn hides_names_deterministically_per_key() {
+        let key = PrivateKey::from_signing_key(&signing_key()).unwrap();
+        let other = PrivateKey::from_signing_key(&signing_key()).unwrap();
+        let hidden = key.hide_name(&name("diary"));
+        assert_ne!(hidden.as_str(), "diary");
+        assert_eq!(key.hide_name(&name("diary")).as_str(), hidden.as_str());
+        assert_ne!(key.hide_name(&name("notes")).as_str(), hidden.as_str());
+        assert_ne!(other.hide_name(&name("diary")).as_str(), hidden.as_str());
+    }
+}
@@ -3,13 +3,26 @@ use bincode::{Decode, Encode};
 use ed25519_dalek::{PUBLIC_KEY_LENGTH, Signature, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
-pub trait Signable: Clone + Encode + Serialize {}
+use super::delegation::Delegation;
+
+pub trait Signable: Clone + Encode + Serialize {
+    /// The bytes a signature covers
+    fn signing_bytes(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, standard()).unwrap()
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize, Encode, Decode)]
 pub struct Signed<T: Signable> {
     pub inner: T,
     verifying_key: [u8; PUBLIC_KEY_LENGTH],
     signature: Vec<u8>,
+    /// Grants the signer write access to the delegating owner's keyspace
+    #[serde(default)]
+    delegation: Option<Box<Signed<Delegation>>>,
+    /// Nonce of a proof-of-work stamp over the payload, not covered by the signature
+    #[serde(default)]
+    stamp: Option<u64>,
 }
 
 impl<T: Signable> Signed<T> {
@@ -18,21 +31,69 @@ impl<T: Signable> Signed<T> {
             inner,
             verifying_key: verifying_key.to_bytes(),
             signature: signature.to_bytes().to_vec(),
+            delegation: None,
+            stamp: None,
+        }
+    }
+
+    pub fn with_delegation(mut self, delegation: Signed<Delegation>) -> Self {
+        self.delegation = Some(Box::new(delegation));
+        self
+    }
+
+    pub fn with_stamp(mut self, stamp: u64) -> Self {
+        self.stamp = Some(stamp);
+        self
+    }
+
+    /// Checks the payload was signed for the given keyspace, either by its owner
+    /// or by a key holding a delegation signed by the owner
+    pub fn verify(&self, keyspace: &VerifyingKey) -> bool {
+        match &self.delegation {
+            None => self.verify_signature(keyspace),
+            Some(delegation) => {
+                delegation.delegation.is_none()
+                    && delegation.verify_signature(keyspace)
+                    && delegation.inner.delegate == self.verifying_key
+                    && self
+                        .verifying_key()
+                        .is_some_and(|verifying_key| self.verify_signature(&verifying_key))
+            }
+        }
+    }
+
+    fn verify_signature(&self, verifying_key: &VerifyingKey) -> bool {
+        self.signature().is_some_and(|signature| {
+            verifying_key
+                .verify_strict(&self.inner.signing_bytes(), &signature)
+                .is_ok()
+        })
+    }
+
+    /// The key that signed the payload, or None if its bytes are not a valid key
+    pub fn verifying_key(&self) -> Option<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.verifying_key).ok()
+    }
+
+    /// The keyspace the payload belongs to: the delegating owner's, or else the signer's.
+    /// None if the key bytes are not a valid key, which only untrusted events can carry.
+    pub fn keyspace(&self) -> Option<VerifyingKey> {
+        match &self.delegation {
+            Some(delegation) => delegation.verifying_key(),
+            None => self.verifying_key(),
         }
     }
 
-    pub fn verify(&self, verifying_key: &VerifyingKey) -> bool {
-        let serialized = bincode::encode_to_vec(&self.inner, standard()).unwrap();
-        verifying_key
-            .verify_strict(&serialized, &self.signature())
-            .is_ok()
+    pub fn delegation(&self) -> Option<&Signed<Delegation>> {
+        self.delegation.as_deref()
     }
 
-    pub fn verifying_key(&self) -> VerifyingKey {
-        VerifyingKey::from_bytes(&self.verifying_key).unwrap()
+    pub fn stamp(&self) -> Option<u64> {
+        self.stamp
     }
 
-    pub fn signature(&self) -> Signature {
-        Signature::from_bytes(&self.signature.as_slice().try_into().unwrap())
+    /// The signature, or None if it has the wrong length
+    pub fn signature(&self) -> Option<Signature> {
+        Signature::from_slice(&self.signature).ok()
     }
 }
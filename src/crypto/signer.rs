@@ -0,0 +1,31 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use super::{CryptoKey, Signable, Signed};
+
+/// Produces ed25519 signatures without the caller needing the signing key in memory
+#[async_trait]
+pub trait Signer: Send + Sync {
+    fn verifying_key(&self) -> VerifyingKey;
+
+    async fn sign_bytes(&self, message: &[u8]) -> Result<Signature>;
+}
+
+impl dyn Signer + '_ {
+    pub async fn sign<T: Signable>(&self, payload: T) -> Result<Signed<T>> {
+        let signature = self.sign_bytes(&payload.signing_bytes()).await?;
+        Ok(Signed::new(payload, self.verifying_key(), signature))
+    }
+}
+
+#[async_trait]
+impl Signer for CryptoKey {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.verifying()
+    }
+
+    async fn sign_bytes(&self, message: &[u8]) -> Result<Signature> {
+        Ok(self.sign_message(message))
+    }
+}
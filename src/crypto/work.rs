@@ -0,0 +1,75 @@
+use ed25519_dalek::VerifyingKey;
+
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Proof of work embodied in a key: the leading zero bits of its hash. Each extra bit doubles
+/// the expected number of keys generated to find one.
+pub fn key_work(verifying_key: &VerifyingKey) -> u32 {
+    leading_zero_bits(blake3::hash(verifying_key.as_bytes()).as_bytes())
+}
+
+/// Events up to this size need the minimum stamp work, and each doubling adds a bit
+pub const STAMP_SIZE_UNIT: usize = 1024;
+
+/// Most stamp work a client mints. Each bit doubles the expected time, so a server demanding
+/// more is not worth waiting for.
+pub const MAX_STAMP_WORK: u32 = 32;
+
+/// Work required of a stamp on an event of `size` bytes, given the minimum for small events
+pub fn required_stamp_work(min_stamp_work: u32, size: usize) -> u32 {
+    let units = size.div_ceil(STAMP_SIZE_UNIT).max(1);
+    min_stamp_work.saturating_add(units.next_power_of_two().ilog2())
+}
+
+/// Proof of work of a hashcash-style stamp: the leading zero bits of the hash of an event hash
+/// followed by the stamp's nonce
+pub fn stamp_work(event_hash: &[u8; 32], nonce: u64) -> u32 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(event_hash);
+    hasher.update(&nonce.to_le_bytes());
+    leading_zero_bits(hasher.finalize().as_bytes())
+}
+
+/// Searches for a nonce giving a stamp with at least `work` bits of work
+pub fn mint_stamp(event_hash: &[u8; 32], work: u32) -> u64 {
+    (0..)
+        .find(|nonce| stamp_work(event_hash, *nonce) >= work)
+        .expect("Exhausted stamp nonces")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+
+    #[test]
+    fn adds_a_bit_per_doubling_in_size() {
+        assert_eq!(required_stamp_work(8, 0), 8);
+        assert_eq!(required_stamp_work(8, STAMP_SIZE_UNIT), 8);
+        assert_eq!(required_stamp_work(8, STAMP_SIZE_UNIT + 1), 9);
+        assert_eq!(required_stamp_work(8, 4 * STAMP_SIZE_UNIT), 10);
+        assert_eq!(required_stamp_work(u32::MAX, 4 * STAMP_SIZE_UNIT), u32::MAX);
+    }
+
+    #[test]
+    fn minted_stamps_have_the_work() {
+        let event_hash = [3; 32];
+        let nonce = mint_stamp(&event_hash, 8);
+        assert!(stamp_work(&event_hash, nonce) >= 8);
+    }
+}
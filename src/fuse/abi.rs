@@ -0,0 +1,338 @@
+use anyhow::{Result, bail};
+
+// Kernel protocol version spoken, from linux/fuse.h
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+pub const FUSE_ROOT_ID: u64 = 1;
+
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_SETATTR: u32 = 4;
+pub const FUSE_MKDIR: u32 = 9;
+pub const FUSE_UNLINK: u32 = 10;
+pub const FUSE_RMDIR: u32 = 11;
+pub const FUSE_RENAME: u32 = 12;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_WRITE: u32 = 16;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FSYNC: u32 = 20;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_FSYNCDIR: u32 = 30;
+pub const FUSE_ACCESS: u32 = 34;
+pub const FUSE_CREATE: u32 = 35;
+pub const FUSE_INTERRUPT: u32 = 36;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+pub const FUSE_RENAME2: u32 = 45;
+
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_FH: u32 = 1 << 6;
+
+pub const MAX_WRITE: u32 = 128 * 1024;
+
+/// Request buffer size, which the kernel requires to hold a maximal write and its headers
+pub const REQUEST_BUFFER_SIZE: usize = MAX_WRITE as usize + 64 * 1024;
+
+pub struct Request<'a> {
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    pub body: Reader<'a>,
+}
+
+impl Request<'_> {
+    pub fn parse(buffer: &[u8]) -> Result<Request<'_>> {
+        let mut header = Reader::new(buffer);
+        let length = header.u32()? as usize;
+        if length != buffer.len() {
+            bail!(
+                "Request length {length} does not match the {} bytes read",
+                buffer.len()
+            );
+        }
+        let opcode = header.u32()?;
+        let unique = header.u64()?;
+        let nodeid = header.u64()?;
+        // uid, gid, pid, total_extlen and padding
+        header.take(16)?;
+        Ok(Request {
+            opcode,
+            unique,
+            nodeid,
+            body: header,
+        })
+    }
+}
+
+pub fn unique(buffer: &[u8]) -> Option<u64> {
+    let bytes = buffer.get(8..16)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < length {
+            bail!("Truncated request");
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn name(&mut self) -> Result<&'a str> {
+        let Some(end) = self.bytes.iter().position(|byte| *byte == 0) else {
+            bail!("Unterminated name");
+        };
+        let name = std::str::from_utf8(self.take(end)?)?;
+        self.take(1)?;
+        Ok(name)
+    }
+}
+
+pub fn reply(unique: u64, error: i32, body: &[u8]) -> Vec<u8> {
+    let mut reply = Vec::with_capacity(16 + body.len());
+    reply.extend(((16 + body.len()) as u32).to_le_bytes());
+    reply.extend((-error).to_le_bytes());
+    reply.extend(unique.to_le_bytes());
+    reply.extend(body);
+    reply
+}
+
+pub fn error(unique: u64, errno: i32) -> Vec<u8> {
+    reply(unique, errno, &[])
+}
+
+#[derive(Clone, Copy)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+const CACHE_TIMEOUT: u64 = 1;
+
+#[derive(Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes.extend(value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes.extend(value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes.extend(value.to_le_bytes());
+        self
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn attr(&mut self, attr: &Attr) -> &mut Self {
+        let nlink = if attr.mode & libc::S_IFDIR != 0 { 2 } else { 1 };
+        self.u64(attr.ino)
+            .u64(attr.size)
+            .u64(attr.size.div_ceil(512))
+            // atime, mtime and ctime, then their nanoseconds
+            .u64(attr.mtime)
+            .u64(attr.mtime)
+            .u64(attr.mtime)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(attr.mode)
+            .u32(nlink)
+            .u32(attr.uid)
+            .u32(attr.gid)
+            // rdev, blksize and flags
+            .u32(0)
+            .u32(4096)
+            .u32(0)
+    }
+
+    pub fn entry_out(&mut self, attr: &Attr) -> &mut Self {
+        self.u64(attr.ino)
+            // generation
+            .u64(0)
+            .u64(CACHE_TIMEOUT)
+            .u64(CACHE_TIMEOUT)
+            .u32(0)
+            .u32(0)
+            .attr(attr)
+    }
+
+    pub fn attr_out(&mut self, attr: &Attr) -> &mut Self {
+        self.u64(CACHE_TIMEOUT).u32(0).u32(0).attr(attr)
+    }
+
+    pub fn open_out(&mut self, fh: u64) -> &mut Self {
+        self.u64(fh).u32(0).u32(0)
+    }
+
+    pub fn dirent(&mut self, size: usize, ino: u64, offset: u64, mode: u32, name: &str) -> bool {
+        let entry_length = (24 + name.len()).next_multiple_of(8);
+        if self.bytes.len() + entry_length > size {
+            return false;
+        }
+        self.u64(ino)
+            .u64(offset)
+            .u32(name.len() as u32)
+            .u32((mode & libc::S_IFMT) >> 12);
+        self.bytes.extend(name.as_bytes());
+        self.bytes
+            .resize(self.bytes.len() + entry_length - 24 - name.len(), 0);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(opcode: u32, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        let mut request = Writer::default();
+        request
+            .u32(40 + body.len() as u32)
+            .u32(opcode)
+            .u64(unique)
+            .u64(nodeid)
+            .u64(0)
+            .u64(0);
+        let mut request = request.bytes().to_vec();
+        request.extend(body);
+        request
+    }
+
+    #[test]
+    fn parses_requests() {
+        let mut body = Writer::default();
+        body.u32(7).u64(42);
+        let mut body = body.bytes().to_vec();
+        body.extend(b"name\0rest");
+        let buffer = request(FUSE_LOOKUP, 9, FUSE_ROOT_ID, &body);
+
+        let mut request = Request::parse(&buffer).unwrap();
+        assert_eq!(request.opcode, FUSE_LOOKUP);
+        assert_eq!(request.unique, 9);
+        assert_eq!(request.nodeid, FUSE_ROOT_ID);
+        assert_eq!(request.body.u32().unwrap(), 7);
+        assert_eq!(request.body.u64().unwrap(), 42);
+        assert_eq!(request.body.name().unwrap(), "name");
+        assert!(request.body.name().is_err());
+        assert_eq!(request.body.take(4).unwrap(), b"rest");
+        assert!(request.body.u32().is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        let buffer = request(FUSE_GETATTR, 5, FUSE_ROOT_ID, &[]);
+        assert!(Request::parse(&buffer[..buffer.len() - 1]).is_err());
+        assert!(Request::parse(&buffer[..12]).is_err());
+        assert_eq!(unique(&buffer[..20]), Some(5));
+        assert_eq!(unique(&buffer[..12]), None);
+
+        let mut mislabeled = buffer.clone();
+        mislabeled.push(0);
+        assert!(Request::parse(&mislabeled).is_err());
+    }
+
+    #[test]
+    fn encodes_replies() {
+        let reply = reply(3, 0, b"body");
+        assert_eq!(reply.len(), 20);
+        let mut reader = Reader::new(&reply);
+        assert_eq!(reader.u32().unwrap(), 20);
+        assert_eq!(reader.u32().unwrap(), 0);
+        assert_eq!(reader.u64().unwrap(), 3);
+        assert_eq!(reader.take(4).unwrap(), b"body");
+
+        let error = error(4, libc::ENOENT);
+        let mut reader = Reader::new(&error);
+        assert_eq!(reader.u32().unwrap(), 16);
+        assert_eq!(reader.u32().unwrap() as i32, -libc::ENOENT);
+        assert_eq!(reader.u64().unwrap(), 4);
+    }
+
+    #[test]
+    fn encodes_attributes() {
+        let attr = Attr {
+            ino: 2,
+            size: 1000,
+            mode: libc::S_IFREG | 0o644,
+            mtime: 1_700_000_000,
+            uid: 1000,
+            gid: 100,
+        };
+        // fuse_attr is 88 bytes, after 40 bytes of fuse_entry_out or 16 of fuse_attr_out
+        assert_eq!(Writer::default().attr(&attr).bytes().len(), 88);
+        assert_eq!(Writer::default().attr_out(&attr).bytes().len(), 104);
+        let entry = Writer::default().entry_out(&attr).bytes().to_vec();
+        assert_eq!(entry.len(), 128);
+
+        let mut reader = Reader::new(&entry[40..]);
+        assert_eq!(reader.u64().unwrap(), 2);
+        assert_eq!(reader.u64().unwrap(), 1000);
+        // blocks of 512 bytes
+        assert_eq!(reader.u64().unwrap(), 2);
+        assert_eq!(reader.u64().unwrap(), 1_700_000_000);
+        reader.take(28).unwrap();
+        assert_eq!(reader.u32().unwrap(), libc::S_IFREG | 0o644);
+        // nlink
+        assert_eq!(reader.u32().unwrap(), 1);
+        assert_eq!(reader.u32().unwrap(), 1000);
+        assert_eq!(reader.u32().unwrap(), 100);
+    }
+
+    #[test]
+    fn pads_directory_entries_and_stops_when_full() {
+        let mut writer = Writer::default();
+        assert!(writer.dirent(64, 5, 1, libc::S_IFDIR | 0o755, "docs"));
+        assert_eq!(writer.bytes().len(), 32);
+        let mut reader = Reader::new(writer.bytes());
+        assert_eq!(reader.u64().unwrap(), 5);
+        assert_eq!(reader.u64().unwrap(), 1);
+        assert_eq!(reader.u32().unwrap(), 4);
+        assert_eq!(reader.u32().unwrap(), libc::DT_DIR as u32);
+        assert_eq!(reader.take(8).unwrap(), b"docs\0\0\0\0");
+
+        assert!(writer.dirent(96, 6, 2, libc::S_IFREG, "notes.txt"));
+        assert_eq!(writer.bytes().len(), 72);
+        assert!(!writer.dirent(96, 7, 3, libc::S_IFREG, "more"));
+        assert_eq!(writer.bytes().len(), 72);
+    }
+}
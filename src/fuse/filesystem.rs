@@ -0,0 +1,1002 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use ed25519_dalek::VerifyingKey;
+use tracing::{debug, warn};
+
+use crate::{
+    client::{Actions, AliasBook, RESERVED_NAME_PREFIX, content::ContentPointer},
+    crypto::encode::encode_verifying_key,
+    models::{Name, Value},
+    time::unix_timestamp,
+};
+
+use super::abi::{self, Attr, Reader, Request, Writer};
+
+/// Files up to this size are stored in their value, larger ones as content trees
+const INLINE_LIMIT: usize = 64 * 1024;
+
+/// Largest file size, since open files are held in memory
+const MAX_FILE_SIZE: usize = 1 << 30;
+
+const LISTING_TTL: Duration = Duration::from_secs(5);
+
+/// Directory at the root whose entries are immutable content trees named by their root hash
+const IMMUTABLE_DIR: &str = "immutable";
+
+type Reply = Result<Vec<u8>, i32>;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Root,
+    ImmutableDir,
+    Immutable(blake3::Hash),
+    /// Directory of the names in a keyspace starting with `path/`, or all names at its root
+    Dir {
+        keyspace: VerifyingKey,
+        path: String,
+    },
+    File {
+        keyspace: VerifyingKey,
+        name: String,
+    },
+}
+
+enum Content {
+    Inline(Vec<u8>),
+    Tree(ContentPointer),
+    /// Encrypted for someone else, or otherwise undecodable
+    Unreadable,
+}
+
+struct Entry {
+    size: u64,
+    mtime: u64,
+    content: Content,
+}
+
+struct Listing {
+    fetched: Instant,
+    entries: BTreeMap<String, Entry>,
+}
+
+/// An open file, whose contents are written back as a whole when flushed
+struct Handle {
+    node: Node,
+    data: Vec<u8>,
+    dirty: bool,
+    writable: bool,
+}
+
+/// Serves this identity's keyspace read-write and other keyspaces read-only, with names split
+/// into directories at each `/`
+pub struct Filesystem {
+    actions: Actions,
+    aliases: AliasBook,
+    own_keyspace: VerifyingKey,
+    uid: u32,
+    gid: u32,
+    nodes: HashMap<u64, Node>,
+    inodes: HashMap<Node, u64>,
+    listings: HashMap<VerifyingKey, Listing>,
+    /// Directories made with mkdir in this keyspace that hold no names yet
+    empty_dirs: BTreeSet<String>,
+    immutable: HashMap<blake3::Hash, Vec<u8>>,
+    handles: HashMap<u64, Handle>,
+    next_fh: u64,
+    priorities: HashMap<String, u64>,
+}
+
+impl Filesystem {
+    pub fn new(actions: Actions, aliases: AliasBook, own_keyspace: VerifyingKey) -> Filesystem {
+        // SAFETY: getuid and getgid cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Filesystem {
+            actions,
+            aliases,
+            own_keyspace,
+            uid,
+            gid,
+            nodes: HashMap::from([(abi::FUSE_ROOT_ID, Node::Root)]),
+            inodes: HashMap::from([(Node::Root, abi::FUSE_ROOT_ID)]),
+            listings: HashMap::new(),
+            empty_dirs: BTreeSet::new(),
+            immutable: HashMap::new(),
+            handles: HashMap::new(),
+            next_fh: 1,
+            priorities: HashMap::new(),
+        }
+    }
+
+    /// Returns None for requests the kernel expects no reply to
+    pub async fn handle(&mut self, request: Request<'_>) -> Option<Vec<u8>> {
+        let Request {
+            opcode,
+            unique,
+            nodeid,
+            mut body,
+        } = request;
+        let result = match opcode {
+            abi::FUSE_FORGET | abi::FUSE_BATCH_FORGET | abi::FUSE_INTERRUPT => return None,
+            abi::FUSE_INIT => self.init(&mut body),
+            abi::FUSE_DESTROY | abi::FUSE_ACCESS | abi::FUSE_RELEASEDIR | abi::FUSE_FSYNCDIR => {
+                Ok(Vec::new())
+            }
+            abi::FUSE_LOOKUP => self.lookup(nodeid, &mut body).await,
+            abi::FUSE_GETATTR => self.getattr(nodeid).await,
+            abi::FUSE_SETATTR => self.setattr(nodeid, &mut body).await,
+            abi::FUSE_OPENDIR => self.opendir(nodeid),
+            abi::FUSE_READDIR => self.readdir(nodeid, &mut body).await,
+            abi::FUSE_OPEN => self.open(nodeid, &mut body).await,
+            abi::FUSE_READ => self.read(&mut body),
+            abi::FUSE_WRITE => self.write(&mut body),
+            abi::FUSE_FLUSH | abi::FUSE_FSYNC => self.flush(&mut body).await,
+            abi::FUSE_RELEASE => self.release(&mut body).await,
+            abi::FUSE_CREATE => self.create(nodeid, &mut body).await,
+            abi::FUSE_MKDIR => self.mkdir(nodeid, &mut body).await,
+            abi::FUSE_UNLINK => self.unlink(nodeid, &mut body).await,
+            abi::FUSE_RMDIR => self.rmdir(nodeid, &mut body).await,
+            abi::FUSE_RENAME => self.rename(nodeid, &mut body, false).await,
+            abi::FUSE_RENAME2 => self.rename(nodeid, &mut body, true).await,
+            abi::FUSE_STATFS => Ok(statfs()),
+            _ => {
+                debug!("Unsupported FUSE request {}", opcode);
+                Err(libc::ENOSYS)
+            }
+        };
+        Some(match result {
+            Ok(body) => abi::reply(unique, 0, &body),
+            Err(errno) => abi::error(unique, errno),
+        })
+    }
+
+    fn init(&mut self, body: &mut Reader) -> Reply {
+        let major = body.u32().map_err(invalid)?;
+        let _minor = body.u32().map_err(invalid)?;
+        let max_readahead = body.u32().map_err(invalid)?;
+        if major != abi::FUSE_KERNEL_VERSION {
+            warn!("Unsupported FUSE kernel protocol {}", major);
+            return Err(libc::EPROTO);
+        }
+        let mut reply = Writer::default();
+        reply
+            .u32(abi::FUSE_KERNEL_VERSION)
+            .u32(abi::FUSE_KERNEL_MINOR_VERSION)
+            .u32(max_readahead)
+            // flags
+            .u32(0)
+            // max_background and congestion_threshold
+            .u16(16)
+            .u16(12)
+            .u32(abi::MAX_WRITE)
+            // time_gran, max_pages, map_alignment and flags2
+            .u32(1)
+            .u16(0)
+            .u16(0)
+            .u32(0);
+        for _ in 0..7 {
+            reply.u32(0);
+        }
+        Ok(reply.bytes().to_vec())
+    }
+
+    async fn lookup(&mut self, parent: u64, body: &mut Reader<'_>) -> Reply {
+        let name = body.name().map_err(invalid)?;
+        let node = self.child(parent, name).await?;
+        let attr = self.attr(node).await?;
+        Ok(Writer::default().entry_out(&attr).bytes().to_vec())
+    }
+
+    async fn getattr(&mut self, nodeid: u64) -> Reply {
+        let node = self.node(nodeid)?;
+        let attr = self.attr(node).await?;
+        Ok(Writer::default().attr_out(&attr).bytes().to_vec())
+    }
+
+    async fn setattr(&mut self, nodeid: u64, body: &mut Reader<'_>) -> Reply {
+        let valid = body.u32().map_err(invalid)?;
+        let _padding = body.u32().map_err(invalid)?;
+        let fh = body.u64().map_err(invalid)?;
+        let size = body.u64().map_err(invalid)? as usize;
+        let node = self.node(nodeid)?;
+        // Modes, owners and times are fixed, so only truncation changes anything
+        if valid & abi::FATTR_SIZE != 0 {
+            if size > MAX_FILE_SIZE {
+                return Err(libc::EFBIG);
+            }
+            let name = self.writable_name(&node)?;
+            match self.handles.get_mut(&fh) {
+                Some(handle) if valid & abi::FATTR_FH != 0 => {
+                    handle.data.resize(size, 0);
+                    handle.dirty = true;
+                }
+                _ => {
+                    let mut data = self.file_content(&node).await?;
+                    data.resize(size, 0);
+                    self.store(&name, data).await?;
+                }
+            }
+        }
+        let attr = self.attr(node).await?;
+        Ok(Writer::default().attr_out(&attr).bytes().to_vec())
+    }
+
+    fn opendir(&mut self, nodeid: u64) -> Reply {
+        match self.node(nodeid)? {
+            Node::File { .. } | Node::Immutable(_) => Err(libc::ENOTDIR),
+            _ => Ok(Writer::default().open_out(0).bytes().to_vec()),
+        }
+    }
+
+    async fn readdir(&mut self, nodeid: u64, body: &mut Reader<'_>) -> Reply {
+        let _fh = body.u64().map_err(invalid)?;
+        let offset = body.u64().map_err(invalid)? as usize;
+        let size = body.u32().map_err(invalid)? as usize;
+        let node = self.node(nodeid)?;
+        let mut entries = vec![
+            (".".to_string(), nodeid, libc::S_IFDIR),
+            ("..".to_string(), nodeid, libc::S_IFDIR),
+        ];
+        for (name, child) in self.children(&node).await? {
+            let mode = match child {
+                Node::File { .. } | Node::Immutable(_) => libc::S_IFREG,
+                _ => libc::S_IFDIR,
+            };
+            entries.push((name, self.inode(child), mode));
+        }
+        let mut reply = Writer::default();
+        for (index, (name, ino, mode)) in entries.iter().enumerate().skip(offset) {
+            if !reply.dirent(size, *ino, index as u64 + 1, *mode, name) {
+                break;
+            }
+        }
+        Ok(reply.bytes().to_vec())
+    }
+
+    async fn open(&mut self, nodeid: u64, body: &mut Reader<'_>) -> Reply {
+        let flags = body.u32().map_err(invalid)? as i32;
+        let writable = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        let node = self.node(nodeid)?;
+        let data = match &node {
+            Node::File { .. } if writable => {
+                self.writable_name(&node)?;
+                if flags & libc::O_TRUNC != 0 {
+                    Vec::new()
+                } else {
+                    self.file_content(&node).await?
+                }
+            }
+            Node::File { .. } => self.file_content(&node).await?,
+            Node::Immutable(_) if writable => return Err(libc::EROFS),
+            Node::Immutable(hash) => self.immutable.get(hash).cloned().ok_or(libc::ENOENT)?,
+            _ => return Err(libc::EISDIR),
+        };
+        let fh = self.open_handle(Handle {
+            node,
+            data,
+            dirty: writable && flags & libc::O_TRUNC != 0,
+            writable,
+        });
+        Ok(Writer::default().open_out(fh).bytes().to_vec())
+    }
+
+    fn read(&mut self, body: &mut Reader) -> Reply {
+        let fh = body.u64().map_err(invalid)?;
+        let offset = body.u64().map_err(invalid)? as usize;
+        let size = body.u32().map_err(invalid)? as usize;
+        let handle = self.handles.get(&fh).ok_or(libc::EBADF)?;
+        let start = offset.min(handle.data.len());
+        let end = offset.saturating_add(size).min(handle.data.len());
+        Ok(handle.data[start..end].to_vec())
+    }
+
+    fn write(&mut self, body: &mut Reader) -> Reply {
+        let fh = body.u64().map_err(invalid)?;
+        let offset = body.u64().map_err(invalid)? as usize;
+        let size = body.u32().map_err(invalid)? as usize;
+        // write_flags, lock_owner, flags and padding
+        body.take(20).map_err(invalid)?;
+        let data = body.take(size).map_err(invalid)?;
+        let handle = self.handles.get_mut(&fh).ok_or(libc::EBADF)?;
+        if !handle.writable {
+            return Err(libc::EBADF);
+        }
+        let end = offset
+            .checked_add(size)
+            .filter(|end| *end <= MAX_FILE_SIZE)
+            .ok_or(libc::EFBIG)?;
+        if handle.data.len() < end {
+            handle.data.resize(end, 0);
+        }
+        handle.data[offset..end].copy_from_slice(data);
+        handle.dirty = true;
+        Ok(Writer::default().u32(size as u32).u32(0).bytes().to_vec())
+    }
+
+    async fn flush(&mut self, body: &mut Reader<'_>) -> Reply {
+        let fh = body.u64().map_err(invalid)?;
+        self.write_back(fh).await?;
+        Ok(Vec::new())
+    }
+
+    async fn release(&mut self, body: &mut Reader<'_>) -> Reply {
+        let fh = body.u64().map_err(invalid)?;
+        let result = self.write_back(fh).await;
+        self.handles.remove(&fh);
+        result.map(|_| Vec::new())
+    }
+
+    async fn create(&mut self, parent: u64, body: &mut Reader<'_>) -> Reply {
+        // flags, mode, umask and open_flags
+        body.take(16).map_err(invalid)?;
+        let name = body.name().map_err(invalid)?;
+        let name = self.writable_child_name(parent, name)?;
+        let node = Node::File {
+            keyspace: self.own_keyspace,
+            name: name.clone(),
+        };
+        self.insert_entry(&name, Content::Inline(Vec::new()), 0);
+        let attr = self.attr(node.clone()).await?;
+        let fh = self.open_handle(Handle {
+            node,
+            data: Vec::new(),
+            dirty: true,
+            writable: true,
+        });
+        Ok(Writer::default()
+            .entry_out(&attr)
+            .open_out(fh)
+            .bytes()
+            .to_vec())
+    }
+
+    async fn mkdir(&mut self, parent: u64, body: &mut Reader<'_>) -> Reply {
+        // mode and umask
+        body.take(8).map_err(invalid)?;
+        let name = body.name().map_err(invalid)?;
+        let path = self.writable_child_name(parent, name)?;
+        if self.child(parent, name).await.is_ok() {
+            return Err(libc::EEXIST);
+        }
+        self.empty_dirs.insert(path.clone());
+        let attr = self
+            .attr(Node::Dir {
+                keyspace: self.own_keyspace,
+                path,
+            })
+            .await?;
+        Ok(Writer::default().entry_out(&attr).bytes().to_vec())
+    }
+
+    async fn unlink(&mut self, parent: u64, body: &mut Reader<'_>) -> Reply {
+        let name = body.name().map_err(invalid)?;
+        let name = self.writable_child_name(parent, name)?;
+        let exists = self
+            .listing(&self.own_keyspace.clone())
+            .await?
+            .entries
+            .contains_key(&name);
+        if !exists {
+            return Err(libc::ENOENT);
+        }
+        self.delete(&name).await?;
+        Ok(Vec::new())
+    }
+
+    async fn rmdir(&mut self, parent: u64, body: &mut Reader<'_>) -> Reply {
+        let name = body.name().map_err(invalid)?;
+        let path = self.writable_child_name(parent, name)?;
+        let node = Node::Dir {
+            keyspace: self.own_keyspace,
+            path: path.clone(),
+        };
+        if !self.children(&node).await?.is_empty() {
+            return Err(libc::ENOTEMPTY);
+        }
+        if !self.empty_dirs.remove(&path) {
+            return Err(libc::ENOENT);
+        }
+        Ok(Vec::new())
+    }
+
+    async fn rename(&mut self, parent: u64, body: &mut Reader<'_>, with_flags: bool) -> Reply {
+        let new_parent = body.u64().map_err(invalid)?;
+        if with_flags {
+            let flags = body.u32().map_err(invalid)?;
+            let _padding = body.u32().map_err(invalid)?;
+            if flags != 0 {
+                return Err(libc::EINVAL);
+            }
+        }
+        let old_name = body.name().map_err(invalid)?;
+        let new_name = body.name().map_err(invalid)?;
+        let source = self.child(parent, old_name).await?;
+        let old_name = self.writable_child_name(parent, old_name)?;
+        let new_name = self.writable_child_name(new_parent, new_name)?;
+        if !matches!(source, Node::File { .. }) {
+            // Moving a directory means rewriting every name under it, so leave that to copying
+            return Err(libc::EXDEV);
+        }
+        if old_name == new_name {
+            return Ok(Vec::new());
+        }
+        let data = self.file_content(&source).await?;
+        self.store(&new_name, data).await?;
+        self.delete(&old_name).await?;
+        // The kernel keeps using the renamed file's inode, so point it at the new name
+        let target = Node::File {
+            keyspace: self.own_keyspace,
+            name: new_name,
+        };
+        if let Some(ino) = self.inodes.remove(&source) {
+            self.inodes.insert(target.clone(), ino);
+            self.nodes.insert(ino, target);
+        }
+        Ok(Vec::new())
+    }
+
+    fn node(&self, nodeid: u64) -> Result<Node, i32> {
+        self.nodes.get(&nodeid).cloned().ok_or(libc::ENOENT)
+    }
+
+    fn inode(&mut self, node: Node) -> u64 {
+        if let Some(ino) = self.inodes.get(&node) {
+            return *ino;
+        }
+        let ino = self.nodes.len() as u64 + 1;
+        self.nodes.insert(ino, node.clone());
+        self.inodes.insert(node, ino);
+        ino
+    }
+
+    fn open_handle(&mut self, handle: Handle) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, handle);
+        fh
+    }
+
+    async fn attr(&mut self, node: Node) -> Result<Attr, i32> {
+        let writable_dir = 0o755 | libc::S_IFDIR;
+        let read_only_dir = 0o555 | libc::S_IFDIR;
+        let (size, mode, mtime) = match &node {
+            Node::Root | Node::ImmutableDir => (0, read_only_dir, 0),
+            Node::Dir { keyspace, .. } if *keyspace == self.own_keyspace => (0, writable_dir, 0),
+            Node::Dir { .. } => (0, read_only_dir, 0),
+            Node::Immutable(hash) => {
+                let size = self.immutable.get(hash).map(Vec::len).unwrap_or_default();
+                (size as u64, 0o444 | libc::S_IFREG, 0)
+            }
+            Node::File { keyspace, name } => {
+                let keyspace = *keyspace;
+                let entry = self
+                    .listing(&keyspace)
+                    .await?
+                    .entries
+                    .get(name)
+                    .ok_or(libc::ENOENT)?;
+                let (size, mtime) = (entry.size, entry.mtime);
+                // Writes not flushed yet already count towards the size
+                let size = self
+                    .handles
+                    .values()
+                    .filter(|handle| handle.dirty && handle.node == node)
+                    .map(|handle| handle.data.len() as u64)
+                    .max()
+                    .unwrap_or(size);
+                let permissions = if keyspace == self.own_keyspace {
+                    0o644
+                } else {
+                    0o444
+                };
+                (size, permissions | libc::S_IFREG, mtime)
+            }
+        };
+        Ok(Attr {
+            ino: self.inode(node),
+            size,
+            mode,
+            mtime,
+            uid: self.uid,
+            gid: self.gid,
+        })
+    }
+
+    async fn child(&mut self, parent: u64, name: &str) -> Result<Node, i32> {
+        match self.node(parent)? {
+            Node::Root if name == IMMUTABLE_DIR => Ok(Node::ImmutableDir),
+            Node::Root => {
+                let keyspace = self.aliases.resolve(name).map_err(|_| libc::ENOENT)?;
+                Ok(Node::Dir {
+                    keyspace,
+                    path: String::new(),
+                })
+            }
+            Node::ImmutableDir => {
+                let hash = blake3::Hash::from_hex(name).map_err(|_| libc::ENOENT)?;
+                if !self.immutable.contains_key(&hash) {
+                    let content = self.actions.get_tree(&hash).await.map_err(|e| {
+                        debug!("Failed to read immutable content {}: {:?}", hash, e);
+                        libc::ENOENT
+                    })?;
+                    self.immutable.insert(hash, content);
+                }
+                Ok(Node::Immutable(hash))
+            }
+            node @ Node::Dir { .. } => self
+                .children(&node)
+                .await?
+                .into_iter()
+                .find(|(child_name, _)| child_name == name)
+                .map(|(_, child)| child)
+                .ok_or(libc::ENOENT),
+            Node::File { .. } | Node::Immutable(_) => Err(libc::ENOTDIR),
+        }
+    }
+
+    async fn children(&mut self, node: &Node) -> Result<Vec<(String, Node)>, i32> {
+        match node {
+            Node::Root => {
+                // Other keyspaces can be looked up by verifying key, but only aliases are listed
+                let mut children = vec![
+                    (IMMUTABLE_DIR.to_string(), Node::ImmutableDir),
+                    (
+                        encode_verifying_key(&self.own_keyspace),
+                        Node::Dir {
+                            keyspace: self.own_keyspace,
+                            path: String::new(),
+                        },
+                    ),
+                ];
+                for (alias, verifying_key) in self.aliases.iter() {
+                    if let Ok(keyspace) = self.aliases.resolve(verifying_key) {
+                        let node = Node::Dir {
+                            keyspace,
+                            path: String::new(),
+                        };
+                        children.push((alias.to_string(), node));
+                    }
+                }
+                Ok(children)
+            }
+            Node::ImmutableDir => Ok(Vec::new()),
+            Node::Dir { keyspace, path } => {
+                let prefix = if path.is_empty() {
+                    String::new()
+                } else {
+                    format!("{path}/")
+                };
+                let mut files = BTreeSet::new();
+                let mut dirs = BTreeSet::new();
+                let mut sort = |name: &str| {
+                    if let Some(rest) = name.strip_prefix(&prefix) {
+                        match rest.split_once('/') {
+                            Some((dir, _)) => dirs.insert(dir.to_string()),
+                            None => files.insert(rest.to_string()),
+                        };
+                    }
+                };
+                for name in self.listing(keyspace).await?.entries.keys() {
+                    sort(name);
+                }
+                if *keyspace == self.own_keyspace {
+                    for name in &self.empty_dirs {
+                        sort(&format!("{name}/"));
+                    }
+                }
+                let mut children = Vec::new();
+                for file in &files {
+                    children.push((
+                        file.clone(),
+                        Node::File {
+                            keyspace: *keyspace,
+                            name: format!("{prefix}{file}"),
+                        },
+                    ));
+                }
+                // A name that is both a file and a directory shows as the file
+                for dir in dirs.difference(&files) {
+                    children.push((
+                        dir.clone(),
+                        Node::Dir {
+                            keyspace: *keyspace,
+                            path: format!("{prefix}{dir}"),
+                        },
+                    ));
+                }
+                Ok(children)
+            }
+            Node::File { .. } | Node::Immutable(_) => Err(libc::ENOTDIR),
+        }
+    }
+
+    async fn listing(&mut self, keyspace: &VerifyingKey) -> Result<&Listing, i32> {
+        let fresh = self
+            .listings
+            .get(keyspace)
+            .is_some_and(|listing| listing.fetched.elapsed() < LISTING_TTL);
+        if !fresh {
+            let mut entries = BTreeMap::new();
+            for (name, event) in io(self.actions.list(keyspace).await)? {
+                let name = name.as_str();
+                if name.starts_with(RESERVED_NAME_PREFIX) || !is_valid_path(name) {
+                    continue;
+                }
+                let content = match self.actions.decode_value(&event).await {
+                    Ok(value) => match ContentPointer::from_value(&value) {
+                        Some(pointer) => Content::Tree(pointer),
+                        None => Content::Inline(value.as_bytes().to_vec()),
+                    },
+                    Err(_) => Content::Unreadable,
+                };
+                let size = match &content {
+                    Content::Inline(data) => data.len() as u64,
+                    Content::Tree(pointer) => pointer.size,
+                    Content::Unreadable => 0,
+                };
+                let entry = Entry {
+                    size,
+                    mtime: event.priority,
+                    content,
+                };
+                entries.insert(name.to_string(), entry);
+            }
+            let listing = Listing {
+                fetched: Instant::now(),
+                entries,
+            };
+            self.listings.insert(*keyspace, listing);
+        }
+        Ok(&self.listings[keyspace])
+    }
+
+    async fn file_content(&mut self, node: &Node) -> Result<Vec<u8>, i32> {
+        let Node::File { keyspace, name } = node else {
+            return Err(libc::EISDIR);
+        };
+        let entry = self
+            .listing(keyspace)
+            .await?
+            .entries
+            .get(name)
+            .ok_or(libc::ENOENT)?;
+        match &entry.content {
+            Content::Inline(data) => Ok(data.clone()),
+            Content::Tree(pointer) => {
+                let pointer = *pointer;
+                io(self.actions.get_content(&pointer).await)
+            }
+            Content::Unreadable => Err(libc::EACCES),
+        }
+    }
+
+    fn writable_child_name(&self, parent: u64, name: &str) -> Result<String, i32> {
+        match self.node(parent)? {
+            Node::Dir { keyspace, path } if keyspace == self.own_keyspace => {
+                if !is_valid_path(name) || name.contains('/') {
+                    return Err(libc::EINVAL);
+                }
+                if path.is_empty() {
+                    Ok(name.to_string())
+                } else {
+                    Ok(format!("{path}/{name}"))
+                }
+            }
+            Node::Dir { .. } | Node::Root | Node::ImmutableDir => Err(libc::EROFS),
+            Node::File { .. } | Node::Immutable(_) => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn writable_name(&self, node: &Node) -> Result<String, i32> {
+        match node {
+            Node::File { keyspace, name } if *keyspace == self.own_keyspace => Ok(name.clone()),
+            Node::File { .. } | Node::Immutable(_) => Err(libc::EROFS),
+            _ => Err(libc::EISDIR),
+        }
+    }
+
+    async fn write_back(&mut self, fh: u64) -> Result<(), i32> {
+        let Some(handle) = self.handles.get(&fh) else {
+            return Err(libc::EBADF);
+        };
+        if !handle.dirty {
+            return Ok(());
+        }
+        let name = self.writable_name(&handle.node)?;
+        let data = handle.data.clone();
+        self.store(&name, data).await?;
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.dirty = false;
+        }
+        Ok(())
+    }
+
+    async fn store(&mut self, name: &str, data: Vec<u8>) -> Result<(), i32> {
+        let (value, content) = if data.len() <= INLINE_LIMIT {
+            (Value::new(data.clone()), Content::Inline(data))
+        } else {
+            let pointer = io(self.actions.set_content(&data).await)?;
+            (pointer.to_value(), Content::Tree(pointer))
+        };
+        let priority = self.next_priority(name);
+        io(self
+            .actions
+            .set()
+            .name(Name::new(name.to_string()))
+            .value(value)
+            .priority(priority)
+            .call()
+            .await)?;
+        self.insert_entry(name, content, priority);
+        Ok(())
+    }
+
+    async fn delete(&mut self, name: &str) -> Result<(), i32> {
+        io(self.actions.delete(Name::new(name.to_string())).await)?;
+        self.next_priority(name);
+        if let Some(listing) = self.listings.get_mut(&self.own_keyspace) {
+            listing.entries.remove(name);
+        }
+        Ok(())
+    }
+
+    /// Outranks earlier writes of the name, even those within the same second
+    fn next_priority(&mut self, name: &str) -> u64 {
+        let listed = self
+            .listings
+            .get(&self.own_keyspace)
+            .and_then(|listing| listing.entries.get(name))
+            .map(|entry| entry.mtime);
+        let previous = self.priorities.get(name).copied().max(listed);
+        let priority = previous
+            .map(|priority| priority + 1)
+            .unwrap_or_default()
+            .max(unix_timestamp());
+        self.priorities.insert(name.to_string(), priority);
+        priority
+    }
+
+    fn insert_entry(&mut self, name: &str, content: Content, mtime: u64) {
+        let size = match &content {
+            Content::Inline(data) => data.len() as u64,
+            Content::Tree(pointer) => pointer.size,
+            Content::Unreadable => 0,
+        };
+        let listing = self
+            .listings
+            .entry(self.own_keyspace)
+            .or_insert_with(|| Listing {
+                fetched: Instant::now(),
+                entries: BTreeMap::new(),
+            });
+        listing.entries.insert(
+            name.to_string(),
+            Entry {
+                size,
+                mtime,
+                content,
+            },
+        );
+        // Directories made with mkdir exist through their names from now on
+        self.empty_dirs
+            .retain(|dir| !name.starts_with(&format!("{dir}/")));
+    }
+}
+
+/// Whether a name can be shown as a path: no empty, `.` or `..` components
+fn is_valid_path(name: &str) -> bool {
+    name.split('/')
+        .all(|component| !component.is_empty() && component != "." && component != "..")
+}
+
+fn statfs() -> Vec<u8> {
+    let mut reply = Writer::default();
+    // blocks, bfree, bavail, files and ffree
+    for _ in 0..5 {
+        reply.u64(0);
+    }
+    // bsize, namelen, frsize and padding
+    reply.u32(4096).u32(255).u32(4096).u32(0);
+    for _ in 0..6 {
+        reply.u32(0);
+    }
+    reply.bytes().to_vec()
+}
+
+fn invalid(e: anyhow::Error) -> i32 {
+    debug!("Malformed FUSE request: {:?}", e);
+    libc::EINVAL
+}
+
+fn io<T>(result: anyhow::Result<T>) -> Result<T, i32> {
+    result.map_err(|e| {
+        warn!("Filesystem operation failed: {:?}", e);
+        libc::EIO
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::Configuration,
+        connectors::{connection::Connection, local::LocalConnection},
+        crypto::CryptoKey,
+        server::node::Node as ServerNode,
+    };
+
+    fn filesystem(
+        connection: &LocalConnection,
+        base_dir: &std::path::Path,
+        key: &CryptoKey,
+    ) -> Filesystem {
+        let config = Configuration::new(
+            base_dir.to_path_buf(),
+            vec![Connection::Local(connection.clone())],
+        );
+        let actions = Actions::with_identity(config, key.clone());
+        Filesystem::new(actions, AliasBook::default(), key.verifying())
+    }
+
+    async fn call(filesystem: &mut Filesystem, opcode: u32, nodeid: u64, body: &[u8]) -> Reply {
+        let mut buffer = Writer::default();
+        buffer
+            .u32(40 + body.len() as u32)
+            .u32(opcode)
+            .u64(1)
+            .u64(nodeid)
+            .u64(0)
+            .u64(0);
+        let mut buffer = buffer.bytes().to_vec();
+        buffer.extend(body);
+        let reply = filesystem
+            .handle(Request::parse(&buffer).unwrap())
+            .await
+            .unwrap();
+        let mut header = Reader::new(&reply);
+        assert_eq!(header.u32().unwrap() as usize, reply.len());
+        match header.u32().unwrap() as i32 {
+            0 => Ok(reply[16..].to_vec()),
+            error => Err(-error),
+        }
+    }
+
+    async fn lookup(filesystem: &mut Filesystem, parent: u64, name: &str) -> Result<Vec<u8>, i32> {
+        call(
+            filesystem,
+            abi::FUSE_LOOKUP,
+            parent,
+            format!("{name}\0").as_bytes(),
+        )
+        .await
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn dirent_names(mut reply: &[u8]) -> Vec<String> {
+        let mut names = Vec::new();
+        while !reply.is_empty() {
+            let length = u32::from_le_bytes(reply[16..20].try_into().unwrap()) as usize;
+            names.push(String::from_utf8(reply[24..24 + length].to_vec()).unwrap());
+            reply = &reply[(24 + length).next_multiple_of(8)..];
+        }
+        names
+    }
+
+    #[tokio::test]
+    async fn writes_reads_and_lists_files() {
+        let connection = LocalConnection::new(ServerNode::in_memory().unwrap());
+        let base_dir = tempfile::tempdir().unwrap();
+        let key = CryptoKey::generate();
+        let own_name = encode_verifying_key(&key.verifying());
+
+        let mut writer = filesystem(&connection, base_dir.path(), &key);
+        let own_dir = u64_at(
+            &lookup(&mut writer, abi::FUSE_ROOT_ID, &own_name)
+                .await
+                .unwrap(),
+            0,
+        );
+        let mut create = vec![0; 16];
+        create.extend(b"notes\0");
+        let created = call(&mut writer, abi::FUSE_CREATE, own_dir, &create)
+            .await
+            .unwrap();
+        let fh = u64_at(&created, 128);
+        let mut write = Writer::default();
+        write.u64(fh).u64(0).u32(5).u32(0).u64(0).u64(0);
+        let mut write = write.bytes().to_vec();
+        write.extend(b"hello");
+        let written = call(&mut writer, abi::FUSE_WRITE, 0, &write).await.unwrap();
+        assert_eq!(&written[..4], 5u32.to_le_bytes());
+        let release = Writer::default().u64(fh).u64(0).u64(0).bytes().to_vec();
+        call(&mut writer, abi::FUSE_RELEASE, 0, &release)
+            .await
+            .unwrap();
+
+        // A fresh mount only sees what was published
+        let mut reader = filesystem(&connection, base_dir.path(), &key);
+        let own_dir = u64_at(
+            &lookup(&mut reader, abi::FUSE_ROOT_ID, &own_name)
+                .await
+                .unwrap(),
+            0,
+        );
+        let entry = lookup(&mut reader, own_dir, "notes").await.unwrap();
+        // fuse_attr follows 40 bytes of fuse_entry_out, with the size after the inode
+        assert_eq!(u64_at(&entry, 48), 5);
+        assert_eq!(
+            lookup(&mut reader, own_dir, "missing").await,
+            Err(libc::ENOENT)
+        );
+
+        let open = Writer::default()
+            .u32(libc::O_RDONLY as u32)
+            .u32(0)
+            .bytes()
+            .to_vec();
+        let opened = call(&mut reader, abi::FUSE_OPEN, u64_at(&entry, 0), &open)
+            .await
+            .unwrap();
+        let read = Writer::default()
+            .u64(u64_at(&opened, 0))
+            .u64(1)
+            .u32(64)
+            .bytes()
+            .to_vec();
+        let data = call(&mut reader, abi::FUSE_READ, 0, &read).await.unwrap();
+        assert_eq!(data, b"ello");
+
+        let readdir = Writer::default().u64(0).u64(0).u32(4096).bytes().to_vec();
+        let listed = call(&mut reader, abi::FUSE_READDIR, own_dir, &readdir)
+            .await
+            .unwrap();
+        assert_eq!(dirent_names(&listed), vec![".", "..", "notes"]);
+    }
+
+    #[tokio::test]
+    async fn splits_names_into_read_only_directories_for_others() {
+        let connection = LocalConnection::new(ServerNode::in_memory().unwrap());
+        let base_dir = tempfile::tempdir().unwrap();
+        let other = CryptoKey::generate();
+        filesystem(&connection, base_dir.path(), &other)
+            .actions
+            .set()
+            .name(Name::new("docs/readme".to_string()))
+            .value(Value::new(b"hi".to_vec()))
+            .call()
+            .await
+            .unwrap();
+
+        let mut filesystem = filesystem(&connection, base_dir.path(), &CryptoKey::generate());
+        let other_name = encode_verifying_key(&other.verifying());
+        let other_dir = u64_at(
+            &lookup(&mut filesystem, abi::FUSE_ROOT_ID, &other_name)
+                .await
+                .unwrap(),
+            0,
+        );
+        let readdir = Writer::default().u64(0).u64(0).u32(4096).bytes().to_vec();
+        let listed = call(&mut filesystem, abi::FUSE_READDIR, other_dir, &readdir)
+            .await
+            .unwrap();
+        assert_eq!(dirent_names(&listed), vec![".", "..", "docs"]);
+
+        let docs = u64_at(
+            &lookup(&mut filesystem, other_dir, "docs").await.unwrap(),
+            0,
+        );
+        let entry = lookup(&mut filesystem, docs, "readme").await.unwrap();
+        assert_eq!(u64_at(&entry, 48), 2);
+        let mut create = vec![0; 16];
+        create.extend(b"new\0");
+        assert_eq!(
+            call(&mut filesystem, abi::FUSE_CREATE, docs, &create).await,
+            Err(libc::EROFS)
+        );
+    }
+}
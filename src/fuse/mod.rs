@@ -0,0 +1,5 @@
+mod abi;
+mod filesystem;
+mod session;
+
+pub use session::mount;
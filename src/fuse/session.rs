@@ -0,0 +1,161 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{Context, Result, bail};
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+
+use crate::client::{Actions, AliasBook};
+
+use super::{
+    abi::{self, FUSE_DESTROY, REQUEST_BUFFER_SIZE, Request},
+    filesystem::Filesystem,
+};
+
+/// The setuid helper from libfuse that lets unprivileged users mount
+const FUSERMOUNT: &str = "fusermount3";
+
+pub async fn mount(actions: Actions, aliases: AliasBook, mountpoint: &Path) -> Result<()> {
+    let own_keyspace = actions.whoami().await?;
+    let device = mount_device(mountpoint)?;
+    info!("Mounted at {}", mountpoint.display());
+
+    let filesystem = Filesystem::new(actions, aliases, own_keyspace);
+    let runtime = Handle::current();
+    let mut session = tokio::task::spawn_blocking(move || serve(device, filesystem, runtime));
+    let result = tokio::select! {
+        result = &mut session => result,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Unmounting {}", mountpoint.display());
+            unmount(mountpoint)?;
+            return session.await?;
+        }
+    };
+    // The session also ends on errors, which would otherwise leave the mount behind
+    if let Err(e) = unmount(mountpoint) {
+        warn!("{:?}", e);
+    }
+    result?
+}
+
+/// Has fusermount3 mount /dev/fuse and pass back its descriptor over a socket
+fn mount_device(mountpoint: &Path) -> Result<File> {
+    let (ours, theirs) = UnixStream::pair()?;
+    // SAFETY: clearing close-on-exec on a descriptor we own, so the helper inherits it
+    if unsafe { libc::fcntl(theirs.as_raw_fd(), libc::F_SETFD, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let status = Command::new(FUSERMOUNT)
+        .arg("-o")
+        .arg("default_permissions,fsname=baybridge,subtype=baybridge")
+        .arg("--")
+        .arg(mountpoint)
+        .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+        .status()
+        .with_context(|| format!("Failed to run {FUSERMOUNT}"))?;
+    drop(theirs);
+    if !status.success() {
+        bail!(
+            "Failed to mount {}: {FUSERMOUNT} {status}",
+            mountpoint.display()
+        );
+    }
+    let device =
+        receive_fd(&ours).with_context(|| format!("{FUSERMOUNT} did not pass back /dev/fuse"))?;
+    Ok(File::from(device))
+}
+
+fn receive_fd(socket: &UnixStream) -> Result<OwnedFd> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    // SAFETY: msghdr is plain data, for which all zeroes is valid
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = std::mem::size_of_val(&control) as _;
+    // SAFETY: the message points at buffers that outlive the call
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) };
+    if received < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: recvmsg filled in the control buffer and its length
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null()
+            || (*header).cmsg_level != libc::SOL_SOCKET
+            || (*header).cmsg_type != libc::SCM_RIGHTS
+        {
+            bail!("No descriptor received");
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<libc::c_int>());
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn unmount(mountpoint: &Path) -> Result<()> {
+    let status = Command::new(FUSERMOUNT)
+        .arg("-u")
+        .arg("-z")
+        .arg("--")
+        .arg(mountpoint)
+        .status()
+        .with_context(|| format!("Failed to run {FUSERMOUNT}"))?;
+    if !status.success() {
+        bail!(
+            "Failed to unmount {}: {FUSERMOUNT} {status}",
+            mountpoint.display()
+        );
+    }
+    Ok(())
+}
+
+fn serve(mut device: File, mut filesystem: Filesystem, runtime: Handle) -> Result<()> {
+    let mut buffer = vec![0u8; REQUEST_BUFFER_SIZE];
+    loop {
+        let length = match device.read(&mut buffer) {
+            Ok(length) => length,
+            Err(e) => match e.raw_os_error() {
+                // The request was interrupted before it was read
+                Some(libc::ENOENT | libc::EINTR | libc::EAGAIN) => continue,
+                Some(libc::ENODEV) => return Ok(()),
+                _ => return Err(e.into()),
+            },
+        };
+        let (reply, opcode) = match Request::parse(&buffer[..length]) {
+            Ok(request) => {
+                let opcode = request.opcode;
+                (runtime.block_on(filesystem.handle(request)), Some(opcode))
+            }
+            Err(e) => {
+                warn!("Malformed FUSE request: {:?}", e);
+                (
+                    abi::unique(&buffer[..length]).map(|unique| abi::error(unique, libc::EIO)),
+                    None,
+                )
+            }
+        };
+        if let Some(reply) = reply
+            && let Err(e) = device.write_all(&reply)
+            && e.raw_os_error() != Some(libc::ENOENT)
+        {
+            warn!("Failed to reply to FUSE request: {:?}", e);
+        }
+        if opcode == Some(FUSE_DESTROY) {
+            return Ok(());
+        }
+    }
+}
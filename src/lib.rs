@@ -4,8 +4,11 @@ pub mod configuration;
 pub mod connectors;
 pub mod crdt;
 pub mod crypto;
+#[cfg(target_os = "linux")]
+pub mod fuse;
 pub mod models;
 pub mod server;
+pub mod time;
 
 pub mod built_info {
     // The file has been placed there by the build script.
@@ -1,16 +1,31 @@
 use std::{path::PathBuf, str::FromStr};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use baybridge::{
-    client::{Actions, Expiry},
+    api::NamespaceOrder,
+    client::{Actions, AliasBook, Event, Expiry, Freshness, TrustPolicy},
     configuration::Configuration,
     connectors::{connection::Connection, http::HttpConnection},
-    crypto::encode::encode_verifying_key,
-    models::{Name, Value},
-    server::http::start_http_server,
+    crdt::{CrdtKind, CrdtValue},
+    crypto::{
+        CryptoKey,
+        delegation::{decode_delegation, encode_delegation},
+        encode::encode_verifying_key,
+        identity,
+        work::key_work,
+    },
+    models::{Compression, Name, Value},
+    server::{
+        archive::{self, ArchiveFilter},
+        http::start_http_server,
+        node::Node,
+    },
 };
 use clap::{Parser, Subcommand};
 
+#[cfg(unix)]
+use baybridge::crypto::agent::{self, AgentSigner};
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -20,6 +35,51 @@ struct Args {
     config_dir: Option<String>,
     #[clap(short, long)]
     server: Vec<String>,
+    // Named identity to act as, defaults to the one chosen with `identity use`
+    #[clap(short, long, global = true)]
+    identity: Option<String>,
+    // Delegation certificate for writing to another owner's keyspace
+    #[clap(long, global = true)]
+    delegation: Option<String>,
+    // Sign with a key held by the ssh-agent at $SSH_AUTH_SOCK
+    #[cfg(unix)]
+    #[clap(long, global = true)]
+    agent: bool,
+    // Sign with a key held by the ssh-agent at this socket
+    #[cfg(unix)]
+    #[clap(long, global = true)]
+    agent_socket: Option<PathBuf>,
+    // Verifying key of the agent key to sign with, defaults to its first ed25519 key
+    #[cfg(unix)]
+    #[clap(long, global = true)]
+    agent_key: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct TrustArgs {
+    // Verifying key or alias whose values are trusted, may be repeated
+    #[clap(long)]
+    trust: Vec<String>,
+    // Also trust keys endorsed by a trusted key
+    #[clap(long)]
+    endorsed: bool,
+    // Also trust keys with at least this many bits of key work
+    #[clap(long)]
+    min_key_work: Option<u32>,
+}
+
+impl TrustArgs {
+    fn policy(&self, aliases: &AliasBook) -> Result<TrustPolicy> {
+        Ok(TrustPolicy {
+            trusted: self
+                .trust
+                .iter()
+                .map(|key| aliases.resolve(key))
+                .collect::<Result<Vec<_>>>()?,
+            endorsements: self.endorsed,
+            min_key_work: self.min_key_work,
+        })
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -27,6 +87,34 @@ enum Commands {
     Serve {
         #[clap(short, long)]
         peer: Vec<String>,
+        // Keep superseded events for this many seconds for the audit log
+        #[clap(long)]
+        history_retention: Option<u64>,
+        // Only serve values for this namespace from keyspaces admitted by the trust options
+        #[clap(long)]
+        protect: Vec<String>,
+        #[command(flatten)]
+        trust: TrustArgs,
+        // Reject events without a proof-of-work stamp of this many bits, more for large events
+        #[clap(long)]
+        min_stamp_work: Option<u32>,
+    },
+    // Write this node's events and immutable blocks to an archive, or stdout if no path is given
+    Export {
+        path: Option<PathBuf>,
+        // Only export this verifying key or alias, may be repeated
+        #[clap(long)]
+        key: Vec<String>,
+        // Only export values for this namespace, may be repeated
+        #[clap(long)]
+        namespace: Vec<String>,
+    },
+    // Verify an archive and add its events and blocks to this node, reading stdin if no path is given
+    Import {
+        path: Option<PathBuf>,
+        // Reject events without a proof-of-work stamp of this many bits, more for large events
+        #[clap(long)]
+        min_stamp_work: Option<u32>,
     },
     Set {
         name: String,
@@ -39,18 +127,203 @@ enum Commands {
         expires_at: Option<u64>,
         #[clap(short, long)]
         priority: Option<u64>,
+        // Compress the value with zstd before signing
+        #[clap(long)]
+        compress: bool,
+        // Verifying key allowed to decrypt the value, may be repeated
+        #[clap(long, conflicts_with = "private")]
+        encrypt_to: Vec<String>,
+        // Encrypt the value so only this identity can read it
+        #[clap(long)]
+        private: bool,
+        // Store the value under an HMAC of the name
+        #[clap(long)]
+        hide_name: bool,
+    },
+    // Set a value only if the current value matches, retrying if another write races it
+    Cas {
+        name: String,
+        value: String,
+        // Value expected to be current, omit to expect the name to be unset
+        #[clap(long)]
+        expect: Option<String>,
     },
     Delete {
         name: String,
+        #[clap(long)]
+        hide_name: bool,
     },
     Get {
         verifying_key: String,
         name: String,
+        // Look up a name stored with --hide-name
+        #[clap(long)]
+        hide_name: bool,
+        // Read from the newest key that succeeded this one
+        #[clap(long)]
+        follow_succession: bool,
     },
     Namespace {
         name: String,
+        // Only include this verifying key or alias, may be repeated
+        #[clap(long)]
+        key: Vec<String>,
+        #[clap(long)]
+        limit: Option<usize>,
+        // priority or recency
+        #[clap(long)]
+        order: Option<NamespaceOrder>,
+        // Only include values with at least this priority
+        #[clap(long)]
+        min_priority: Option<u64>,
+        // Only include values the server received at or after this unix timestamp
+        #[clap(long)]
+        since: Option<u64>,
+        // Print the number of contributing keyspaces instead of their values
+        #[clap(long, conflicts_with_all = ["trust", "endorsed", "min_key_work"])]
+        count: bool,
+        #[command(flatten)]
+        trust: TrustArgs,
+    },
+    // Add to a counter, which is grow-only unless created with --decrementable
+    Increment {
+        name: String,
+        #[clap(default_value_t = 1, allow_hyphen_values = true)]
+        amount: i64,
+        #[clap(long)]
+        decrementable: bool,
+    },
+    SetAdd {
+        name: String,
+        element: String,
+    },
+    SetRemove {
+        name: String,
+        element: String,
+    },
+    MapPut {
+        name: String,
+        key: String,
+        value: String,
+    },
+    MapRemove {
+        name: String,
+        key: String,
+    },
+    // Show a counter, set or map merged across all contributors
+    Crdt {
+        name: String,
+        // Only read the replica written by this key
+        #[clap(long)]
+        from: Option<String>,
+        // Only combine contributors holding this kind: counter, set or map
+        #[clap(long)]
+        kind: Option<CrdtKind>,
+    },
+    // Show the signed history of a name retained by the servers
+    History {
+        verifying_key: String,
+        name: String,
+        #[clap(long)]
+        hide_name: bool,
     },
     Whoami,
+    // Send writes queued while no server was reachable
+    Flush,
+    // Print a certificate letting another key write to this identity's keyspace
+    Delegate {
+        delegate: String,
+        #[clap(long)]
+        name_prefix: Option<String>,
+        #[clap(long)]
+        expires_at: Option<u64>,
+    },
+    // Vouch for another key, admitting it to namespaces read with --trust <this identity> --endorsed
+    Endorse {
+        verifying_key: String,
+        // Withdraw an earlier endorsement instead
+        #[clap(long)]
+        withdraw: bool,
+    },
+    // Print the bits of key work of a key, defaults to this identity's
+    KeyWork {
+        verifying_key: Option<String>,
+    },
+    // Endorse another key as the successor of this identity
+    Succeed {
+        successor: String,
+    },
+    // Mark this identity's writes after a unix timestamp (default now) as untrusted
+    Revoke {
+        #[clap(long)]
+        after: Option<u64>,
+    },
+    // Encrypt the signing key file with a passphrase, or change its passphrase
+    ProtectKey,
+    // Mount this identity's keyspace read-write and other keyspaces read-only
+    #[cfg(target_os = "linux")]
+    Mount {
+        mountpoint: PathBuf,
+    },
+    // Run a minimal ssh-agent holding this identity's key, for testing --agent
+    #[cfg(unix)]
+    TestAgent {
+        socket: PathBuf,
+    },
+    Identity {
+        #[command(subcommand)]
+        command: IdentityCommands,
+    },
+    Alias {
+        #[command(subcommand)]
+        command: AliasCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum IdentityCommands {
+    New {
+        name: String,
+        // Search for a key with at least this many bits of key work
+        #[clap(long, default_value_t = 0)]
+        key_work: u32,
+    },
+    List,
+    Use {
+        name: String,
+    },
+    // Print the identity's key file, encrypted if it is passphrase-protected
+    Export {
+        name: String,
+    },
+    // Read a key file from the given path, or stdin if omitted
+    Import {
+        name: String,
+        path: Option<PathBuf>,
+    },
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AliasCommands {
+    Add {
+        alias: String,
+        verifying_key: String,
+        // Also publish the alias as a signed value in this identity's keyspace
+        #[clap(long)]
+        publish: bool,
+    },
+    Remove {
+        alias: String,
+    },
+    List,
+    // Add an alias published by another key, verified against its signature
+    Fetch {
+        owner: String,
+        alias: String,
+    },
 }
 
 #[tokio::main]
@@ -76,23 +349,133 @@ async fn main() -> Result<()> {
         })
         .collect();
 
-    let config = Configuration::new(config_dir, servers);
+    let private_passphrase = std::env::var("BAYBRIDGE_PRIVATE_PASSPHRASE").ok();
+    let key_passphrase = std::env::var("BAYBRIDGE_KEY_PASSPHRASE").ok();
+    let config = Configuration::new(config_dir, servers)
+        .with_identity(cli.identity)?
+        .with_private_passphrase(private_passphrase)
+        .with_key_passphrase(key_passphrase);
     config.init().await?;
+    let mut aliases = AliasBook::load(&config).await?;
+
+    let delegation = cli
+        .delegation
+        .as_deref()
+        .map(decode_delegation)
+        .transpose()?;
+    #[cfg(unix)]
+    let agent_socket = match cli.agent_socket {
+        Some(socket) => Some(socket),
+        None if cli.agent => Some(
+            std::env::var_os("SSH_AUTH_SOCK")
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("SSH_AUTH_SOCK is not set"))?,
+        ),
+        None => None,
+    };
+    #[cfg(unix)]
+    let agent = match agent_socket {
+        Some(socket) => {
+            let agent_key = cli
+                .agent_key
+                .as_deref()
+                .map(|key| aliases.resolve(key))
+                .transpose()?;
+            Some(AgentSigner::connect(&socket, agent_key.as_ref()).await?)
+        }
+        None => None,
+    };
+    let actions = move |config| {
+        let actions = Actions::new(config);
+        #[cfg(unix)]
+        let actions = match agent {
+            Some(agent) => actions.with_signer(agent),
+            None => actions,
+        };
+        match delegation {
+            Some(delegation) => actions.with_delegation(delegation),
+            None => actions,
+        }
+    };
 
     match cli.command {
-        Commands::Serve { peer } => {
+        Commands::Serve {
+            peer,
+            history_retention,
+            protect,
+            trust,
+            min_stamp_work,
+        } => {
             let peer_http_url = peer
                 .iter()
                 .map(|peer| url::Url::parse(peer).expect("Failed to parse peer url: {url}"))
                 .collect();
+            let mut config = config
+                .with_history_retention(history_retention.map(std::time::Duration::from_secs))
+                .with_min_stamp_work(min_stamp_work);
+            let policy = trust.policy(&aliases)?;
+            if !protect.is_empty() && policy.is_open() {
+                bail!("Protecting a namespace needs --trust or --min-key-work");
+            }
+            for name in protect {
+                config = config.with_protected_namespace(name, policy.clone());
+            }
             start_http_server(&config, peer_http_url).await?
         }
+        Commands::Export {
+            path,
+            key,
+            namespace,
+        } => {
+            let filter = ArchiveFilter {
+                keys: key
+                    .iter()
+                    .map(|key| aliases.resolve(key))
+                    .collect::<Result<Vec<_>>>()?,
+                namespaces: namespace,
+            };
+            let node = Node::open(&config).await?;
+            let summary = match path {
+                Some(path) => {
+                    let file = tokio::fs::File::create(path).await?;
+                    archive::export(&node, &filter, tokio::io::BufWriter::new(file)).await?
+                }
+                None => archive::export(&node, &filter, tokio::io::stdout()).await?,
+            };
+            eprintln!(
+                "Exported {} events and {} blocks",
+                summary.events, summary.blocks
+            );
+        }
+        Commands::Import {
+            path,
+            min_stamp_work,
+        } => {
+            let node = Node::open(&config.with_min_stamp_work(min_stamp_work)).await?;
+            let summary = match path {
+                Some(path) => {
+                    let file = tokio::fs::File::open(path).await?;
+                    archive::import(&node, tokio::io::BufReader::new(file)).await?
+                }
+                None => archive::import(&node, tokio::io::stdin()).await?,
+            };
+            println!(
+                "Imported {} events and {} blocks, rejected {} events",
+                summary.events - summary.rejected_events,
+                summary.blocks,
+                summary.rejected_events
+            );
+        }
         Commands::Set {
             name,
             value,
             ttl,
             expires_at,
             priority,
+            compress,
+            encrypt_to,
+            private,
+            hide_name,
         } => {
             let name = Name::new(name);
             let value = Value::new(value.as_bytes().to_vec());
@@ -101,44 +484,354 @@ async fn main() -> Result<()> {
                 Some(expires_at) => Some(Expiry::ExpiresAt(expires_at)),
                 None => expiry,
             };
+            let compression = compress.then_some(Compression::Zstd);
+            let recipients = encrypt_to
+                .iter()
+                .map(|key| aliases.resolve(key))
+                .collect::<Result<Vec<_>>>()?;
 
-            Actions::new(config)
+            actions(config)
                 .set()
                 .name(name)
                 .value(value)
                 .maybe_expiry(expiry)
                 .maybe_priority(priority)
+                .maybe_compression(compression)
+                .recipients(recipients)
+                .private(private)
+                .hide_name(hide_name)
                 .call()
                 .await?
         }
-        Commands::Delete { name } => {
+        Commands::Cas {
+            name,
+            value,
+            expect,
+        } => {
+            actions(config)
+                .cas(Name::new(name), |current| {
+                    let current = current.map(|current| current.as_bytes().to_vec());
+                    let expected = expect.as_ref().map(|expect| expect.as_bytes().to_vec());
+                    if current != expected {
+                        bail!(
+                            "Current value is {:?}",
+                            current.map(|current| String::from_utf8_lossy(&current).to_string())
+                        );
+                    }
+                    Ok(Value::new(value.as_bytes().to_vec()))
+                })
+                .await?;
+        }
+        Commands::Delete { name, hide_name } => {
+            let actions = actions(config);
             let name = Name::new(name);
-            Actions::new(config).delete(name).await?
+            let name = if hide_name {
+                actions.hidden_name(&name).await?
+            } else {
+                name
+            };
+            actions.delete(name).await?
         }
         Commands::Get {
             verifying_key,
             name,
+            hide_name,
+            follow_succession,
         } => {
+            let actions = actions(config);
             let name = Name::new(name);
-            let value = Actions::new(config).get(&verifying_key, &name).await?;
-            let value = String::from_utf8_lossy(value.as_bytes());
+            let name = if hide_name {
+                actions.hidden_name(&name).await?
+            } else {
+                name
+            };
+            let reading = actions
+                .read()
+                .verifying_key(&verifying_key)
+                .name(&name)
+                .follow_succession(follow_succession)
+                .call()
+                .await?;
+            match reading.freshness {
+                Freshness::Live => {}
+                Freshness::Stale {
+                    synced_at: Some(synced_at),
+                } => eprintln!(
+                    "No server reachable, read from the local replica synced at {synced_at}"
+                ),
+                Freshness::Stale { synced_at: None } => {
+                    eprintln!("No server reachable, read from the local replica")
+                }
+            }
+            let value = String::from_utf8_lossy(reading.value.as_bytes());
             println!("{}", value);
         }
-        Commands::Namespace { name } => {
-            let namespace = Actions::new(config).namespace(&name).await?;
+        Commands::Namespace {
+            name,
+            key,
+            limit,
+            order,
+            min_priority,
+            since,
+            count,
+            trust,
+        } => {
+            let keys = if key.is_empty() {
+                None
+            } else {
+                Some(
+                    key.iter()
+                        .map(|key| aliases.resolve(key))
+                        .collect::<Result<Vec<_>>>()?,
+                )
+            };
+            if count {
+                let count = actions(config)
+                    .namespace_count()
+                    .name(&name)
+                    .maybe_keys(keys)
+                    .maybe_min_priority(min_priority)
+                    .maybe_since(since)
+                    .call()
+                    .await?;
+                println!("{}", count);
+                return Ok(());
+            }
+            let namespace = actions(config)
+                .namespace()
+                .name(&name)
+                .maybe_keys(keys)
+                .maybe_limit(limit)
+                .maybe_order(order)
+                .maybe_min_priority(min_priority)
+                .maybe_since(since)
+                .trust(&trust.policy(&aliases)?)
+                .call()
+                .await?;
             for (verifying_key, value) in namespace.mapping {
-                println!(
-                    "{}: {}",
-                    encode_verifying_key(&verifying_key),
-                    String::from_utf8_lossy(value.as_bytes())
-                );
+                let encoded_verifying_key = encode_verifying_key(&verifying_key);
+                let writer = match aliases.alias_of(&verifying_key) {
+                    Some(alias) => format!("{alias} ({encoded_verifying_key})"),
+                    None => encoded_verifying_key,
+                };
+                println!("{}: {}", writer, String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        Commands::Increment {
+            name,
+            amount,
+            decrementable,
+        } => {
+            actions(config)
+                .increment()
+                .name(Name::new(name))
+                .amount(amount)
+                .decrementable(decrementable)
+                .call()
+                .await?
+        }
+        Commands::SetAdd { name, element } => {
+            actions(config).set_add(Name::new(name), element).await?
+        }
+        Commands::SetRemove { name, element } => {
+            actions(config).set_remove(Name::new(name), element).await?
+        }
+        Commands::MapPut { name, key, value } => {
+            let value = Value::new(value.as_bytes().to_vec());
+            actions(config).map_put(Name::new(name), key, value).await?
+        }
+        Commands::MapRemove { name, key } => {
+            actions(config).map_remove(Name::new(name), key).await?
+        }
+        Commands::Crdt { name, from, kind } => {
+            let value = actions(config)
+                .crdt_value()
+                .name(&Name::new(name))
+                .maybe_from(from.as_deref())
+                .maybe_kind(kind)
+                .call()
+                .await?;
+            match value {
+                CrdtValue::Counter(count) => println!("{}", count),
+                CrdtValue::Set(elements) => {
+                    for element in elements {
+                        println!("{}", element);
+                    }
+                }
+                CrdtValue::Map(entries) => {
+                    for (key, value) in entries {
+                        println!("{}: {}", key, String::from_utf8_lossy(value.as_bytes()));
+                    }
+                }
+            }
+        }
+        Commands::History {
+            verifying_key,
+            name,
+            hide_name,
+        } => {
+            let actions = actions(config);
+            let name = Name::new(name);
+            let name = if hide_name {
+                actions.hidden_name(&name).await?
+            } else {
+                name
+            };
+            for event in actions.history(&verifying_key, &name).await? {
+                let Some(signer) = event.verifying_key() else {
+                    continue;
+                };
+                let signer = match aliases.alias_of(&signer) {
+                    Some(alias) => alias.to_string(),
+                    None => encode_verifying_key(&signer),
+                };
+                let description = match &event.inner {
+                    Event::Set(set) => match actions.decode_value(set).await {
+                        Ok(value) => format!("set {}", String::from_utf8_lossy(value.as_bytes())),
+                        Err(_) => "set <unreadable>".to_string(),
+                    },
+                    Event::Delete(_) => "delete".to_string(),
+                    Event::Succession(succession) => match succession.successor() {
+                        Some(successor) => {
+                            format!("succession {}", encode_verifying_key(&successor))
+                        }
+                        None => "succession <invalid key>".to_string(),
+                    },
+                    Event::Revocation(revocation) => {
+                        format!("revocation after {}", revocation.revoked_after)
+                    }
+                    Event::Crdt(crdt) => format!("{} {:?}", crdt.state.kind(), crdt.state),
+                    Event::Endorsement(endorsement) => {
+                        let action = if endorsement.withdrawn {
+                            "withdrawn endorsement"
+                        } else {
+                            "endorsement"
+                        };
+                        match endorsement.endorsed() {
+                            Some(endorsed) => {
+                                format!("{} {}", action, encode_verifying_key(&endorsed))
+                            }
+                            None => format!("{} <invalid key>", action),
+                        }
+                    }
+                };
+                println!("{} {} {}", event.inner.priority(), signer, description);
             }
         }
         Commands::Whoami => {
-            let verifying_key = Actions::new(config).whoami().await;
+            let verifying_key = actions(config).whoami().await?;
             let encoded_verifying_key = encode_verifying_key(&verifying_key);
             println!("{}", encoded_verifying_key);
         }
+        Commands::Flush => {
+            let flushed = actions(config).flush().await?;
+            println!("Sent {flushed} queued events");
+        }
+        Commands::Delegate {
+            delegate,
+            name_prefix,
+            expires_at,
+        } => {
+            let delegate = aliases.resolve(&delegate)?;
+            let delegation = actions(config)
+                .delegate()
+                .delegate(&delegate)
+                .maybe_name_prefix(name_prefix)
+                .maybe_expires_at(expires_at)
+                .call()
+                .await?;
+            println!("{}", encode_delegation(&delegation)?);
+        }
+        Commands::Endorse {
+            verifying_key,
+            withdraw,
+        } => {
+            let verifying_key = aliases.resolve(&verifying_key)?;
+            if withdraw {
+                actions(config).withdraw_endorsement(&verifying_key).await?
+            } else {
+                actions(config).endorse(&verifying_key).await?
+            }
+        }
+        Commands::KeyWork { verifying_key } => {
+            let verifying_key = match verifying_key {
+                Some(verifying_key) => aliases.resolve(&verifying_key)?,
+                None => actions(config).whoami().await?,
+            };
+            println!("{}", key_work(&verifying_key));
+        }
+        Commands::Succeed { successor } => {
+            let successor = aliases.resolve(&successor)?;
+            actions(config).succeed(&successor).await?
+        }
+        Commands::Revoke { after } => actions(config).revoke(after).await?,
+        Commands::ProtectKey => CryptoKey::protect(&config, None).await?,
+        #[cfg(target_os = "linux")]
+        Commands::Mount { mountpoint } => {
+            baybridge::fuse::mount(actions(config), aliases, &mountpoint).await?
+        }
+        #[cfg(unix)]
+        Commands::TestAgent { socket } => {
+            let crypto_key = CryptoKey::from_config(&config).await?;
+            agent::serve(&socket, vec![crypto_key]).await?
+        }
+        Commands::Identity { command } => match command {
+            IdentityCommands::New { name, key_work } => {
+                let verifying_key = identity::create(&config, &name, key_work).await?;
+                println!("{}", encode_verifying_key(&verifying_key));
+            }
+            IdentityCommands::List => {
+                let current = config.identity_name();
+                for name in identity::list(&config).await? {
+                    let marker = if name == current { "*" } else { " " };
+                    println!("{} {}", marker, name);
+                }
+            }
+            IdentityCommands::Use { name } => identity::select(&config, &name).await?,
+            IdentityCommands::Export { name } => {
+                print!("{}", identity::export(&config, &name).await?);
+            }
+            IdentityCommands::Import { name, path } => {
+                let contents = match path {
+                    Some(path) => tokio::fs::read_to_string(path).await?,
+                    None => std::io::read_to_string(std::io::stdin())?,
+                };
+                identity::import(&config, &name, &contents).await?
+            }
+            IdentityCommands::Remove { name } => identity::remove(&config, &name).await?,
+        },
+        Commands::Alias { command } => match command {
+            AliasCommands::Add {
+                alias,
+                verifying_key,
+                publish,
+            } => {
+                let verifying_key = aliases.resolve(&verifying_key)?;
+                aliases.add(&alias, &verifying_key)?;
+                aliases.save(&config).await?;
+                if publish {
+                    actions(config)
+                        .publish_alias(&alias, &verifying_key)
+                        .await?
+                }
+            }
+            AliasCommands::Remove { alias } => {
+                aliases.remove(&alias)?;
+                aliases.save(&config).await?
+            }
+            AliasCommands::List => {
+                for (alias, verifying_key) in aliases.iter() {
+                    println!("{}: {}", alias, verifying_key);
+                }
+            }
+            AliasCommands::Fetch { owner, alias } => {
+                let actions = actions(config);
+                let verifying_key = actions.fetch_alias(&owner, &alias).await?;
+                aliases.add(&alias, &verifying_key)?;
+                aliases.save(&actions.config).await?;
+                println!("{}", encode_verifying_key(&verifying_key));
+            }
+        },
     }
     Ok(())
 }
@@ -8,4 +8,4 @@ pub use immutable::ContentBlock;
 pub use name::Name;
 pub use namespace::NamespaceValues;
 pub use peer::Peers;
-pub use value::Value;
+pub use value::{Compression, Encryption, Value};
@@ -1,10 +1,9 @@
-use std::collections::HashMap;
-
 use ed25519_dalek::VerifyingKey;
 
 use super::Value;
 
 pub struct NamespaceValues {
     pub namespace: String,
-    pub mapping: HashMap<VerifyingKey, Value>,
+    /// Values by keyspace, in the order requested from the servers
+    pub mapping: Vec<(VerifyingKey, Value)>,
 }
@@ -1,12 +1,18 @@
+use std::io::Read;
+
+use anyhow::{Result, bail};
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use serde_with::base64::Base64;
-use serde_with::serde_as;
+use serde_with::{Bytes, IfIsHumanReadable, serde_as};
+
+/// Largest size a compressed value may decompress to
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
 
 #[serde_as]
 #[derive(Clone, Debug, Encode, Decode, Deserialize, Serialize)]
 pub struct Value {
-    #[serde_as(as = "Base64")]
+    #[serde_as(as = "IfIsHumanReadable<Base64, Bytes>")]
     bytes: Vec<u8>,
 }
 
@@ -18,6 +24,48 @@ impl Value {
     pub fn as_bytes(&self) -> &[u8] {
         &self.bytes
     }
+
+    pub fn compress(&self, compression: Compression) -> Result<Value> {
+        match compression {
+            Compression::None => Ok(self.clone()),
+            Compression::Zstd => Ok(Value::new(zstd::encode_all(self.as_bytes(), 0)?)),
+        }
+    }
+
+    pub fn decompress(&self, compression: Compression) -> Result<Value> {
+        match compression {
+            Compression::None => Ok(self.clone()),
+            Compression::Zstd => {
+                let mut bytes = Vec::new();
+                zstd::Decoder::new(self.as_bytes())?
+                    .take(MAX_DECOMPRESSED_SIZE + 1)
+                    .read_to_end(&mut bytes)?;
+                if bytes.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                    bail!("Value decompresses to more than {MAX_DECOMPRESSED_SIZE} bytes");
+                }
+                Ok(Value::new(bytes))
+            }
+        }
+    }
+}
+
+/// Encryption applied to a value's bytes before it was signed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode, Deserialize, Serialize)]
+pub enum Encryption {
+    #[default]
+    None,
+    /// Sealed in an envelope readable by a set of recipient verifying keys
+    Recipients,
+    /// Encrypted with a symmetric key only the writer holds
+    Private,
+}
+
+/// Compression applied to a value's bytes before it was signed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode, Deserialize, Serialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
 }
 
 impl From<Vec<u8>> for Value {
@@ -0,0 +1,346 @@
+//! An archive starts with `ARCHIVE_MAGIC` and a header, followed by one record per event or
+//! block and a trailer. Each part is framed by its length as a little-endian `u32` and
+//! encoded with bincode. The trailer counts the records and holds a blake3 hash of every frame
+//! before it.
+
+use std::collections::HashSet;
+
+use anyhow::{Result, anyhow, bail};
+use bincode::{Decode, Encode, config::standard};
+use ed25519_dalek::VerifyingKey;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::{
+    api::INSUFFICIENT_WORK,
+    client::{
+        Event, SetEvent,
+        content::{ContentPointer, decode_block},
+    },
+    crypto::{Signed, encode::encode_verifying_key},
+    models::Encryption,
+    time::unix_timestamp,
+};
+
+use super::node::Node;
+
+pub const ARCHIVE_MAGIC: &[u8] = b"baybridge-archive\n";
+
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Largest frame accepted when reading, well above the largest block
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+const EXPORT_PAGE_SIZE: usize = 1000;
+
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+#[derive(Encode, Decode)]
+struct Header {
+    version: u32,
+    created_at: u64,
+    // Filters the archive was exported with, empty for everything
+    keys: Vec<String>,
+    namespaces: Vec<String>,
+}
+
+#[derive(Encode, Decode)]
+enum Record {
+    Event(Signed<Event>),
+    Block { hash: [u8; 32], encoded: Vec<u8> },
+    End(Trailer),
+}
+
+#[derive(Encode, Decode)]
+struct Trailer {
+    events: u64,
+    blocks: u64,
+    checksum: [u8; 32],
+}
+
+/// Empty lists match everything
+#[derive(Debug, Default, Clone)]
+pub struct ArchiveFilter {
+    pub keys: Vec<VerifyingKey>,
+    pub namespaces: Vec<String>,
+}
+
+impl ArchiveFilter {
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.namespaces.is_empty()
+    }
+
+    fn matches(&self, event: &Signed<Event>) -> bool {
+        (self.keys.is_empty()
+            || event
+                .keyspace()
+                .is_some_and(|keyspace| self.keys.contains(&keyspace)))
+            && (self.namespaces.is_empty()
+                || self
+                    .namespaces
+                    .iter()
+                    .any(|namespace| namespace == event.inner.name().as_str()))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveSummary {
+    pub events: u64,
+    pub blocks: u64,
+    pub rejected_events: u64,
+}
+
+/// When filtering, only blocks reachable from the content pointers of exported plaintext values
+/// are included, since blocks do not belong to a keyspace.
+pub async fn export(
+    node: &Node,
+    filter: &ArchiveFilter,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<ArchiveSummary> {
+    let mut writer = FrameWriter::new(writer);
+    writer.writer.write_all(ARCHIVE_MAGIC).await?;
+    let header = Header {
+        version: ARCHIVE_VERSION,
+        created_at: unix_timestamp(),
+        keys: filter.keys.iter().map(encode_verifying_key).collect(),
+        namespaces: filter.namespaces.clone(),
+    };
+    writer.write(&header).await?;
+
+    let mut summary = ArchiveSummary::default();
+    let mut roots = Vec::new();
+    let mut after = None;
+    loop {
+        let events = node
+            .controller()
+            .signed_events_page(after, EXPORT_PAGE_SIZE)
+            .await?;
+        let Some(last) = events.last() else {
+            break;
+        };
+        after = Some(last.to_bytes());
+        for event in events.into_iter().filter(|event| filter.matches(event)) {
+            if let Some(root) = content_root(&event) {
+                roots.push(root);
+            }
+            writer.write(&Record::Event(event)).await?;
+            summary.events += 1;
+        }
+    }
+
+    let immutable_controller = node.immutable_controller();
+    let hashes = if filter.is_empty() {
+        immutable_controller.hashes().await?
+    } else {
+        roots
+    };
+    // Blocks are read one at a time, following references when exporting a filtered set
+    let mut pending = hashes;
+    let mut seen = HashSet::new();
+    while let Some(hash) = pending.pop() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        let Some(encoded) = immutable_controller.get_encoded(&hash).await else {
+            warn!("Skipping missing block {}", hash);
+            continue;
+        };
+        if !filter.is_empty() {
+            pending.extend(decode_block(&hash, &encoded)?.references);
+        }
+        writer
+            .write(&Record::Block {
+                hash: *hash.as_bytes(),
+                encoded,
+            })
+            .await?;
+        summary.blocks += 1;
+    }
+
+    let checksum = *writer.hasher.finalize().as_bytes();
+    writer
+        .write(&Record::End(Trailer {
+            events: summary.events,
+            blocks: summary.blocks,
+            checksum,
+        }))
+        .await?;
+    writer.writer.flush().await?;
+    Ok(summary)
+}
+
+/// Records are applied as they are read, so a corrupt archive fails after importing the valid
+/// records before the corruption
+pub async fn import(node: &Node, reader: impl AsyncRead + Unpin) -> Result<ArchiveSummary> {
+    let mut reader = FrameReader::new(reader);
+    let mut magic = vec![0; ARCHIVE_MAGIC.len()];
+    reader
+        .reader
+        .read_exact(&mut magic)
+        .await
+        .map_err(|_| anyhow!("Not a baybridge archive"))?;
+    if magic != ARCHIVE_MAGIC {
+        bail!("Not a baybridge archive");
+    }
+    let header: Header = reader
+        .read()
+        .await?
+        .ok_or_else(|| anyhow!("Archive ends before its header"))?;
+    if header.version != ARCHIVE_VERSION {
+        bail!(
+            "Archive version {} is not supported, expected {}",
+            header.version,
+            ARCHIVE_VERSION
+        );
+    }
+
+    let mut summary = ArchiveSummary::default();
+    let mut batch = Vec::new();
+    let read = read_records(node, &mut reader, &mut summary, &mut batch).await;
+    // Events read before a failure passed their checks, so keep them like earlier batches
+    node.controller().insert_events(batch).await?;
+    read?;
+    Ok(summary)
+}
+
+/// Leaves the events read since the last full batch in `batch`
+async fn read_records<R: AsyncRead + Unpin>(
+    node: &Node,
+    reader: &mut FrameReader<R>,
+    summary: &mut ArchiveSummary,
+    batch: &mut Vec<Signed<Event>>,
+) -> Result<()> {
+    loop {
+        let checksum = reader.hasher.finalize();
+        let record = reader
+            .read()
+            .await?
+            .ok_or_else(|| anyhow!("Archive ends before its trailer"))?;
+        match record {
+            Record::Event(event) => {
+                summary.events += 1;
+                // Checked like events synced from a peer: archived delegations may have
+                // expired since, which does not undo the writes made under them
+                let rejected = if event.verified_keyspace().is_none() {
+                    Some("Invalid signature")
+                } else if node
+                    .min_stamp_work()
+                    .is_some_and(|min_stamp_work| !event.meets_stamp_work(min_stamp_work))
+                {
+                    Some(INSUFFICIENT_WORK)
+                } else {
+                    None
+                };
+                if let Some(reason) = rejected {
+                    warn!("Rejected archived event: {}", reason);
+                    summary.rejected_events += 1;
+                    continue;
+                }
+                batch.push(event);
+                if batch.len() >= IMPORT_BATCH_SIZE {
+                    node.controller()
+                        .insert_events(std::mem::take(batch))
+                        .await?;
+                }
+            }
+            Record::Block { hash, encoded } => {
+                let hash = blake3::Hash::from_bytes(hash);
+                let block = decode_block(&hash, &encoded)?;
+                node.immutable_controller().set(&block).await;
+                summary.blocks += 1;
+            }
+            Record::End(trailer) => {
+                if trailer.checksum != *checksum.as_bytes() {
+                    bail!("Archive checksum does not match its contents");
+                }
+                if (trailer.events, trailer.blocks) != (summary.events, summary.blocks) {
+                    bail!(
+                        "Archive should hold {} events and {} blocks, found {} and {}",
+                        trailer.events,
+                        trailer.blocks,
+                        summary.events,
+                        summary.blocks
+                    );
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn content_root(event: &Signed<Event>) -> Option<blake3::Hash> {
+    let Event::Set(SetEvent {
+        value,
+        compression,
+        encryption: Encryption::None,
+        ..
+    }) = &event.inner
+    else {
+        return None;
+    };
+    let value = value.decompress(*compression).ok()?;
+    ContentPointer::from_value(&value).map(|pointer| pointer.root)
+}
+
+struct FrameWriter<W> {
+    writer: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    async fn write(&mut self, item: &impl Encode) -> Result<()> {
+        let encoded = bincode::encode_to_vec(item, standard())?;
+        let length = u32::try_from(encoded.len())?.to_le_bytes();
+        self.hasher.update(&length);
+        self.hasher.update(&encoded);
+        self.writer.write_all(&length).await?;
+        self.writer.write_all(&encoded).await?;
+        Ok(())
+    }
+}
+
+struct FrameReader<R> {
+    reader: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    async fn read<T: Decode<()>>(&mut self) -> Result<Option<T>> {
+        let mut length = [0; 4];
+        match self.reader.read_exact(&mut length).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let size = u32::from_le_bytes(length) as usize;
+        if size > MAX_FRAME_SIZE {
+            bail!("Archive frame of {size} bytes is too large");
+        }
+        let mut encoded = vec![0; size];
+        self.reader
+            .read_exact(&mut encoded)
+            .await
+            .map_err(|_| anyhow!("Archive is truncated"))?;
+        self.hasher.update(&length);
+        self.hasher.update(&encoded);
+        let (item, _) =
+            bincode::decode_from_slice(&encoded, standard().with_limit::<MAX_FRAME_SIZE>())
+                .map_err(|e| anyhow!("Archive record is corrupt: {e}"))?;
+        Ok(Some(item))
+    }
+}
@@ -0,0 +1,628 @@
+//! Behaviour every `EventStore` backend must share. Each check panics on a mismatch, so a
+//! backend is tested by calling `run` from a test with a constructor for fresh stores.
+
+use std::{collections::HashMap, time::Duration};
+
+use futures::future::join_all;
+
+use crate::{
+    api::{NamespaceOrder, StateHash, WriteConflict},
+    client::{Event, NO_PREVIOUS_EVENT, REVOCATION_NAME, RevocationEvent, SetEvent},
+    crypto::{CryptoKey, Signed, encode::encode_verifying_key},
+    models::{Name, Value},
+    time::unix_timestamp,
+};
+
+use super::event_store::EventStore;
+
+const HISTORY_RETENTION: Duration = Duration::from_secs(3600);
+
+pub async fn run<S: EventStore>(new_store: impl Fn(Option<Duration>) -> S) {
+    stores_and_reads_events(&new_store(None)).await;
+    ignores_duplicate_events(&new_store(None)).await;
+    supersedes_lower_priorities(&new_store(None)).await;
+    keeps_events_outliving_newer_ones(&new_store(None)).await;
+    keeps_earliest_revocations(&new_store(None)).await;
+    detects_stale_events(&new_store(None)).await;
+    inserts_current_events(&new_store(Some(HISTORY_RETENTION))).await;
+    checks_expected_previous_events(&new_store(None)).await;
+    inserts_batches_of_current_events(&new_store(None)).await;
+    admits_one_of_concurrent_conditional_writes(&new_store(None)).await;
+    deletes_expired_events(&new_store(None)).await;
+    stores_the_largest_priorities_and_expiries(&new_store(None)).await;
+    drops_superseded_events_without_retention(&new_store(None)).await;
+    retains_superseded_events(&new_store(Some(HISTORY_RETENTION))).await;
+    groups_and_orders_namespaces(&new_store(None)).await;
+    keeps_namespace_groups_in_received_order(&new_store(None)).await;
+    filters_namespaces_by_received_time(&new_store(None)).await;
+    state_hash_ignores_insertion_order(&new_store(None), &new_store(None)).await;
+    pages_through_events(&new_store(None)).await;
+    remembers_peer_hashes(&new_store(None)).await;
+    queues_events_in_order(&new_store(None)).await;
+    remembers_sync_times(&new_store(None)).await;
+}
+
+pub async fn stores_and_reads_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let mut bob = CryptoKey::generate();
+    let first = set_event(&mut alice, "first", 1, None);
+    let second = set_event(&mut alice, "second", 1, None);
+    let other = set_event(&mut bob, "first", 1, None);
+    for event in [&first, &second, &other] {
+        assert_eq!(store.insert_event(event).await.unwrap(), 1);
+    }
+
+    assert_eq!(store.event_count().await.unwrap(), 3);
+    assert_eq!(
+        store.event_counts_by_kind().await.unwrap(),
+        HashMap::from([("set", 3)])
+    );
+    assert!(store.size_bytes().await.unwrap() > 0);
+    assert_eq!(
+        unordered(&store.events_by_key(key_of(&alice)).await.unwrap()),
+        unordered(&[first.clone(), second])
+    );
+    assert_eq!(
+        signatures(
+            &store
+                .events_by_key_and_name(key_of(&alice), "first".to_string())
+                .await
+                .unwrap()
+        ),
+        signatures(&[first])
+    );
+    assert!(
+        store
+            .events_by_key_and_name(key_of(&alice), "missing".to_string())
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+pub async fn ignores_duplicate_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let event = set_event(&mut alice, "name", 1, None);
+    assert_eq!(store.insert_event(&event).await.unwrap(), 1);
+    assert_eq!(store.insert_event(&event).await.unwrap(), 0);
+    assert_eq!(store.event_count().await.unwrap(), 1);
+}
+
+pub async fn supersedes_lower_priorities(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let old = set_event(&mut alice, "name", 1, None);
+    let new = set_event(&mut alice, "name", 2, None);
+    let unrelated = set_event(&mut alice, "other", 1, None);
+    for event in [&old, &unrelated, &new] {
+        store.insert_event(event).await.unwrap();
+    }
+
+    assert_eq!(store.delete_stale_events(&new).await.unwrap(), 1);
+    assert_eq!(
+        unordered(&store.events_by_key(key_of(&alice)).await.unwrap()),
+        unordered(&[unrelated, new])
+    );
+}
+
+pub async fn keeps_events_outliving_newer_ones(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let lasting = set_event(&mut alice, "name", 1, Some(200));
+    let permanent = set_event(&mut alice, "name", 1, None);
+    let new = set_event(&mut alice, "name", 2, Some(100));
+    for event in [&lasting, &permanent, &new] {
+        store.insert_event(event).await.unwrap();
+    }
+
+    assert_eq!(store.delete_stale_events(&new).await.unwrap(), 0);
+    assert_eq!(store.event_count().await.unwrap(), 3);
+}
+
+pub async fn keeps_earliest_revocations(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let mut revocation = |revoked_after, priority| {
+        alice.sign(Event::Revocation(RevocationEvent {
+            revoked_after,
+            priority,
+        }))
+    };
+    let first = revocation(100, 1);
+    let later = revocation(u64::MAX, 2);
+    let earlier = revocation(50, 3);
+    let revocations = || async {
+        store
+            .events_by_key_and_name(key_of(&alice), REVOCATION_NAME.to_string())
+            .await
+            .unwrap()
+    };
+
+    store.insert_current_event(&first, None).await.unwrap();
+    store.insert_current_event(&later, None).await.unwrap();
+    assert!(signatures(&revocations().await).contains(&first.signature().unwrap().to_bytes()));
+
+    store.insert_current_event(&earlier, None).await.unwrap();
+    assert_eq!(signatures(&revocations().await), signatures(&[earlier]));
+}
+
+pub async fn detects_stale_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let current = set_event(&mut alice, "name", 2, Some(200));
+    store.insert_event(&current).await.unwrap();
+
+    let outranked = set_event(&mut alice, "name", 1, Some(100));
+    let newer = set_event(&mut alice, "name", 3, Some(100));
+    let outliving = set_event(&mut alice, "name", 1, Some(300));
+    let elsewhere = set_event(&mut alice, "other", 1, Some(100));
+    assert!(store.is_stale_event(&outranked).await.unwrap());
+    assert!(!store.is_stale_event(&newer).await.unwrap());
+    assert!(!store.is_stale_event(&outliving).await.unwrap());
+    assert!(!store.is_stale_event(&elsewhere).await.unwrap());
+}
+
+pub async fn inserts_current_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let old = set_event(&mut alice, "name", 1, None);
+    let new = set_event(&mut alice, "name", 2, None);
+    assert_eq!(store.insert_current_event(&old, None).await.unwrap(), 1);
+    assert_eq!(store.insert_current_event(&new, None).await.unwrap(), 1);
+    assert_eq!(store.insert_current_event(&new, None).await.unwrap(), 0);
+
+    let mut bob = CryptoKey::generate();
+    let lasting = set_event(&mut bob, "name", 2, Some(200));
+    let outranked = set_event(&mut bob, "name", 1, Some(100));
+    assert_eq!(store.insert_current_event(&lasting, None).await.unwrap(), 1);
+    assert_eq!(
+        store.insert_current_event(&outranked, None).await.unwrap(),
+        0
+    );
+
+    let history = store
+        .history_by_key_and_name(key_of(&alice), "name".to_string())
+        .await
+        .unwrap();
+    assert_eq!(signatures(&history), signatures(&[old, new.clone()]));
+    assert_eq!(
+        signatures(&store.events_by_key(key_of(&alice)).await.unwrap()),
+        signatures(&[new])
+    );
+}
+
+pub async fn checks_expected_previous_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let first = set_event(&mut alice, "name", 1, None);
+    let stale = set_event(&mut alice, "name", 2, None);
+    assert_eq!(
+        store
+            .insert_current_event(&first, Some(NO_PREVIOUS_EVENT))
+            .await
+            .unwrap(),
+        1
+    );
+    let conflict = store
+        .insert_current_event(&stale, Some(NO_PREVIOUS_EVENT))
+        .await
+        .unwrap_err();
+    assert!(conflict.is::<WriteConflict>());
+
+    let second = set_event(&mut alice, "name", 2, None);
+    let expected_previous = Some(first.inner.hash());
+    assert_eq!(
+        store
+            .insert_current_event(&second, expected_previous)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        signatures(&store.events_by_key(key_of(&alice)).await.unwrap()),
+        signatures(&[second])
+    );
+}
+
+pub async fn inserts_batches_of_current_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let old = set_event(&mut alice, "name", 1, None);
+    let new = set_event(&mut alice, "name", 2, None);
+    let other = set_event(&mut alice, "other", 1, None);
+    let lasting = set_event(&mut alice, "expiring", 2, Some(200));
+    let outranked = set_event(&mut alice, "expiring", 1, Some(100));
+    let batch = [
+        old,
+        new.clone(),
+        other.clone(),
+        new.clone(),
+        lasting.clone(),
+        outranked,
+    ];
+    assert_eq!(store.insert_current_events(&batch).await.unwrap(), 4);
+
+    assert_eq!(
+        unordered(&store.events_by_key(key_of(&alice)).await.unwrap()),
+        unordered(&[new, other, lasting])
+    );
+    assert_eq!(store.insert_current_events(&[]).await.unwrap(), 0);
+}
+
+pub async fn admits_one_of_concurrent_conditional_writes(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let writes = (1..=8)
+        .map(|priority| set_event(&mut alice, "name", priority, None))
+        .collect::<Vec<_>>();
+    let results = join_all(
+        writes
+            .iter()
+            .map(|event| store.insert_current_event(event, Some(NO_PREVIOUS_EVENT))),
+    )
+    .await;
+
+    let accepted = results.iter().filter(|result| result.is_ok()).count();
+    assert_eq!(accepted, 1);
+    assert!(
+        results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .all(|e| e.is::<WriteConflict>())
+    );
+    assert_eq!(store.event_count().await.unwrap(), 1);
+}
+
+pub async fn deletes_expired_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let expiring = set_event(&mut alice, "expiring", 1, Some(100));
+    let later = set_event(&mut alice, "later", 1, Some(101));
+    let permanent = set_event(&mut alice, "permanent", 1, None);
+    for event in [&expiring, &later, &permanent] {
+        store.insert_event(event).await.unwrap();
+    }
+
+    assert_eq!(store.delete_expired_events(100).await.unwrap(), 1);
+    assert_eq!(
+        unordered(&store.events_by_key(key_of(&alice)).await.unwrap()),
+        unordered(&[later, permanent])
+    );
+}
+
+pub async fn stores_the_largest_priorities_and_expiries(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let lower = set_event(&mut alice, "name", i64::MAX as u64 - 1, Some(100));
+    let largest = set_event(&mut alice, "name", u64::MAX, Some(u64::MAX));
+    assert_eq!(
+        store
+            .insert_current_events(std::slice::from_ref(&largest))
+            .await
+            .unwrap(),
+        1
+    );
+
+    assert!(store.is_stale_event(&lower).await.unwrap());
+    assert_eq!(
+        signatures(&store.events_by_key(key_of(&alice)).await.unwrap()),
+        signatures(&[largest])
+    );
+}
+
+pub async fn drops_superseded_events_without_retention(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let old = set_event(&mut alice, "name", 1, None);
+    let new = set_event(&mut alice, "name", 2, None);
+    for event in [&old, &new] {
+        store.insert_event(event).await.unwrap();
+    }
+    store.delete_stale_events(&new).await.unwrap();
+
+    let history = store
+        .history_by_key_and_name(key_of(&alice), "name".to_string())
+        .await
+        .unwrap();
+    assert_eq!(signatures(&history), signatures(&[new]));
+    assert_eq!(store.delete_old_history(u64::MAX).await.unwrap(), 0);
+}
+
+pub async fn retains_superseded_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let old = set_event(&mut alice, "name", 1, None);
+    let new = set_event(&mut alice, "name", 2, None);
+    store.insert_event(&new).await.unwrap();
+    store.insert_event(&old).await.unwrap();
+    store.delete_stale_events(&new).await.unwrap();
+
+    let history = || async {
+        let history = store
+            .history_by_key_and_name(key_of(&alice), "name".to_string())
+            .await
+            .unwrap();
+        signatures(&history)
+    };
+    assert_eq!(history().await, signatures(&[old, new.clone()]));
+    assert_eq!(store.event_count().await.unwrap(), 1);
+
+    let now = unix_timestamp();
+    assert_eq!(store.delete_old_history(now).await.unwrap(), 0);
+    let past_retention = now + HISTORY_RETENTION.as_secs() + 1;
+    assert_eq!(store.delete_old_history(past_retention).await.unwrap(), 1);
+    assert_eq!(history().await, signatures(&[new]));
+}
+
+pub async fn groups_and_orders_namespaces(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let mut bob = CryptoKey::generate();
+    let mut carol = CryptoKey::generate();
+    let from_bob = set_event(&mut bob, "shared", 1, None);
+    let from_alice = set_event(&mut alice, "shared", 5, None);
+    let unrelated = set_event(&mut alice, "other", 5, None);
+    let from_carol = set_event(&mut carol, "shared", 3, None);
+    for event in [&from_bob, &from_alice, &unrelated, &from_carol] {
+        store.insert_event(event).await.unwrap();
+    }
+
+    let namespace = |keys: Option<Vec<String>>, min_priority, order, limit| async move {
+        store
+            .events_by_namespace("shared", keys, min_priority, None, order, limit)
+            .await
+            .unwrap()
+            .iter()
+            .map(|group| signatures(group))
+            .collect::<Vec<_>>()
+    };
+    let by_priority =
+        [&from_alice, &from_carol, &from_bob].map(|event| signatures(std::slice::from_ref(event)));
+    assert_eq!(
+        namespace(None, None, NamespaceOrder::Priority, None).await,
+        by_priority
+    );
+    assert_eq!(
+        namespace(None, None, NamespaceOrder::Recency, None).await,
+        [&from_carol, &from_alice, &from_bob].map(|event| signatures(std::slice::from_ref(event)))
+    );
+    assert_eq!(
+        namespace(None, Some(3), NamespaceOrder::Priority, None).await,
+        by_priority[..2]
+    );
+    assert_eq!(
+        namespace(None, None, NamespaceOrder::Priority, Some(1)).await,
+        by_priority[..1]
+    );
+    let keys = Some(vec![key_of(&bob), key_of(&carol)]);
+    assert_eq!(
+        namespace(keys.clone(), None, NamespaceOrder::Priority, None).await,
+        by_priority[1..]
+    );
+
+    assert_eq!(
+        store
+            .namespace_count("shared", None, None, None)
+            .await
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        store
+            .namespace_count("shared", None, Some(3), None)
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        store
+            .namespace_count("shared", keys, Some(3), None)
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(
+        store
+            .events_by_namespace("missing", None, None, None, NamespaceOrder::Priority, None)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(
+        store
+            .namespace_count("missing", None, None, None)
+            .await
+            .unwrap(),
+        0
+    );
+}
+
+pub async fn keeps_namespace_groups_in_received_order(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let outliving = set_event(&mut alice, "shared", 1, Some(200));
+    let higher = set_event(&mut alice, "shared", 2, Some(100));
+    for event in [&outliving, &higher] {
+        store.insert_event(event).await.unwrap();
+    }
+
+    let groups = store
+        .events_by_namespace("shared", None, None, None, NamespaceOrder::Priority, None)
+        .await
+        .unwrap();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(signatures(&groups[0]), signatures(&[outliving, higher]));
+    assert_eq!(
+        store
+            .namespace_count("shared", None, None, None)
+            .await
+            .unwrap(),
+        1
+    );
+}
+
+pub async fn filters_namespaces_by_received_time(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let before = unix_timestamp();
+    let event = set_event(&mut alice, "shared", 1, None);
+    store.insert_event(&event).await.unwrap();
+    let after = unix_timestamp() + 1;
+
+    let namespace = |since| async move {
+        store
+            .events_by_namespace("shared", None, None, since, NamespaceOrder::Priority, None)
+            .await
+            .unwrap()
+            .iter()
+            .map(|group| signatures(group))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(namespace(Some(before)).await, vec![signatures(&[event])]);
+    assert!(namespace(Some(after)).await.is_empty());
+    assert_eq!(
+        store
+            .namespace_count("shared", None, None, Some(before))
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        store
+            .namespace_count("shared", None, None, Some(after))
+            .await
+            .unwrap(),
+        0
+    );
+}
+
+pub async fn state_hash_ignores_insertion_order(store: &dyn EventStore, other: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let events = (0..4)
+        .map(|i| set_event(&mut alice, &format!("name{i}"), 1, None))
+        .collect::<Vec<_>>();
+    let empty_hash = store.current_state_hash().await.unwrap();
+    for event in &events {
+        store.insert_event(event).await.unwrap();
+    }
+    for event in events.iter().rev() {
+        other.insert_event(event).await.unwrap();
+    }
+
+    let hash = store.current_state_hash().await.unwrap();
+    assert_ne!(hash, empty_hash);
+    assert_eq!(hash, other.current_state_hash().await.unwrap());
+    assert_eq!(
+        signatures(&store.signed_events().await.unwrap()),
+        signatures(&other.signed_events().await.unwrap())
+    );
+}
+
+pub async fn pages_through_events(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    for i in 0..5 {
+        let event = set_event(&mut alice, &format!("name{i}"), 1, None);
+        store.insert_event(&event).await.unwrap();
+    }
+
+    let mut paged = Vec::new();
+    let mut after = None;
+    loop {
+        let page = store.signed_events_page(after, 2).await.unwrap();
+        assert!(page.len() <= 2);
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.to_bytes());
+        paged.extend(page);
+    }
+    assert_eq!(
+        signatures(&paged),
+        signatures(&store.signed_events().await.unwrap())
+    );
+}
+
+pub async fn remembers_peer_hashes(store: &dyn EventStore) {
+    let peer = "http://peer.example:3000/";
+    assert_eq!(store.get_peer_last_hash(peer).await, None);
+
+    for contents in [b"first", b"later"] {
+        let hash = StateHash {
+            hash: blake3::hash(contents),
+        };
+        store.set_peer_last_hash(peer, hash).await.unwrap();
+        let expected = StateHash {
+            hash: blake3::hash(contents),
+        };
+        assert_eq!(store.get_peer_last_hash(peer).await, Some(expected));
+    }
+    assert_eq!(
+        store.get_peer_last_hash("http://other.example/").await,
+        None
+    );
+}
+
+pub async fn queues_events_in_order(store: &dyn EventStore) {
+    let mut alice = CryptoKey::generate();
+    let first = set_event(&mut alice, "first", 1, None);
+    let second = set_event(&mut alice, "second", 1, None);
+    for event in [&first, &second, &first] {
+        store.enqueue_event(event).await.unwrap();
+    }
+
+    let queued = store.queued_events().await.unwrap();
+    assert_eq!(queued.len(), 2);
+    assert!(queued[0].0 < queued[1].0);
+    let queued_events = queued
+        .iter()
+        .map(|(_, event)| event.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        signatures(&queued_events),
+        signatures(&[first, second.clone()])
+    );
+
+    store.dequeue_event(queued[0].0).await.unwrap();
+    let remaining = store.queued_events().await.unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(
+        signatures(&[remaining[0].1.clone()]),
+        signatures(std::slice::from_ref(&second))
+    );
+    // Events stay out of the current events until inserted separately
+    assert_eq!(store.event_count().await.unwrap(), 0);
+
+    // Discarding a refused event also drops the copy applied locally
+    store
+        .insert_current_events(std::slice::from_ref(&second))
+        .await
+        .unwrap();
+    store.discard_queued_event(remaining[0].0).await.unwrap();
+    assert!(store.queued_events().await.unwrap().is_empty());
+    assert_eq!(store.event_count().await.unwrap(), 0);
+}
+
+pub async fn remembers_sync_times(store: &dyn EventStore) {
+    let alice = encode_verifying_key(&CryptoKey::generate().verifying());
+    assert_eq!(store.get_synced_at(alice.clone()).await.unwrap(), None);
+    store.set_synced_at(alice.clone(), 5).await.unwrap();
+    store.set_synced_at(alice.clone(), 7).await.unwrap();
+    assert_eq!(store.get_synced_at(alice).await.unwrap(), Some(7));
+}
+
+fn set_event(
+    key: &mut CryptoKey,
+    name: &str,
+    priority: u64,
+    expires_at: Option<u64>,
+) -> Signed<Event> {
+    key.sign(Event::Set(SetEvent::plain(
+        Name::new(name.to_string()),
+        Value::new(format!("{name} at {priority}").into_bytes()),
+        priority,
+        expires_at,
+    )))
+}
+
+fn key_of(key: &CryptoKey) -> String {
+    encode_verifying_key(&key.verifying())
+}
+
+fn signatures(events: &[Signed<Event>]) -> Vec<[u8; 64]> {
+    events
+        .iter()
+        .map(|event| event.signature().unwrap().to_bytes())
+        .collect()
+}
+
+/// Signatures for results in no particular order
+fn unordered(events: &[Signed<Event>]) -> Vec<[u8; 64]> {
+    let mut signatures = signatures(events);
+    signatures.sort();
+    signatures
+}
@@ -1,26 +1,29 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use tokio::sync::Mutex;
+use ed25519_dalek::VerifyingKey;
 
-use crate::{api::StateHash, client::Event, crypto::Signed};
+use crate::{
+    api::{NamespaceOrder, StateHash},
+    client::{Event, SetEvent, TrustPolicy, endorsement_name},
+    crypto::{Signed, encode::encode_verifying_key},
+};
 
-use super::sqlite_store::SqliteStore;
+use super::event_store::EventStore;
 
 #[derive(Clone)]
 pub struct DataController {
-    store: Arc<Mutex<SqliteStore>>,
+    store: Arc<dyn EventStore>,
 }
 
 impl DataController {
-    pub fn new(store: SqliteStore) -> Self {
+    pub fn new(store: impl EventStore + 'static) -> Self {
         Self {
-            store: Arc::new(Mutex::new(store)),
+            store: Arc::new(store),
         }
     }
 
     pub async fn get_peer_last_hash(&self, url: &str) -> Option<StateHash> {
-        let store_guard = self.store.lock().await;
-        store_guard.get_peer_last_hash(url).await
+        self.store.get_peer_last_hash(url).await
     }
 
     pub async fn set_peer_last_hash(
@@ -28,46 +31,90 @@ impl DataController {
         url: &str,
         events_hash: StateHash,
     ) -> anyhow::Result<()> {
-        let store_guard = self.store.lock().await;
-        store_guard.set_peer_last_hash(url, events_hash).await
+        self.store.set_peer_last_hash(url, events_hash).await
     }
 
     pub async fn current_state_hash(&self) -> anyhow::Result<StateHash> {
-        let store_guard = self.store.lock().await;
-        store_guard.current_state_hash().await
+        self.store.current_state_hash().await
     }
 
     pub async fn delete_expired_events(&self, unix_timestamp: u64) -> anyhow::Result<usize> {
-        let store_guard = self.store.lock().await;
-        store_guard.delete_expired_events(unix_timestamp).await
+        self.store.delete_expired_events(unix_timestamp).await
     }
 
+    pub async fn delete_old_history(&self, unix_timestamp: u64) -> anyhow::Result<usize> {
+        self.store.delete_old_history(unix_timestamp).await
+    }
+
+    /// Fails with `WriteConflict` if the write expects a previous event that is no longer the
+    /// latest
     pub async fn insert_event(&self, event: Signed<Event>) -> anyhow::Result<usize> {
-        let store_guard = self.store.lock().await;
-        if store_guard.is_stale_event(&event).await? {
-            return Ok(0);
-        }
+        let expected_previous = match &event.inner {
+            Event::Set(SetEvent {
+                expected_previous, ..
+            }) => *expected_previous,
+            _ => None,
+        };
+        self.store
+            .insert_current_event(&event, expected_previous)
+            .await
+    }
 
-        let inserted_count = store_guard.insert_event(&event).await?;
-        store_guard.delete_stale_events(&event).await?;
-        Ok(inserted_count)
+    /// Inserts replicated events, which were already accepted by the node that received them
+    pub async fn insert_events(&self, events: Vec<Signed<Event>>) -> anyhow::Result<usize> {
+        self.store.insert_current_events(&events).await
     }
 
-    pub async fn insert_events(&self, events: Vec<Signed<Event>>) -> anyhow::Result<()> {
-        for event in events {
-            self.insert_event(event).await?;
-        }
-        Ok(())
+    pub async fn enqueue_event(&self, event: &Signed<Event>) -> anyhow::Result<()> {
+        self.store.enqueue_event(event).await
+    }
+
+    pub async fn queued_events(&self) -> anyhow::Result<Vec<(i64, Signed<Event>)>> {
+        self.store.queued_events().await
+    }
+
+    pub async fn dequeue_event(&self, id: i64) -> anyhow::Result<()> {
+        self.store.dequeue_event(id).await
+    }
+
+    pub async fn discard_queued_event(&self, id: i64) -> anyhow::Result<()> {
+        self.store.discard_queued_event(id).await
+    }
+
+    pub async fn set_synced_at(&self, verifying_key: String, synced_at: u64) -> anyhow::Result<()> {
+        self.store.set_synced_at(verifying_key, synced_at).await
+    }
+
+    pub async fn get_synced_at(&self, verifying_key: String) -> anyhow::Result<Option<u64>> {
+        self.store.get_synced_at(verifying_key).await
     }
 
     pub async fn event_count(&self) -> anyhow::Result<usize> {
-        let store_guard = self.store.lock().await;
-        store_guard.event_count().await
+        self.store.event_count().await
+    }
+
+    pub async fn event_counts_by_kind(&self) -> anyhow::Result<HashMap<&'static str, usize>> {
+        self.store.event_counts_by_kind().await
+    }
+
+    pub async fn size_bytes(&self) -> anyhow::Result<u64> {
+        self.store.size_bytes().await
     }
 
     pub async fn signed_events(&self) -> anyhow::Result<Vec<Signed<Event>>> {
-        let store_guard = self.store.lock().await;
-        store_guard.signed_events().await
+        self.store.signed_events().await
+    }
+
+    pub async fn signed_events_page(
+        &self,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Signed<Event>>> {
+        self.store.signed_events_page(after, limit).await
+    }
+
+    pub async fn events_by_key(&self, verifying_key: String) -> anyhow::Result<Vec<Signed<Event>>> {
+        self.store.events_by_key(verifying_key).await
     }
 
     pub async fn events_by_key_and_name(
@@ -75,14 +122,73 @@ impl DataController {
         verifying_key: String,
         name: String,
     ) -> anyhow::Result<Vec<Signed<Event>>> {
-        let store_guard = self.store.lock().await;
-        store_guard
-            .events_by_key_and_name(verifying_key, name)
+        self.store.events_by_key_and_name(verifying_key, name).await
+    }
+
+    pub async fn history_by_key_and_name(
+        &self,
+        verifying_key: String,
+        name: String,
+    ) -> anyhow::Result<Vec<Signed<Event>>> {
+        self.store
+            .history_by_key_and_name(verifying_key, name)
+            .await
+    }
+
+    pub async fn admits(
+        &self,
+        policy: &TrustPolicy,
+        verifying_key: &VerifyingKey,
+    ) -> anyhow::Result<bool> {
+        if !policy.needs_endorsements(verifying_key) {
+            return Ok(policy.admits_outright(verifying_key));
+        }
+        let name = endorsement_name(verifying_key);
+        let mut endorsements = Vec::new();
+        for endorser in &policy.trusted {
+            endorsements.extend(
+                self.store
+                    .events_by_key_and_name(encode_verifying_key(endorser), name.to_string())
+                    .await?,
+            );
+        }
+        Ok(policy.admits(verifying_key, &endorsements))
+    }
+
+    pub async fn events_by_namespace(
+        &self,
+        namespace: &str,
+        keys: Option<&[VerifyingKey]>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+        order: NamespaceOrder,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Vec<Signed<Event>>>> {
+        self.store
+            .events_by_namespace(
+                namespace,
+                encode_keys(keys),
+                min_priority,
+                since,
+                order,
+                limit,
+            )
             .await
     }
 
-    pub async fn events_by_namespace(&self, namespace: &str) -> anyhow::Result<Vec<Signed<Event>>> {
-        let store_guard = self.store.lock().await;
-        store_guard.events_by_namespace(namespace).await
+    pub async fn namespace_count(
+        &self,
+        namespace: &str,
+        keys: Option<&[VerifyingKey]>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+    ) -> anyhow::Result<usize> {
+        self.store
+            .namespace_count(namespace, encode_keys(keys), min_priority, since)
+            .await
     }
 }
+
+fn encode_keys(keys: Option<&[VerifyingKey]>) -> Option<Vec<String>> {
+    keys.map(|keys| keys.iter().map(encode_verifying_key).collect())
+}
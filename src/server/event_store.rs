@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bincode::config::standard;
+
+use crate::{
+    api::{NamespaceOrder, StateHash},
+    client::Event,
+    crypto::Signed,
+};
+
+/// Keyspaces are identified by their encoded verifying keys. Each call must be atomic on its own
+/// and safe to make concurrently with any other.
+#[async_trait]
+pub trait EventStore: Send + Sync {
+    /// Stores an event, returning 0 if the same signed event is already stored
+    async fn insert_event(&self, signed_event: &Signed<Event>) -> Result<usize>;
+
+    /// Whether a stored event for the same address outranks the event and outlives it
+    async fn is_stale_event(&self, event: &Signed<Event>) -> Result<bool>;
+
+    /// Removes events for the same address with a lower priority, unless they outlive the
+    /// event, moving them to the history if it is retained
+    async fn delete_stale_events(&self, event: &Signed<Event>) -> Result<usize>;
+
+    /// Inserts an event unless it is stale, then removes the events it supersedes, all as one
+    /// atomic write. With `expected_previous`, fails with `WriteConflict` unless the latest
+    /// event for the address has that hash, or there is none and it is `NO_PREVIOUS_EVENT`.
+    async fn insert_current_event(
+        &self,
+        event: &Signed<Event>,
+        expected_previous: Option<[u8; 32]>,
+    ) -> Result<usize>;
+
+    /// Inserts events like `insert_current_event` without expected previous events, all in one
+    /// atomic write, returning how many were inserted
+    async fn insert_current_events(&self, events: &[Signed<Event>]) -> Result<usize>;
+
+    /// Removes expired superseded events too, but only counts the current ones
+    async fn delete_expired_events(&self, unix_timestamp: u64) -> Result<usize>;
+
+    async fn delete_old_history(&self, unix_timestamp: u64) -> Result<usize>;
+
+    async fn event_count(&self) -> Result<usize>;
+
+    /// Number of current events of each kind, told apart by `Event::encoded_kind`
+    async fn event_counts_by_kind(&self) -> Result<HashMap<&'static str, usize>>;
+
+    /// Bytes taken up by the stored events and history
+    async fn size_bytes(&self) -> Result<u64>;
+
+    /// All current events, ordered by their encoding so that nodes holding the same events
+    /// agree on the state hash
+    async fn signed_events(&self) -> Result<Vec<Signed<Event>>>;
+
+    /// Up to `limit` current events in the order of `signed_events`, starting after the event
+    /// encoded as `after`
+    async fn signed_events_page(
+        &self,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<Signed<Event>>>;
+
+    async fn current_state_hash(&self) -> Result<StateHash> {
+        let all_signed_events = self.signed_events().await?;
+        let serialized_events = bincode::encode_to_vec(&all_signed_events, standard())?;
+        let hash = blake3::hash(&serialized_events);
+        Ok(StateHash { hash })
+    }
+
+    async fn events_by_key(&self, verifying_key: String) -> Result<Vec<Signed<Event>>>;
+
+    async fn events_by_key_and_name(
+        &self,
+        verifying_key: String,
+        name: String,
+    ) -> Result<Vec<Signed<Event>>>;
+
+    /// Current and retained superseded events for an address, oldest priority first
+    async fn history_by_key_and_name(
+        &self,
+        verifying_key: String,
+        name: String,
+    ) -> Result<Vec<Signed<Event>>>;
+
+    /// `since` is compared with when this node received the events. Groups are sorted by
+    /// `order`, ties broken by key, and each holds its events in the order they were received.
+    async fn events_by_namespace(
+        &self,
+        name: &str,
+        keys: Option<Vec<String>>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+        order: NamespaceOrder,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<Signed<Event>>>>;
+
+    async fn namespace_count(
+        &self,
+        name: &str,
+        keys: Option<Vec<String>>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+    ) -> Result<usize>;
+
+    async fn set_peer_last_hash(&self, peer_url: &str, hash: StateHash) -> Result<()>;
+
+    async fn get_peer_last_hash(&self, peer_url: &str) -> Option<StateHash>;
+
+    /// Queues an event a client could not send, ignoring one already queued
+    async fn enqueue_event(&self, signed_event: &Signed<Event>) -> Result<()>;
+
+    async fn queued_events(&self) -> Result<Vec<(i64, Signed<Event>)>>;
+
+    async fn dequeue_event(&self, id: i64) -> Result<()>;
+
+    /// Dequeues an event that was refused, also removing it from the current events
+    async fn discard_queued_event(&self, id: i64) -> Result<()>;
+
+    async fn set_synced_at(&self, verifying_key: String, synced_at: u64) -> Result<()>;
+
+    async fn get_synced_at(&self, verifying_key: String) -> Result<Option<u64>>;
+}
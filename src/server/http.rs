@@ -1,28 +1,29 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use axum::{
-    Json,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{MatchedPath, Path, Query, Request, State},
+    http::{StatusCode, header::CONTENT_TYPE},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::post,
 };
-use tokio::time::{Duration, sleep};
-use tower_http::services::ServeDir;
-use tracing::info;
+use tokio::time::{Duration, Instant, sleep};
+use tower_http::{compression::CompressionLayer, services::ServeDir};
+use tracing::{error, info};
 
 use crate::{
-    api::SyncEvents,
+    api::{NamespaceQuery, SNAPSHOT_CONTENT_TYPE, StateHash, SyncEvents, WriteConflict},
     client::{Event, RelevantEvents},
     configuration::Configuration,
-    connectors::{
-        connection::Connection,
-        http::{HttpConnection, NamespaceResponse},
-    },
+    connectors::{connection::Connection, http::HttpConnection},
     crypto::{Signed, encode::decode_verifying_key},
     models::{ContentBlock, Peers},
     server::{
-        data_controller::DataController, immutable_controller::ImmutableController,
-        sqlite_store::SqliteStore, task_controller::TaskController,
+        metrics::METRICS_CONTENT_TYPE,
+        negotiate::{Accept, Encoded, Negotiated},
+        node::Node,
+        task_controller::TaskController,
     },
 };
 
@@ -30,33 +31,37 @@ use super::templates;
 
 #[derive(Clone)]
 pub struct AppState {
-    immutable_controller: ImmutableController,
-    controller: DataController,
+    node: Node,
     peers: Vec<url::Url>,
+    peer_connections: Arc<Vec<Connection>>,
 }
 
 pub async fn start_http_server(config: &Configuration, peers: Vec<url::Url>) -> Result<()> {
     use axum::{Router, routing::get};
     use tokio::net::TcpListener;
 
-    let database_path = config.server_database_path();
-    info!("Using database at {}", database_path.display());
-    let store = SqliteStore::new(&database_path)?;
-    let controller = DataController::new(store);
-    let immutable_controller = ImmutableController::new(config.immutable_store_path()).await;
-    let peer_connections = peers
-        .iter()
-        .map(|peer| Connection::Http(HttpConnection::new(peer.clone())))
-        .collect();
+    info!(
+        "Using database at {}",
+        config.server_database_path().display()
+    );
+    let node = Node::open(config).await?;
+    let peer_connections: Arc<Vec<_>> = Arc::new(
+        peers
+            .iter()
+            .map(|peer| Connection::Http(HttpConnection::new(peer.clone())))
+            .collect(),
+    );
 
     let task_controller = TaskController::builder()
-        .controller(controller.clone())
-        .peer_connections(peer_connections)
+        .controller(node.controller().clone())
+        .peer_connections(peer_connections.clone())
+        .maybe_min_stamp_work(config.min_stamp_work())
+        .metrics(node.metrics().clone())
         .build();
     let state = AppState {
-        immutable_controller,
-        controller,
+        node,
         peers,
+        peer_connections,
     };
 
     tokio::spawn(async move {
@@ -69,18 +74,43 @@ pub async fn start_http_server(config: &Configuration, peers: Vec<url::Url>) ->
     let app = Router::new()
         .route("/", get(dashboard))
         .route("/info", get(info))
-        .route("/keyspace/:verifying_key", post(set_event))
+        .route("/policy", get(policy))
+        .route(
+            "/keyspace/:verifying_key",
+            get(get_keyspace).post(set_event),
+        )
         .route("/keyspace/:verifying_key/:address_key", get(get_name))
-        .route("/namespace/:address_key", get(get_namespace))
+        .route(
+            "/audit/keyspace/:verifying_key/:address_key",
+            get(get_history),
+        )
+        .route(
+            "/namespace/:address_key",
+            get(get_namespace).layer(CompressionLayer::new()),
+        )
         .route("/sync/peers", get(sync_peers))
         .route("/sync/state", get(sync_state))
-        .route("/sync/events", get(sync_events))
+        .route(
+            "/sync/events",
+            get(sync_events).layer(CompressionLayer::new()),
+        )
+        .route("/sync/snapshot/:state_hash", get(sync_snapshot))
         .route("/immutable/:hash", get(get_immutable))
         .route("/immutable", post(post_immutable))
-        .nest_service("/dist", ServeDir::new(option_env!("BAYBRIDGE_DIST_PATH").unwrap_or("dist")))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_requests,
+        ))
+        .nest_service(
+            "/dist",
+            ServeDir::new(option_env!("BAYBRIDGE_DIST_PATH").unwrap_or("dist")),
+        )
         .nest_service(
             "/dist/chartjs",
-            ServeDir::new(option_env!("BAYBRIDGE_CHARTJS_DIST_PATH").unwrap_or("node_modules/chart.js/dist")),
+            ServeDir::new(
+                option_env!("BAYBRIDGE_CHARTJS_DIST_PATH").unwrap_or("node_modules/chart.js/dist"),
+            ),
         )
         .with_state(state);
 
@@ -96,7 +126,8 @@ async fn dashboard(State(state): State<AppState>) -> impl IntoResponse {
         .unwrap_or("unknown")
         .to_string();
     let state_hash = state
-        .controller
+        .node
+        .controller()
         .current_state_hash()
         .await
         .unwrap()
@@ -105,7 +136,7 @@ async fn dashboard(State(state): State<AppState>) -> impl IntoResponse {
         .chars()
         .take(12)
         .collect();
-    let event_count = state.controller.event_count().await.unwrap();
+    let event_count = state.node.controller().event_count().await.unwrap();
     let peer_count = state.peers.len();
     templates::Dashboard {
         state_hash,
@@ -117,8 +148,8 @@ async fn dashboard(State(state): State<AppState>) -> impl IntoResponse {
 
 async fn info(State(state): State<AppState>) -> impl IntoResponse {
     let version = crate::built_info::GIT_VERSION.unwrap_or("unknown");
-    let current_state = state.controller.current_state_hash().await.unwrap();
-    let key_count: usize = state.controller.event_count().await.unwrap();
+    let current_state = state.node.controller().current_state_hash().await.unwrap();
+    let key_count: usize = state.node.controller().event_count().await.unwrap();
     (
         StatusCode::OK,
         format!(
@@ -128,86 +159,215 @@ async fn info(State(state): State<AppState>) -> impl IntoResponse {
     )
 }
 
-async fn sync_state(State(state): State<AppState>) -> impl IntoResponse {
-    let hash = state.controller.current_state_hash().await.unwrap();
-    Json(hash)
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    match state
+        .node
+        .metrics()
+        .render(&state.node, &state.peer_connections)
+        .await
+    {
+        Ok(metrics) => ([(CONTENT_TYPE, METRICS_CONTENT_TYPE)], metrics).into_response(),
+        Err(e) => {
+            error!("Failed to render metrics: {e:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to render metrics",
+            )
+                .into_response()
+        }
+    }
 }
 
-async fn sync_peers(State(state): State<AppState>) -> impl IntoResponse {
+/// Records the latency of each request by the route it matched
+async fn track_requests(
+    State(state): State<AppState>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path.as_str().to_string();
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state.node.metrics().record_request(
+        route,
+        method,
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+    response
+}
+
+async fn policy(Accept(format): Accept, State(state): State<AppState>) -> impl IntoResponse {
+    Encoded(format, state.node.policy())
+}
+
+async fn sync_state(Accept(format): Accept, State(state): State<AppState>) -> impl IntoResponse {
+    let hash = state.node.controller().current_state_hash().await.unwrap();
+    Encoded(format, hash)
+}
+
+async fn sync_peers(Accept(format): Accept, State(state): State<AppState>) -> impl IntoResponse {
     let peers = Peers {
         peers: state.peers.iter().map(|peer| peer.to_string()).collect(),
     };
-    Json(peers)
+    Encoded(format, peers)
+}
+
+async fn sync_events(Accept(format): Accept, State(state): State<AppState>) -> impl IntoResponse {
+    let events = state.node.controller().signed_events().await.unwrap();
+    Encoded(format, SyncEvents { events })
+}
+
+async fn sync_snapshot(Path(state_hash): Path<String>, State(state): State<AppState>) -> Response {
+    let Ok(hash) = blake3::Hash::from_hex(&state_hash) else {
+        return (StatusCode::BAD_REQUEST, "Invalid state hash").into_response();
+    };
+    let snapshot = state
+        .node
+        .snapshot(&StateHash { hash })
+        .await
+        .and_then(|snapshot| snapshot.map(|snapshot| snapshot.encode()).transpose());
+    match snapshot {
+        Ok(Some(encoded)) => ([(CONTENT_TYPE, SNAPSHOT_CONTENT_TYPE)], encoded).into_response(),
+        Ok(None) => (StatusCode::CONFLICT, "State has changed").into_response(),
+        Err(e) => {
+            error!("Failed to build snapshot: {e:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to build snapshot",
+            )
+                .into_response()
+        }
+    }
 }
 
-async fn sync_events(State(state): State<AppState>) -> impl IntoResponse {
-    let events = state.controller.signed_events().await.unwrap();
-    Json(SyncEvents { events })
+async fn get_keyspace(
+    Path(verifying_key_string): Path<String>,
+    Accept(format): Accept,
+    State(state): State<AppState>,
+) -> Response {
+    match state
+        .node
+        .controller()
+        .events_by_key(verifying_key_string)
+        .await
+    {
+        Ok(events) => (StatusCode::OK, Encoded(format, RelevantEvents { events })).into_response(),
+        Err(e) => {
+            error!("Failed to read keyspace: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read keyspace").into_response()
+        }
+    }
 }
 
 async fn get_name(
     Path((verifying_key_string, name_string)): Path<(String, String)>,
+    Accept(format): Accept,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    let events = state
-        .controller
+) -> Response {
+    match state
+        .node
+        .controller()
         .events_by_key_and_name(verifying_key_string, name_string)
         .await
-        .unwrap();
-    (StatusCode::OK, Json(RelevantEvents { events }))
+    {
+        Ok(events) => (StatusCode::OK, Encoded(format, RelevantEvents { events })).into_response(),
+        Err(e) => {
+            error!("Failed to read name: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read name").into_response()
+        }
+    }
+}
+
+async fn get_history(
+    Path((verifying_key_string, name_string)): Path<(String, String)>,
+    Accept(format): Accept,
+    State(state): State<AppState>,
+) -> Response {
+    match state
+        .node
+        .controller()
+        .history_by_key_and_name(verifying_key_string, name_string)
+        .await
+    {
+        Ok(events) => (StatusCode::OK, Encoded(format, RelevantEvents { events })).into_response(),
+        Err(e) => {
+            error!("Failed to read history: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read history").into_response()
+        }
+    }
 }
 
 async fn get_namespace(
     Path(name_string): Path<String>,
+    Query(query): Query<NamespaceQuery>,
+    Accept(format): Accept,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    let events = state
-        .controller
-        .events_by_namespace(&name_string)
+) -> Response {
+    let keys = match query.verifying_keys() {
+        Ok(keys) => keys,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    match state
+        .node
+        .namespace(name_string, keys.as_deref(), &query)
         .await
-        .unwrap();
-    (
-        StatusCode::OK,
-        Json(NamespaceResponse {
-            namespace: name_string,
-            events,
-        }),
-    )
+    {
+        Ok(response) => (StatusCode::OK, Encoded(format, response)).into_response(),
+        Err(e) => {
+            error!("Failed to read namespace: {e:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read namespace",
+            )
+                .into_response()
+        }
+    }
 }
 
 async fn set_event(
     Path(verifying_key_string): Path<String>,
     State(state): State<AppState>,
-    Json(event): Json<Signed<Event>>,
+    Negotiated(event): Negotiated<Signed<Event>>,
 ) -> impl IntoResponse {
-    let verifying_key = decode_verifying_key(&verifying_key_string).unwrap();
-    let verified = event.verify(&verifying_key);
-    if !verified {
-        // Return 403 Forbidden
-        return (StatusCode::FORBIDDEN, "Forbidden");
+    let Ok(verifying_key) = decode_verifying_key(&verifying_key_string) else {
+        return (StatusCode::BAD_REQUEST, "Invalid verifying key");
+    };
+    if let Err(reason) = state.node.check_event(&verifying_key, &event) {
+        return (StatusCode::FORBIDDEN, reason);
     }
 
-    state.controller.insert_event(event).await.unwrap();
-
-    (StatusCode::OK, "OK")
+    match state.node.insert_event(event).await {
+        Ok(_) => (StatusCode::OK, "OK"),
+        Err(e) if e.is::<WriteConflict>() => (StatusCode::CONFLICT, "Conflict"),
+        Err(e) => {
+            error!("Failed to insert event: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to insert event")
+        }
+    }
 }
 
 async fn get_immutable(
     Path(hash): Path<String>,
+    Accept(format): Accept,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let hash = blake3::Hash::from_hex(&hash).unwrap();
-    let data = state.immutable_controller.get(&hash).await;
+    let Ok(hash) = blake3::Hash::from_hex(&hash) else {
+        return (StatusCode::BAD_REQUEST, "Invalid hash").into_response();
+    };
+    let data = state.node.immutable_controller().get(&hash).await;
     match data {
-        Some(data) => (StatusCode::OK, Json(data)).into_response(),
+        Some(data) => (StatusCode::OK, Encoded(format, data)).into_response(),
         None => (StatusCode::NOT_FOUND, "404 not found").into_response(),
     }
 }
 
 async fn post_immutable(
+    Accept(format): Accept,
     State(state): State<AppState>,
-    Json(body): Json<ContentBlock>,
+    Negotiated(body): Negotiated<ContentBlock>,
 ) -> impl IntoResponse {
-    let hash = state.immutable_controller.set(&body).await;
-    Json(hash)
+    let hash = state.node.immutable_controller().set(&body).await;
+    Encoded(format, hash)
 }
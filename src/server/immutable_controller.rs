@@ -1,41 +1,111 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
+use anyhow::Result;
 use bincode::config::standard;
 use tokio::sync::RwLock;
 
-use crate::models::ContentBlock;
+use crate::{api::MAX_BODY_SIZE, models::ContentBlock};
+
+#[derive(Debug, Clone)]
+enum Storage {
+    Directory(PathBuf),
+    Memory(HashMap<blake3::Hash, Vec<u8>>),
+}
 
 #[derive(Debug, Clone)]
 pub struct ImmutableController {
-    basedir: PathBuf,
-    filesystem_lock: Arc<RwLock<()>>,
+    storage: Arc<RwLock<Storage>>,
 }
 
 impl ImmutableController {
     pub async fn new(basedir: PathBuf) -> Self {
         tokio::fs::create_dir_all(&basedir).await.unwrap();
         Self {
-            basedir,
-            filesystem_lock: Arc::new(RwLock::new(())),
+            storage: Arc::new(RwLock::new(Storage::Directory(basedir))),
+        }
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            storage: Arc::new(RwLock::new(Storage::Memory(HashMap::new()))),
         }
     }
 
     pub async fn get(&self, hash: &blake3::Hash) -> Option<ContentBlock> {
-        let _guard = self.filesystem_lock.read().await;
-        let path = self.basedir.join(hash.to_string());
-        let encoded = tokio::fs::read(&path).await.ok()?;
-        bincode::decode_from_slice(&encoded, standard())
+        let encoded = self.get_encoded(hash).await?;
+        // Blocks are posted as request bodies, so they decode within the same limit
+        bincode::decode_from_slice(&encoded, standard().with_limit::<MAX_BODY_SIZE>())
             .ok()
             .map(|v| v.0)
     }
 
+    pub async fn get_encoded(&self, hash: &blake3::Hash) -> Option<Vec<u8>> {
+        let storage = self.storage.read().await;
+        match &*storage {
+            Storage::Directory(basedir) => {
+                let path = basedir.join(hash.to_string());
+                tokio::fs::read(&path).await.ok()
+            }
+            Storage::Memory(blocks) => blocks.get(hash).cloned(),
+        }
+    }
+
+    /// In no particular order
+    pub async fn hashes(&self) -> Result<Vec<blake3::Hash>> {
+        let storage = self.storage.read().await;
+        match &*storage {
+            Storage::Directory(basedir) => {
+                let mut hashes = Vec::new();
+                let mut entries = tokio::fs::read_dir(basedir).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    if let Some(hash) = entry
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| blake3::Hash::from_hex(name).ok())
+                    {
+                        hashes.push(hash);
+                    }
+                }
+                Ok(hashes)
+            }
+            Storage::Memory(blocks) => Ok(blocks.keys().copied().collect()),
+        }
+    }
+
+    /// Number of stored blocks and their total size in bytes
+    pub async fn usage(&self) -> Result<(u64, u64)> {
+        let storage = self.storage.read().await;
+        match &*storage {
+            Storage::Directory(basedir) => {
+                let (mut count, mut bytes) = (0, 0);
+                let mut entries = tokio::fs::read_dir(basedir).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    count += 1;
+                    bytes += entry.metadata().await?.len();
+                }
+                Ok((count, bytes))
+            }
+            Storage::Memory(blocks) => Ok((
+                blocks.len() as u64,
+                blocks.values().map(|block| block.len() as u64).sum(),
+            )),
+        }
+    }
+
     pub async fn set(&self, content: &ContentBlock) -> blake3::Hash {
-        let _guard = self.filesystem_lock.write().await;
+        let mut storage = self.storage.write().await;
         let encoded = bincode::encode_to_vec(content, standard()).unwrap();
         let hash = blake3::hash(&encoded);
-        let path = self.basedir.join(hash.to_string());
-        if !path.exists() {
-            tokio::fs::write(&path, &encoded).await.unwrap();
+        match &mut *storage {
+            Storage::Directory(basedir) => {
+                let path = basedir.join(hash.to_string());
+                if !path.exists() {
+                    tokio::fs::write(&path, &encoded).await.unwrap();
+                }
+            }
+            Storage::Memory(blocks) => {
+                blocks.entry(hash).or_insert(encoded);
+            }
         }
         hash
     }
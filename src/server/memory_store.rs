@@ -0,0 +1,453 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use itertools::Itertools;
+use tokio::sync::Mutex;
+
+use crate::{
+    api::{NamespaceOrder, StateHash, WriteConflict},
+    client::{Event, NO_PREVIOUS_EVENT},
+    crdt::latest_event,
+    crypto::{Signed, encode::encode_verifying_key},
+    time::unix_timestamp,
+};
+
+use super::event_store::EventStore;
+
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+    history_retention: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Tables {
+    // In the order they were received
+    events: Vec<StoredEvent>,
+    history: Vec<(StoredEvent, u64)>,
+    peers: HashMap<String, StateHash>,
+    outbox: BTreeMap<i64, StoredEvent>,
+    next_outbox_id: i64,
+    synced: HashMap<String, u64>,
+}
+
+#[derive(Clone)]
+struct StoredEvent {
+    verifying_key: String,
+    name: String,
+    encoded: Vec<u8>,
+    signed_event: Signed<Event>,
+    received_at: u64,
+}
+
+impl StoredEvent {
+    fn new(signed_event: &Signed<Event>) -> Result<StoredEvent> {
+        Ok(StoredEvent {
+            verifying_key: encode_verifying_key(
+                &signed_event
+                    .keyspace()
+                    .ok_or_else(|| anyhow!("Event is signed with an invalid key"))?,
+            ),
+            name: signed_event.inner.name().to_string(),
+            encoded: signed_event.to_bytes(),
+            signed_event: signed_event.clone(),
+            received_at: unix_timestamp(),
+        })
+    }
+
+    fn rank(&self) -> u64 {
+        self.signed_event.inner.rank()
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        self.signed_event.inner.expires_at()
+    }
+
+    fn has_address(&self, verifying_key: &str, name: &str) -> bool {
+        self.verifying_key == verifying_key && self.name == name
+    }
+}
+
+impl Tables {
+    fn namespace_events<'a>(
+        &'a self,
+        name: &'a str,
+        keys: Option<&'a [String]>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+    ) -> impl Iterator<Item = (usize, &'a StoredEvent)> + 'a {
+        self.events.iter().enumerate().filter(move |(_, event)| {
+            event.name == name
+                && event.rank() >= min_priority.unwrap_or(0)
+                && event.received_at >= since.unwrap_or(0)
+                && keys.is_none_or(|keys| keys.contains(&event.verifying_key))
+        })
+    }
+
+    fn insert(&mut self, stored: StoredEvent) -> usize {
+        if self
+            .events
+            .iter()
+            .any(|event| event.encoded == stored.encoded)
+        {
+            return 0;
+        }
+        self.events.push(stored);
+        1
+    }
+
+    fn is_stale(&self, stored: &StoredEvent) -> bool {
+        // Like SQL comparisons, an unset expiry never compares as outliving another
+        self.events.iter().any(|existing| {
+            existing.has_address(&stored.verifying_key, &stored.name)
+                && existing
+                    .expires_at()
+                    .zip(stored.expires_at())
+                    .is_some_and(|(existing, new)| existing >= new)
+                && existing.rank() >= stored.rank()
+        })
+    }
+
+    fn delete_stale(&mut self, stored: &StoredEvent, history_retention: Option<Duration>) -> usize {
+        let is_stale = |existing: &StoredEvent| {
+            existing.has_address(&stored.verifying_key, &stored.name)
+                && stored
+                    .expires_at()
+                    .is_none_or(|new| existing.expires_at().is_some_and(|existing| existing < new))
+                && existing.rank() < stored.rank()
+        };
+        let (stale, current) = std::mem::take(&mut self.events)
+            .into_iter()
+            .partition::<Vec<_>, _>(is_stale);
+        self.events = current;
+        let num_deleted = stale.len();
+        if history_retention.is_some() {
+            let superseded_at = unix_timestamp();
+            for event in stale {
+                if !self
+                    .history
+                    .iter()
+                    .any(|(retained, _)| retained.encoded == event.encoded)
+                {
+                    self.history.push((event, superseded_at));
+                }
+            }
+        }
+        num_deleted
+    }
+
+    fn events_at(&self, verifying_key: &str, name: &str) -> Vec<Signed<Event>> {
+        self.events
+            .iter()
+            .filter(|event| event.has_address(verifying_key, name))
+            .map(|event| event.signed_event.clone())
+            .collect()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps superseded events in the history for this long instead of deleting them
+    pub fn with_history_retention(mut self, history_retention: Option<Duration>) -> Self {
+        self.history_retention = history_retention;
+        self
+    }
+}
+
+#[async_trait]
+impl EventStore for MemoryStore {
+    async fn insert_event(&self, signed_event: &Signed<Event>) -> Result<usize> {
+        let stored = StoredEvent::new(signed_event)?;
+        Ok(self.tables.lock().await.insert(stored))
+    }
+
+    async fn is_stale_event(&self, event: &Signed<Event>) -> Result<bool> {
+        let stored = StoredEvent::new(event)?;
+        Ok(self.tables.lock().await.is_stale(&stored))
+    }
+
+    async fn delete_stale_events(&self, event: &Signed<Event>) -> Result<usize> {
+        let stored = StoredEvent::new(event)?;
+        let mut tables = self.tables.lock().await;
+        Ok(tables.delete_stale(&stored, self.history_retention))
+    }
+
+    async fn insert_current_event(
+        &self,
+        event: &Signed<Event>,
+        expected_previous: Option<[u8; 32]>,
+    ) -> Result<usize> {
+        let stored = StoredEvent::new(event)?;
+        let mut tables = self.tables.lock().await;
+        if let Some(expected_previous) = expected_previous {
+            let events = tables.events_at(&stored.verifying_key, &stored.name);
+            let previous = latest_event(&events)
+                .map(|previous| previous.inner.hash())
+                .unwrap_or(NO_PREVIOUS_EVENT);
+            if previous != expected_previous {
+                return Err(WriteConflict.into());
+            }
+        }
+        if tables.is_stale(&stored) {
+            return Ok(0);
+        }
+        tables.delete_stale(&stored, self.history_retention);
+        Ok(tables.insert(stored))
+    }
+
+    async fn insert_current_events(&self, events: &[Signed<Event>]) -> Result<usize> {
+        let stored = events
+            .iter()
+            .map(StoredEvent::new)
+            .collect::<Result<Vec<_>>>()?;
+        let mut tables = self.tables.lock().await;
+        let mut num_inserted = 0;
+        for stored in stored {
+            if tables.is_stale(&stored) {
+                continue;
+            }
+            tables.delete_stale(&stored, self.history_retention);
+            num_inserted += tables.insert(stored);
+        }
+        Ok(num_inserted)
+    }
+
+    async fn delete_expired_events(&self, unix_timestamp: u64) -> Result<usize> {
+        let is_expired = |event: &StoredEvent| {
+            event
+                .expires_at()
+                .is_some_and(|expires_at| expires_at <= unix_timestamp)
+        };
+        let mut tables = self.tables.lock().await;
+        let count = tables.events.len();
+        tables.events.retain(|event| !is_expired(event));
+        let num_deleted = count - tables.events.len();
+        tables.history.retain(|(event, _)| !is_expired(event));
+        Ok(num_deleted)
+    }
+
+    async fn delete_old_history(&self, unix_timestamp: u64) -> Result<usize> {
+        let Some(history_retention) = self.history_retention else {
+            return Ok(0);
+        };
+        let cutoff = unix_timestamp.saturating_sub(history_retention.as_secs());
+        let mut tables = self.tables.lock().await;
+        let count = tables.history.len();
+        tables
+            .history
+            .retain(|(_, superseded_at)| *superseded_at > cutoff);
+        Ok(count - tables.history.len())
+    }
+
+    async fn event_count(&self) -> Result<usize> {
+        Ok(self.tables.lock().await.events.len())
+    }
+
+    async fn event_counts_by_kind(&self) -> Result<HashMap<&'static str, usize>> {
+        let tables = self.tables.lock().await;
+        Ok(tables
+            .events
+            .iter()
+            .filter_map(|event| Event::encoded_kind(&event.encoded))
+            .counts())
+    }
+
+    async fn size_bytes(&self) -> Result<u64> {
+        let tables = self.tables.lock().await;
+        let size = tables
+            .events
+            .iter()
+            .chain(tables.history.iter().map(|(event, _)| event))
+            .map(|event| event.encoded.len() as u64)
+            .sum();
+        Ok(size)
+    }
+
+    async fn signed_events(&self) -> Result<Vec<Signed<Event>>> {
+        let tables = self.tables.lock().await;
+        let mut events = tables.events.iter().collect::<Vec<_>>();
+        events.sort_by(|a, b| a.encoded.cmp(&b.encoded));
+        Ok(events
+            .into_iter()
+            .map(|event| event.signed_event.clone())
+            .collect())
+    }
+
+    async fn signed_events_page(
+        &self,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<Signed<Event>>> {
+        let tables = self.tables.lock().await;
+        let mut events = tables
+            .events
+            .iter()
+            .filter(|event| after.as_ref().is_none_or(|after| event.encoded > *after))
+            .collect::<Vec<_>>();
+        events.sort_by(|a, b| a.encoded.cmp(&b.encoded));
+        Ok(events
+            .into_iter()
+            .take(limit)
+            .map(|event| event.signed_event.clone())
+            .collect())
+    }
+
+    async fn events_by_key(&self, verifying_key: String) -> Result<Vec<Signed<Event>>> {
+        let tables = self.tables.lock().await;
+        Ok(tables
+            .events
+            .iter()
+            .filter(|event| event.verifying_key == verifying_key)
+            .map(|event| event.signed_event.clone())
+            .collect())
+    }
+
+    async fn events_by_key_and_name(
+        &self,
+        verifying_key: String,
+        name: String,
+    ) -> Result<Vec<Signed<Event>>> {
+        Ok(self.tables.lock().await.events_at(&verifying_key, &name))
+    }
+
+    async fn history_by_key_and_name(
+        &self,
+        verifying_key: String,
+        name: String,
+    ) -> Result<Vec<Signed<Event>>> {
+        let tables = self.tables.lock().await;
+        let mut events = tables
+            .events
+            .iter()
+            .chain(tables.history.iter().map(|(event, _)| event))
+            .filter(|event| event.has_address(&verifying_key, &name))
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| event.rank());
+        Ok(events
+            .into_iter()
+            .map(|event| event.signed_event.clone())
+            .collect())
+    }
+
+    async fn events_by_namespace(
+        &self,
+        name: &str,
+        keys: Option<Vec<String>>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+        order: NamespaceOrder,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<Signed<Event>>>> {
+        let tables = self.tables.lock().await;
+        let groups = tables
+            .namespace_events(name, keys.as_deref(), min_priority, since)
+            .map(|(received, event)| (event.verifying_key.as_str(), (received, event)))
+            .into_group_map()
+            .into_iter()
+            .sorted_by_key(|(verifying_key, group)| {
+                let rank = group
+                    .iter()
+                    .map(|(received, event)| match order {
+                        NamespaceOrder::Priority => event.rank(),
+                        NamespaceOrder::Recency => *received as u64,
+                    })
+                    .max();
+                (std::cmp::Reverse(rank), *verifying_key)
+            })
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(_, group)| {
+                group
+                    .into_iter()
+                    .map(|(_, event)| event.signed_event.clone())
+                    .collect()
+            })
+            .collect();
+        Ok(groups)
+    }
+
+    async fn namespace_count(
+        &self,
+        name: &str,
+        keys: Option<Vec<String>>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+    ) -> Result<usize> {
+        let tables = self.tables.lock().await;
+        Ok(tables
+            .namespace_events(name, keys.as_deref(), min_priority, since)
+            .map(|(_, event)| &event.verifying_key)
+            .unique()
+            .count())
+    }
+
+    async fn set_peer_last_hash(&self, peer_url: &str, hash: StateHash) -> Result<()> {
+        let mut tables = self.tables.lock().await;
+        tables.peers.insert(peer_url.to_string(), hash);
+        Ok(())
+    }
+
+    async fn get_peer_last_hash(&self, peer_url: &str) -> Option<StateHash> {
+        let tables = self.tables.lock().await;
+        tables
+            .peers
+            .get(peer_url)
+            .map(|hash| StateHash { hash: hash.hash })
+    }
+
+    async fn enqueue_event(&self, signed_event: &Signed<Event>) -> Result<()> {
+        let stored = StoredEvent::new(signed_event)?;
+        let mut tables = self.tables.lock().await;
+        if tables
+            .outbox
+            .values()
+            .any(|queued| queued.encoded == stored.encoded)
+        {
+            return Ok(());
+        }
+        tables.next_outbox_id += 1;
+        let id = tables.next_outbox_id;
+        tables.outbox.insert(id, stored);
+        Ok(())
+    }
+
+    async fn queued_events(&self) -> Result<Vec<(i64, Signed<Event>)>> {
+        let tables = self.tables.lock().await;
+        Ok(tables
+            .outbox
+            .iter()
+            .map(|(id, queued)| (*id, queued.signed_event.clone()))
+            .collect())
+    }
+
+    async fn dequeue_event(&self, id: i64) -> Result<()> {
+        self.tables.lock().await.outbox.remove(&id);
+        Ok(())
+    }
+
+    async fn discard_queued_event(&self, id: i64) -> Result<()> {
+        let mut tables = self.tables.lock().await;
+        if let Some(queued) = tables.outbox.remove(&id) {
+            tables
+                .events
+                .retain(|stored| stored.encoded != queued.encoded);
+        }
+        Ok(())
+    }
+
+    async fn set_synced_at(&self, verifying_key: String, synced_at: u64) -> Result<()> {
+        let mut tables = self.tables.lock().await;
+        tables.synced.insert(verifying_key, synced_at);
+        Ok(())
+    }
+
+    async fn get_synced_at(&self, verifying_key: String) -> Result<Option<u64>> {
+        Ok(self.tables.lock().await.synced.get(&verifying_key).copied())
+    }
+}
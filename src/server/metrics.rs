@@ -0,0 +1,278 @@
+//! Prometheus metrics of a node, served at `/metrics` in the OpenMetrics text format
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+
+use crate::{connectors::connection::Connection, time::unix_timestamp};
+
+use super::node::Node;
+
+pub const METRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KindLabels {
+    kind: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SourceLabels {
+    source: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReasonLabels {
+    reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PeerLabels {
+    peer: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PeerResultLabels {
+    peer: String,
+    result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    route: String,
+    method: String,
+    status: u16,
+}
+
+/// Counters updated as a node works, and gauges read from its stores when scraped
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    events: Family<KindLabels, Gauge>,
+    store_bytes: Gauge,
+    blocks: Gauge,
+    block_bytes: Gauge,
+    ingested_events: Family<SourceLabels, Counter>,
+    rejected_events: Family<ReasonLabels, Counter>,
+    gc_deleted_events: Family<ReasonLabels, Counter>,
+    peer_sync_duration: Family<PeerLabels, Histogram, fn() -> Histogram>,
+    peer_syncs: Family<PeerResultLabels, Counter>,
+    peer_sync_lag: Family<PeerLabels, Gauge>,
+    peer_circuit_open: Family<PeerLabels, Gauge>,
+    // Unix timestamps of the last successful sync with each peer, for the lag
+    peer_synced_at: Arc<Mutex<HashMap<String, u64>>>,
+    http_request_duration: Family<RouteLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let metrics = Metrics {
+            registry: Arc::new(Registry::default()),
+            events: Family::default(),
+            store_bytes: Gauge::default(),
+            blocks: Gauge::default(),
+            block_bytes: Gauge::default(),
+            ingested_events: Family::default(),
+            rejected_events: Family::default(),
+            gc_deleted_events: Family::default(),
+            peer_sync_duration: Family::new_with_constructor(duration_histogram),
+            peer_syncs: Family::default(),
+            peer_sync_lag: Family::default(),
+            peer_circuit_open: Family::default(),
+            peer_synced_at: Arc::new(Mutex::new(HashMap::new())),
+            http_request_duration: Family::new_with_constructor(duration_histogram),
+        };
+        let mut registry = Registry::with_prefix("baybridge");
+        registry.register("events", "Current events by kind", metrics.events.clone());
+        registry.register(
+            "store_bytes",
+            "Bytes taken up by current and retained events",
+            metrics.store_bytes.clone(),
+        );
+        registry.register(
+            "immutable_blocks",
+            "Stored immutable blocks",
+            metrics.blocks.clone(),
+        );
+        registry.register(
+            "immutable_block_bytes",
+            "Bytes taken up by immutable blocks",
+            metrics.block_bytes.clone(),
+        );
+        registry.register(
+            "ingested_events",
+            "Events inserted, from clients or peers",
+            metrics.ingested_events.clone(),
+        );
+        registry.register(
+            "rejected_events",
+            "Events refused, by reason",
+            metrics.rejected_events.clone(),
+        );
+        registry.register(
+            "gc_deleted_events",
+            "Events deleted by garbage collection, by reason",
+            metrics.gc_deleted_events.clone(),
+        );
+        registry.register(
+            "peer_sync_duration_seconds",
+            "Time taken to sync with a peer",
+            metrics.peer_sync_duration.clone(),
+        );
+        registry.register(
+            "peer_syncs",
+            "Syncs with a peer, by result",
+            metrics.peer_syncs.clone(),
+        );
+        registry.register(
+            "peer_sync_lag_seconds",
+            "Seconds since the last successful sync with a peer",
+            metrics.peer_sync_lag.clone(),
+        );
+        registry.register(
+            "peer_circuit_breaker_open",
+            "Whether requests to a peer are being refused after repeated failures",
+            metrics.peer_circuit_open.clone(),
+        );
+        registry.register(
+            "http_request_duration_seconds",
+            "Time taken to answer requests, by route",
+            metrics.http_request_duration.clone(),
+        );
+        Metrics {
+            registry: Arc::new(registry),
+            ..metrics
+        }
+    }
+
+    pub fn record_ingested(&self, source: &'static str, count: usize) {
+        self.ingested_events
+            .get_or_create(&SourceLabels { source })
+            .inc_by(count as u64);
+    }
+
+    pub fn record_rejected(&self, reason: &'static str, count: usize) {
+        self.rejected_events
+            .get_or_create(&ReasonLabels { reason })
+            .inc_by(count as u64);
+    }
+
+    pub fn record_gc(&self, reason: &'static str, count: usize) {
+        self.gc_deleted_events
+            .get_or_create(&ReasonLabels { reason })
+            .inc_by(count as u64);
+    }
+
+    pub fn record_sync(&self, peer: &str, duration: Duration, success: bool) {
+        let labels = PeerLabels {
+            peer: peer.to_string(),
+        };
+        self.peer_sync_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+        let result = if success { "success" } else { "failure" };
+        self.peer_syncs
+            .get_or_create(&PeerResultLabels {
+                peer: labels.peer,
+                result,
+            })
+            .inc();
+        if success {
+            self.peer_synced_at
+                .lock()
+                .unwrap()
+                .insert(peer.to_string(), unix_timestamp());
+        }
+    }
+
+    pub fn record_request(&self, route: String, method: String, status: u16, duration: Duration) {
+        self.http_request_duration
+            .get_or_create(&RouteLabels {
+                route,
+                method,
+                status,
+            })
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Reads the gauges from the node's stores and peer connections and encodes every metric
+    pub async fn render(&self, node: &Node, peers: &[Connection]) -> Result<String> {
+        let counts = node.controller().event_counts_by_kind().await?;
+        self.events.clear();
+        for (kind, count) in counts {
+            self.events
+                .get_or_create(&KindLabels { kind })
+                .set(count as i64);
+        }
+        self.store_bytes
+            .set(node.controller().size_bytes().await? as i64);
+        let (blocks, block_bytes) = node.immutable_controller().usage().await?;
+        self.blocks.set(blocks as i64);
+        self.block_bytes.set(block_bytes as i64);
+
+        let now = unix_timestamp();
+        for (peer, synced_at) in self.peer_synced_at.lock().unwrap().iter() {
+            self.peer_sync_lag
+                .get_or_create(&PeerLabels { peer: peer.clone() })
+                .set(now.saturating_sub(*synced_at) as i64);
+        }
+        for peer in peers {
+            self.peer_circuit_open
+                .get_or_create(&PeerLabels {
+                    peer: peer.url().to_string(),
+                })
+                .set(peer.is_circuit_open() as i64);
+        }
+
+        let mut encoded = String::new();
+        encode(&mut encoded, &self.registry)?;
+        Ok(encoded)
+    }
+}
+
+/// Buckets from 1ms to about 30s
+fn duration_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.001, 2.0, 16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn renders_recorded_metrics() {
+        let node = Node::in_memory().unwrap();
+        let metrics = Metrics::new();
+        metrics.record_rejected("signature", 2);
+        metrics.record_sync("local://peer", Duration::from_millis(5), true);
+
+        let rendered = metrics.render(&node, &[]).await.unwrap();
+        assert!(rendered.contains(r#"baybridge_rejected_events_total{reason="signature"} 2"#));
+        assert!(
+            rendered
+                .contains(r#"baybridge_peer_syncs_total{peer="local://peer",result="success"} 1"#)
+        );
+        assert!(rendered.contains(r#"baybridge_peer_sync_lag_seconds{peer="local://peer"}"#));
+        assert!(rendered.contains("baybridge_immutable_blocks 0"));
+        assert!(rendered.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,232 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior};
+use tracing::info;
+
+use crate::{client::Event, crypto::Signed, time::unix_timestamp};
+
+enum Migration {
+    Sql(&'static str),
+    /// Changes SQL cannot express, such as re-encoding rows
+    Code(fn(&Transaction) -> Result<()>),
+}
+
+/// Schema changes in the order they are applied. Applying the migration at index `i` brings a
+/// database to schema version `i + 1`. Released migrations must never be edited, only appended
+/// to.
+const MIGRATIONS: &[Migration] = &[
+    // 1: the tables of databases created before schema versioning
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            verifying_key BLOB NOT NULL,
+            name BLOB NOT NULL,
+            signed_event BLOB NOT NULL UNIQUE,
+            priority BIGINT NOT NULL,
+            expires_at INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            verifying_key BLOB NOT NULL,
+            name BLOB NOT NULL,
+            signed_event BLOB NOT NULL UNIQUE,
+            priority BIGINT NOT NULL,
+            expires_at INTEGER,
+            superseded_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS peers (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url TEXT NOT NULL UNIQUE,
+            last_hash BLOB
+        );",
+    ),
+    // 2: client replicas queue events written while offline and note when keyspaces were synced
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            signed_event BLOB NOT NULL UNIQUE
+        );
+        CREATE TABLE IF NOT EXISTS synced (
+            verifying_key BLOB PRIMARY KEY,
+            synced_at INTEGER NOT NULL
+        );",
+    ),
+    // 3: indexes for reads by address and namespace, and for garbage collection
+    Migration::Sql(
+        "CREATE INDEX events_by_address ON events (verifying_key, name, priority);
+        CREATE INDEX events_by_namespace ON events (name, priority);
+        CREATE INDEX events_by_expiry ON events (expires_at) WHERE expires_at IS NOT NULL;
+        CREATE INDEX history_by_address ON history (verifying_key, name, priority);
+        CREATE INDEX history_by_superseded_at ON history (superseded_at);
+        CREATE INDEX history_by_expiry ON history (expires_at) WHERE expires_at IS NOT NULL;",
+    ),
+    // 4: events stored before their encoding was versioned, rewritten with a version tag so
+    // they stay unique against the same events received again
+    Migration::Code(reencode_legacy_events),
+    // 5: when the node received each event, for namespace queries since a time. Events stored
+    // before count as received at 0.
+    Migration::Sql("ALTER TABLE events ADD COLUMN received_at INTEGER NOT NULL DEFAULT 0;"),
+];
+
+/// The schema version this build creates and expects
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Brings a database up to `SCHEMA_VERSION`, first copying an existing database file to a
+/// backup next to it. Each migration is applied in its own transaction, and skipped if another
+/// connection applied it first.
+pub fn migrate(connection: &mut Connection, database_path: Option<&Path>) -> Result<()> {
+    let is_empty = connection.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| {
+        row.get::<_, usize>(0)
+    })? == 0;
+    let version = schema_version(connection)?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "Database schema version {version} is newer than version {SCHEMA_VERSION} supported by this baybridge"
+        );
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    if !is_empty && let Some(database_path) = database_path {
+        let backup_path = backup_path(database_path, version);
+        info!(
+            "Backing up database schema version {} to {}",
+            version,
+            backup_path.display()
+        );
+        let backed_up = connection.execute("VACUUM INTO ?", [backup_path.to_string_lossy()]);
+        // Another connection opening the database at the same version may have written it first
+        if backed_up.is_err() && !backup_path.exists() {
+            backed_up.with_context(|| {
+                format!("Failed to back up database to {}", backup_path.display())
+            })?;
+        }
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let target = index + 1;
+        // Take the write lock before checking, since another process may be migrating too
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if schema_version(&transaction)? >= target {
+            continue;
+        }
+        info!("Migrating database to schema version {}", target);
+        transaction.execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+            (),
+        )?;
+        match migration {
+            Migration::Sql(sql) => transaction.execute_batch(sql).map_err(Into::into),
+            Migration::Code(migrate) => migrate(&transaction),
+        }
+        .with_context(|| format!("Failed to migrate database to schema version {target}"))?;
+        transaction.execute("DELETE FROM schema_version", ())?;
+        transaction.execute("INSERT INTO schema_version (version) VALUES (?)", (target,))?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn reencode_legacy_events(transaction: &Transaction) -> Result<()> {
+    for table in ["events", "history"] {
+        let rows = transaction
+            .prepare(&format!(
+                "SELECT id, signed_event FROM {table} WHERE substr(signed_event, 1, 1) IN (x'00', x'01')"
+            ))?
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (id, signed_event) in rows {
+            let event = Signed::<Event>::from_bytes(&signed_event)
+                .with_context(|| format!("Failed to decode event {id} in {table}"))?;
+            transaction.execute(
+                &format!("UPDATE {table} SET signed_event = ? WHERE id = ?"),
+                (event.to_bytes(), id),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The applied schema version, 0 for databases from before versioning
+pub fn schema_version(connection: &Connection) -> Result<usize> {
+    let versioned = connection
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !versioned {
+        return Ok(0);
+    }
+    let version = connection
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?;
+    Ok(version.unwrap_or(0))
+}
+
+fn backup_path(database_path: &Path, version: usize) -> PathBuf {
+    let timestamp = unix_timestamp();
+    let mut file_name = database_path.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".v{version}.{timestamp}.bak"));
+    database_path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::legacy_set_event, crypto::encode::encode_verifying_key};
+
+    #[test]
+    fn migrates_legacy_databases_after_backing_them_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.sqlite");
+        let (stored, verifying_key) = legacy_set_event();
+        let mut connection = Connection::open(&path).unwrap();
+        let Migration::Sql(tables) = MIGRATIONS[0] else {
+            unreachable!();
+        };
+        connection.execute_batch(tables).unwrap();
+        connection
+            .execute(
+                "INSERT INTO events (verifying_key, name, signed_event, priority) VALUES (?, ?, ?, 1)",
+                (encode_verifying_key(&verifying_key).into_bytes(), b"foo", &stored),
+            )
+            .unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), 0);
+
+        migrate(&mut connection, Some(&path)).unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), SCHEMA_VERSION);
+        let migrated: Vec<u8> = connection
+            .query_row("SELECT signed_event FROM events", [], |row| row.get(0))
+            .unwrap();
+        let event = Signed::<Event>::from_bytes(&migrated).unwrap();
+        assert_eq!(migrated, event.to_bytes());
+        assert!(event.verify_event(&verifying_key));
+        assert_eq!(event.inner.value().unwrap().as_bytes(), b"bar");
+
+        // The backup keeps the database as it was before migrating
+        let backups = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().ends_with(".bak"))
+            .collect::<Vec<_>>();
+        assert_eq!(backups.len(), 1);
+        assert!(
+            backups[0]
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("legacy.sqlite.v0.")
+        );
+        let backup = Connection::open(&backups[0]).unwrap();
+        assert_eq!(schema_version(&backup).unwrap(), 0);
+        let backed_up: Vec<u8> = backup
+            .query_row("SELECT signed_event FROM events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(backed_up, stored);
+    }
+}
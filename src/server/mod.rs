@@ -1,7 +1,16 @@
-mod data_controller;
+pub mod archive;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod data_controller;
+pub mod event_store;
 pub mod http;
-mod immutable_controller;
-mod sqlite_store;
+pub mod immutable_controller;
+pub mod memory_store;
+pub mod metrics;
+mod migrations;
+mod negotiate;
+pub mod node;
+pub mod sqlite_store;
 mod task_controller;
 mod tasks;
 mod templates;
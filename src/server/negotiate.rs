@@ -0,0 +1,114 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{
+        StatusCode,
+        header::{ACCEPT, CONTENT_TYPE, VARY},
+        request::Parts,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::api::WireFormat;
+
+/// Request body decoded according to its Content-Type
+pub struct Negotiated<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let format = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(WireFormat::from_content_type)
+            .ok_or_else(|| {
+                (
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "Unsupported content type",
+                )
+                    .into_response()
+            })?;
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let value = format.decode(&bytes).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Failed to decode body: {e}"),
+            )
+                .into_response()
+        })?;
+        Ok(Negotiated(value))
+    }
+}
+
+/// Response format chosen from the request's Accept header
+pub struct Accept(pub WireFormat);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Accept
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let format = parts
+            .headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .map(WireFormat::from_accept)
+            .unwrap_or(WireFormat::Json);
+        Ok(Accept(format))
+    }
+}
+
+/// Response body encoded in the negotiated format
+pub struct Encoded<T>(pub WireFormat, pub T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Encoded(format, value) = self;
+        match format.encode(&value) {
+            Ok(bytes) => (
+                [(CONTENT_TYPE, format.content_type()), (VARY, "accept")],
+                bytes,
+            )
+                .into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use crate::{api::BINCODE_CONTENT_TYPE, client::Event, crypto::Signed};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_bodies_claiming_huge_lengths() {
+        // A set event whose name claims to be 2^60 bytes long
+        let mut body = vec![0, 253];
+        body.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        let request = Request::builder()
+            .header(CONTENT_TYPE, BINCODE_CONTENT_TYPE)
+            .body(Body::from(body))
+            .unwrap();
+        let Err(rejection) = Negotiated::<Signed<Event>>::from_request(request, &()).await else {
+            panic!("Decoded a truncated body");
+        };
+        assert_eq!(rejection.status(), StatusCode::BAD_REQUEST);
+    }
+}
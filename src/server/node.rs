@@ -0,0 +1,209 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+
+use crate::{
+    api::{INSUFFICIENT_WORK, NamespaceQuery, NodePolicy, Snapshot, StateHash},
+    client::{Event, TrustPolicy},
+    configuration::Configuration,
+    connectors::{connection::Connection, http::NamespaceResponse},
+    crypto::Signed,
+    time::unix_timestamp,
+};
+
+use super::{
+    data_controller::DataController, immutable_controller::ImmutableController, metrics::Metrics,
+    sqlite_store::SqliteStore, tasks,
+};
+
+/// The stores and policies of a node, served over HTTP or used in-process by a local connection
+#[derive(Clone)]
+pub struct Node {
+    controller: DataController,
+    immutable_controller: ImmutableController,
+    protected_namespaces: Arc<HashMap<String, TrustPolicy>>,
+    min_stamp_work: Option<u32>,
+    metrics: Metrics,
+}
+
+impl Node {
+    pub fn new(controller: DataController, immutable_controller: ImmutableController) -> Node {
+        Node {
+            controller,
+            immutable_controller,
+            protected_namespaces: Arc::new(HashMap::new()),
+            min_stamp_work: None,
+            metrics: Metrics::new(),
+        }
+    }
+
+    pub async fn open(config: &Configuration) -> Result<Node> {
+        let store = SqliteStore::new(&config.server_database_path())?
+            .with_history_retention(config.history_retention());
+        let immutable_controller = ImmutableController::new(config.immutable_store_path()).await;
+        Ok(Node::new(DataController::new(store), immutable_controller)
+            .with_protected_namespaces(config.protected_namespaces().clone())
+            .with_min_stamp_work(config.min_stamp_work()))
+    }
+
+    pub fn in_memory() -> Result<Node> {
+        let controller = DataController::new(SqliteStore::in_memory()?);
+        Ok(Node::new(controller, ImmutableController::in_memory()))
+    }
+
+    pub fn with_protected_namespace(mut self, name: String, policy: TrustPolicy) -> Node {
+        Arc::make_mut(&mut self.protected_namespaces).insert(name, policy);
+        self
+    }
+
+    pub fn with_protected_namespaces(
+        mut self,
+        protected_namespaces: HashMap<String, TrustPolicy>,
+    ) -> Node {
+        self.protected_namespaces = Arc::new(protected_namespaces);
+        self
+    }
+
+    /// Reject events without a proof-of-work stamp of at least this many bits, more for large ones
+    pub fn with_min_stamp_work(mut self, min_stamp_work: Option<u32>) -> Node {
+        self.min_stamp_work = min_stamp_work;
+        self
+    }
+
+    pub fn controller(&self) -> &DataController {
+        &self.controller
+    }
+
+    pub fn immutable_controller(&self) -> &ImmutableController {
+        &self.immutable_controller
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn min_stamp_work(&self) -> Option<u32> {
+        self.min_stamp_work
+    }
+
+    pub fn policy(&self) -> NodePolicy {
+        NodePolicy {
+            min_stamp_work: self.min_stamp_work,
+        }
+    }
+
+    /// Returns why a client write is refused, if it is
+    pub fn check_event(
+        &self,
+        verifying_key: &VerifyingKey,
+        event: &Signed<Event>,
+    ) -> Result<(), &'static str> {
+        let verified =
+            event.keyspace() == Some(*verifying_key) && event.verify_event(verifying_key);
+        let delegation_expired = event
+            .delegation()
+            .and_then(|delegation| delegation.inner.expires_at)
+            .is_some_and(|expires_at| expires_at < unix_timestamp());
+        if !verified {
+            self.metrics.record_rejected("signature", 1);
+            return Err("Forbidden");
+        }
+        if delegation_expired {
+            self.metrics.record_rejected("expired_delegation", 1);
+            return Err("Forbidden");
+        }
+        if let Some(min_stamp_work) = self.min_stamp_work
+            && !event.meets_stamp_work(min_stamp_work)
+        {
+            self.metrics.record_rejected("stamp_work", 1);
+            return Err(INSUFFICIENT_WORK);
+        }
+        Ok(())
+    }
+
+    /// Inserts a client write that passed `check_event`
+    pub async fn insert_event(&self, event: Signed<Event>) -> Result<usize> {
+        let inserted = self.controller.insert_event(event).await?;
+        self.metrics.record_ingested("client", inserted);
+        Ok(inserted)
+    }
+
+    /// Leaves out keyspaces rejected by the namespace's policy
+    pub async fn namespace(
+        &self,
+        name: String,
+        keys: Option<&[VerifyingKey]>,
+        query: &NamespaceQuery,
+    ) -> Result<NamespaceResponse> {
+        let (min_priority, since) = (query.min_priority, query.since);
+        let order = query.order.unwrap_or_default();
+        let Some(policy) = self.protected_namespaces.get(&name) else {
+            let response = if query.count {
+                NamespaceResponse {
+                    namespace: name.clone(),
+                    events: Vec::new(),
+                    count: Some(
+                        self.controller
+                            .namespace_count(&name, keys, min_priority, since)
+                            .await?,
+                    ),
+                }
+            } else {
+                let groups = self
+                    .controller
+                    .events_by_namespace(&name, keys, min_priority, since, order, query.limit)
+                    .await?;
+                NamespaceResponse {
+                    namespace: name,
+                    events: groups.into_iter().flatten().collect(),
+                    count: None,
+                }
+            };
+            return Ok(response);
+        };
+        // Keyspaces the policy rejects must not use up the limit or add to the count
+        let groups = self
+            .controller
+            .events_by_namespace(&name, keys, min_priority, since, order, None)
+            .await?;
+        let mut admitted = Vec::new();
+        for group in groups {
+            let Some(keyspace) = group[0].keyspace() else {
+                continue;
+            };
+            if self.controller.admits(policy, &keyspace).await? {
+                admitted.push(group);
+            }
+        }
+        let response = if query.count {
+            NamespaceResponse {
+                namespace: name,
+                events: Vec::new(),
+                count: Some(admitted.len()),
+            }
+        } else {
+            NamespaceResponse {
+                namespace: name,
+                events: admitted
+                    .into_iter()
+                    .take(query.limit.unwrap_or(usize::MAX))
+                    .flatten()
+                    .collect(),
+                count: None,
+            }
+        };
+        Ok(response)
+    }
+
+    /// A snapshot of the current events, or None if they no longer have the state hash
+    pub async fn snapshot(&self, state_hash: &StateHash) -> Result<Option<Snapshot>> {
+        let snapshot = Snapshot::new(self.controller.signed_events().await?)?;
+        Ok((snapshot.state_hash == *state_hash).then_some(snapshot))
+    }
+
+    /// Only imports the events that changed since the last sync with the peer
+    pub async fn sync_with(&self, peer: &Connection) -> Result<()> {
+        tasks::sync::run(&self.controller, peer, self.min_stamp_work, &self.metrics).await
+    }
+}
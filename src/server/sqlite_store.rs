@@ -1,232 +1,607 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
-use bincode::config::standard;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
 use itertools::Itertools;
-use rusqlite::params;
-use tokio::sync::Mutex;
-use tracing::debug;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{
+    Connection, OptionalExtension, Params, TransactionBehavior, params, params_from_iter,
+    types::Value as SqlValue,
+};
 
 use crate::{
-    api::StateHash,
-    client::Event,
+    api::{NamespaceOrder, StateHash, WriteConflict},
+    client::{Event, NO_PREVIOUS_EVENT},
+    crdt::latest_event,
     crypto::{Signed, encode::encode_verifying_key},
+    time::unix_timestamp,
 };
 
+use super::{event_store::EventStore, migrations};
+
+/// Connections open at once. With WAL, readers on them run alongside the one writer.
+const POOL_SIZE: u32 = 8;
+
+/// How long a writer waits for another to commit before failing with `SQLITE_BUSY`
+const BUSY_TIMEOUT: &str = "5000";
+
+const EVENTS_BY_ADDRESS: &str =
+    "SELECT signed_event FROM events WHERE verifying_key = ? AND name = ?";
+
 #[derive(Clone)]
 pub struct SqliteStore {
-    connection: Arc<Mutex<rusqlite::Connection>>,
+    pool: r2d2::Pool<SqliteConnectionManager>,
+    history_retention: Option<Duration>,
 }
 
 impl SqliteStore {
-    pub fn new(database_path: &PathBuf) -> anyhow::Result<Self> {
-        let connection = rusqlite::Connection::open(database_path)?;
-        connection.execute(
-            "CREATE TABLE IF NOT EXISTS events (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                verifying_key BLOB NOT NULL,
-                name BLOB NOT NULL,
-                signed_event BLOB NOT NULL UNIQUE,
-                priority BIGINT NOT NULL,
-                expires_at INTEGER
-            )",
-            (),
-        )?;
-        connection.execute(
-            "CREATE TABLE IF NOT EXISTS peers (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                url TEXT NOT NULL UNIQUE,
-                last_hash BLOB
-            )",
-            (),
-        )?;
-        let connection = Arc::new(Mutex::new(connection));
-        Ok(Self { connection })
+    pub fn new(database_path: &PathBuf) -> Result<Self> {
+        let manager = SqliteConnectionManager::file(database_path).with_init(|connection| {
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            connection.pragma_update(None, "busy_timeout", BUSY_TIMEOUT)
+        });
+        let pool = r2d2::Pool::builder().max_size(POOL_SIZE).build(manager)?;
+        migrations::migrate(&mut *pool.get()?, Some(database_path))?;
+        Ok(Self::with_pool(pool))
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        // Every connection to `:memory:` opens a separate database, so keep exactly one open
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(SqliteConnectionManager::memory())?;
+        migrations::migrate(&mut *pool.get()?, None)?;
+        Ok(Self::with_pool(pool))
+    }
+
+    fn with_pool(pool: r2d2::Pool<SqliteConnectionManager>) -> Self {
+        Self {
+            pool,
+            history_retention: None,
+        }
+    }
+
+    /// Keeps superseded events in the history table for this long instead of deleting them
+    pub fn with_history_retention(mut self, history_retention: Option<Duration>) -> Self {
+        self.history_retention = history_retention;
+        self
+    }
+
+    /// Runs blocking SQLite work on a pooled connection, off the async runtime's workers
+    async fn run<T, F>(&self, work: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || work(&mut *pool.get()?)).await?
     }
+}
 
-    pub async fn delete_expired_events(&self, unix_timestamp: u64) -> anyhow::Result<usize> {
-        let database_guard = self.connection.lock().await;
-        let num_deleted = database_guard.execute(
-            "DELETE FROM events WHERE expires_at <= ?",
-            (unix_timestamp,),
+/// The columns stored for an event
+struct EventRow {
+    verifying_key: String,
+    name: String,
+    signed_event: Vec<u8>,
+    // The event's rank, which is its priority for every kind but revocations
+    priority: u64,
+    expires_at: Option<u64>,
+}
+
+impl EventRow {
+    fn new(event: &Signed<Event>) -> Result<EventRow> {
+        Ok(EventRow {
+            verifying_key: encode_verifying_key(
+                &event
+                    .keyspace()
+                    .ok_or_else(|| anyhow!("Event is signed with an invalid key"))?,
+            ),
+            name: event.inner.name().to_string(),
+            signed_event: event.to_bytes(),
+            priority: event.inner.rank(),
+            // Expiries beyond a signed 64-bit SQLite integer are as good as never
+            expires_at: event
+                .inner
+                .expires_at()
+                .map(|expires_at| expires_at.min(i64::MAX as u64)),
+        })
+    }
+
+    fn is_stale(&self, connection: &Connection) -> Result<bool> {
+        let count: usize = connection.prepare_cached(
+            "SELECT COUNT(*) FROM events WHERE verifying_key = ? AND name = ? AND expires_at >= ? AND priority >= ?",
+        )?
+        .query_row(
+            params![
+                self.verifying_key.as_bytes(),
+                self.name.as_bytes(),
+                self.expires_at,
+                self.priority,
+            ],
+            |row| row.get(0),
         )?;
+        Ok(count > 0)
+    }
+
+    fn insert(&self, connection: &Connection) -> Result<usize> {
+        let num_inserted = connection.prepare_cached(
+            "INSERT OR IGNORE INTO events (verifying_key, name, signed_event, priority, expires_at, received_at) VALUES (?, ?, ?, ?, ?, ?)",
+        )?
+        .execute(params![
+            self.verifying_key.as_bytes(),
+            self.name.as_bytes(),
+            self.signed_event.as_slice(),
+            self.priority,
+            self.expires_at,
+            unix_timestamp(),
+        ])?;
+        Ok(num_inserted)
+    }
+
+    fn delete_stale(
+        &self,
+        connection: &Connection,
+        history_retention: Option<Duration>,
+    ) -> Result<usize> {
+        // Events with an expiry are only superseded by events that outlive them
+        let stale_condition = "verifying_key = ?1 AND name = ?2 AND (?3 IS NULL OR expires_at < ?3) AND priority < ?4";
+        if history_retention.is_some() {
+            connection
+                .prepare_cached(&format!(
+                    "INSERT OR IGNORE INTO history (verifying_key, name, signed_event, priority, expires_at, superseded_at)
+                     SELECT verifying_key, name, signed_event, priority, expires_at, ?5 FROM events WHERE {stale_condition}"
+                ))?
+                .execute(params![
+                    self.verifying_key.as_bytes(),
+                    self.name.as_bytes(),
+                    self.expires_at,
+                    self.priority,
+                    unix_timestamp(),
+                ])?;
+        }
+        let num_deleted = connection
+            .prepare_cached(&format!("DELETE FROM events WHERE {stale_condition}"))?
+            .execute(params![
+                self.verifying_key.as_bytes(),
+                self.name.as_bytes(),
+                self.expires_at,
+                self.priority,
+            ])?;
         Ok(num_deleted)
     }
+}
 
-    pub async fn event_count(&self) -> anyhow::Result<usize> {
-        let database_guard = self.connection.lock().await;
-        let mut stmt = database_guard.prepare("SELECT COUNT(*) FROM events")?;
-        let count = stmt.query_row([], |row| row.get(0))?;
-        Ok(count)
+/// Uses numbered parameters so the condition can appear twice in a query
+fn namespace_condition(
+    name: &str,
+    keys: Option<Vec<String>>,
+    min_priority: Option<u64>,
+    since: Option<u64>,
+) -> (String, Vec<SqlValue>) {
+    let mut condition =
+        "events.name = ?1 AND events.priority >= ?2 AND events.received_at >= ?3".to_string();
+    let mut params = vec![
+        SqlValue::Blob(name.as_bytes().to_vec()),
+        SqlValue::Integer(i64::try_from(min_priority.unwrap_or(0)).unwrap_or(i64::MAX)),
+        SqlValue::Integer(i64::try_from(since.unwrap_or(0)).unwrap_or(i64::MAX)),
+    ];
+    if let Some(keys) = keys {
+        let placeholders = (0..keys.len())
+            .map(|index| format!("?{}", index + 4))
+            .join(", ");
+        condition.push_str(&format!(" AND events.verifying_key IN ({placeholders})"));
+        params.extend(keys.into_iter().map(|key| SqlValue::Blob(key.into_bytes())));
     }
+    (condition, params)
+}
+
+fn query_events(
+    connection: &Connection,
+    sql: &str,
+    params: impl Params,
+) -> Result<Vec<Signed<Event>>> {
+    let mut stmt = connection.prepare_cached(sql)?;
+    let rows = stmt
+        .query_map(params, |row| row.get::<_, Vec<u8>>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    rows.iter()
+        .map(|serialized| Signed::from_bytes(serialized))
+        .collect()
+}
 
-    pub async fn signed_events(&self) -> anyhow::Result<Vec<Signed<Event>>> {
-        let database_guard = self.connection.lock().await;
-        let mut stmt =
-            database_guard.prepare("SELECT signed_event FROM events ORDER BY signed_event")?;
-        let signed_events = stmt
-            .query_map([], |row| {
-                let signed_event_serialized: Vec<u8> = row.get(0)?;
-                let signed_event: Signed<Event> =
-                    bincode::decode_from_slice(&signed_event_serialized, standard())
-                        .unwrap()
-                        .0;
-                Ok(signed_event)
-            })?
-            .filter_map(Result::ok)
-            .collect();
-        Ok(signed_events)
+#[async_trait]
+impl EventStore for SqliteStore {
+    async fn insert_event(&self, signed_event: &Signed<Event>) -> Result<usize> {
+        let row = EventRow::new(signed_event)?;
+        self.run(move |connection| row.insert(connection)).await
     }
 
-    pub async fn current_state_hash(&self) -> anyhow::Result<StateHash> {
-        let all_signed_events = self.signed_events().await?;
-        let serialized_events = bincode::encode_to_vec(&all_signed_events, standard())?;
-        let hash = blake3::hash(&serialized_events);
-        Ok(StateHash { hash })
+    async fn is_stale_event(&self, event: &Signed<Event>) -> Result<bool> {
+        let row = EventRow::new(event)?;
+        self.run(move |connection| row.is_stale(connection)).await
     }
 
-    pub async fn events_by_key_and_name(
+    async fn delete_stale_events(&self, event: &Signed<Event>) -> Result<usize> {
+        let row = EventRow::new(event)?;
+        let history_retention = self.history_retention;
+        self.run(move |connection| row.delete_stale(connection, history_retention))
+            .await
+    }
+
+    async fn insert_current_event(
+        &self,
+        event: &Signed<Event>,
+        expected_previous: Option<[u8; 32]>,
+    ) -> Result<usize> {
+        let row = EventRow::new(event)?;
+        let history_retention = self.history_retention;
+        self.run(move |connection| {
+            // Take the write lock up front so the checks still hold when inserting
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if let Some(expected_previous) = expected_previous {
+                let events = query_events(
+                    &transaction,
+                    EVENTS_BY_ADDRESS,
+                    params![row.verifying_key.as_bytes(), row.name.as_bytes()],
+                )?;
+                let previous = latest_event(&events)
+                    .map(|previous| previous.inner.hash())
+                    .unwrap_or(NO_PREVIOUS_EVENT);
+                if previous != expected_previous {
+                    return Err(WriteConflict.into());
+                }
+            }
+            if row.is_stale(&transaction)? {
+                return Ok(0);
+            }
+            let num_inserted = row.insert(&transaction)?;
+            row.delete_stale(&transaction, history_retention)?;
+            transaction.commit()?;
+            Ok(num_inserted)
+        })
+        .await
+    }
+
+    async fn insert_current_events(&self, events: &[Signed<Event>]) -> Result<usize> {
+        let rows = events
+            .iter()
+            .map(EventRow::new)
+            .collect::<Result<Vec<_>>>()?;
+        let history_retention = self.history_retention;
+        self.run(move |connection| {
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut num_inserted = 0;
+            for row in rows {
+                if row.is_stale(&transaction)? {
+                    continue;
+                }
+                num_inserted += row.insert(&transaction)?;
+                row.delete_stale(&transaction, history_retention)?;
+            }
+            transaction.commit()?;
+            Ok(num_inserted)
+        })
+        .await
+    }
+
+    async fn delete_expired_events(&self, unix_timestamp: u64) -> Result<usize> {
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let num_deleted = transaction.execute(
+                "DELETE FROM events WHERE expires_at <= ?",
+                (unix_timestamp,),
+            )?;
+            transaction.execute(
+                "DELETE FROM history WHERE expires_at <= ?",
+                (unix_timestamp,),
+            )?;
+            transaction.commit()?;
+            Ok(num_deleted)
+        })
+        .await
+    }
+
+    async fn delete_old_history(&self, unix_timestamp: u64) -> Result<usize> {
+        let Some(history_retention) = self.history_retention else {
+            return Ok(0);
+        };
+        let cutoff = unix_timestamp.saturating_sub(history_retention.as_secs());
+        self.run(move |connection| {
+            let num_deleted =
+                connection.execute("DELETE FROM history WHERE superseded_at <= ?", (cutoff,))?;
+            Ok(num_deleted)
+        })
+        .await
+    }
+
+    async fn event_count(&self) -> Result<usize> {
+        self.run(|connection| {
+            let count =
+                connection.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))?;
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn event_counts_by_kind(&self) -> Result<HashMap<&'static str, usize>> {
+        self.run(|connection| {
+            // The first two bytes hold the encoding version and the variant index
+            let mut stmt = connection.prepare_cached(
+                "SELECT substr(signed_event, 1, 2) AS prefix, COUNT(*) FROM events GROUP BY prefix",
+            )?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, usize>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut counts = HashMap::new();
+            for (prefix, count) in rows {
+                if let Some(kind) = Event::encoded_kind(&prefix) {
+                    *counts.entry(kind).or_default() += count;
+                }
+            }
+            Ok(counts)
+        })
+        .await
+    }
+
+    async fn size_bytes(&self) -> Result<u64> {
+        self.run(|connection| {
+            let size = connection.query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(size)
+        })
+        .await
+    }
+
+    async fn signed_events(&self) -> Result<Vec<Signed<Event>>> {
+        self.run(|connection| {
+            query_events(
+                connection,
+                "SELECT signed_event FROM events ORDER BY signed_event",
+                [],
+            )
+        })
+        .await
+    }
+
+    async fn signed_events_page(
+        &self,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<Signed<Event>>> {
+        self.run(move |connection| {
+            query_events(
+                connection,
+                "SELECT signed_event FROM events WHERE signed_event > ? ORDER BY signed_event LIMIT ?",
+                params![after.unwrap_or_default(), limit as i64],
+            )
+        })
+        .await
+    }
+
+    async fn events_by_key(&self, verifying_key: String) -> Result<Vec<Signed<Event>>> {
+        self.run(move |connection| {
+            query_events(
+                connection,
+                "SELECT signed_event FROM events WHERE verifying_key = ?",
+                [verifying_key.as_bytes()],
+            )
+        })
+        .await
+    }
+
+    async fn events_by_key_and_name(
         &self,
         verifying_key: String,
         name: String,
-    ) -> anyhow::Result<Vec<Signed<Event>>> {
-        let database_guard = self.connection.lock().await;
-        let mut stmt = database_guard
-            .prepare("SELECT signed_event FROM events WHERE verifying_key = ? AND name = ?")?;
-        let events = stmt
-            .query_map([verifying_key.as_bytes(), name.as_bytes()], |row| {
-                let signed_event_serialized: Vec<u8> = row.get(0)?;
-                let signed_event: Signed<Event> =
-                    bincode::decode_from_slice(&signed_event_serialized, standard())
-                        .unwrap()
-                        .0;
-                Ok(signed_event)
-            })?
-            .filter_map(Result::ok)
-            .collect();
-        Ok(events)
-    }
-
-    pub async fn events_by_namespace(&self, name: &str) -> anyhow::Result<Vec<Signed<Event>>> {
-        let database_guard = self.connection.lock().await;
-        let mut stmt = database_guard.prepare("SELECT signed_event FROM events WHERE name = ?")?;
-        let events = stmt
-            .query_map([name.as_bytes()], |row| {
-                let signed_event_serialized: Vec<u8> = row.get(0)?;
-                let signed_event: Signed<Event> =
-                    bincode::decode_from_slice(&signed_event_serialized, standard())
-                        .unwrap()
-                        .0;
-                Ok(signed_event)
-            })?
-            .filter_map(Result::ok)
-            .collect();
-        Ok(events)
-    }
-
-    pub async fn insert_event(&self, signed_event: &Signed<Event>) -> anyhow::Result<usize> {
-        let name = signed_event.inner.name();
-        let priority = signed_event.inner.priority();
-        let verifying_key = signed_event.verifying_key();
-        let expires_at = signed_event.inner.expires_at();
-
-        let normalized_verifying_key = encode_verifying_key(&verifying_key);
-        let signed_event_serialized = bincode::encode_to_vec(&signed_event, standard())?;
-        let database_guard = self.connection.lock().await;
-        let insert_result = database_guard.execute(
-            "INSERT INTO events (verifying_key, name, signed_event, priority, expires_at) VALUES (?, ?, ?, ?, ?)",
-            params![
-                normalized_verifying_key.as_bytes(),
-                name.as_str().as_bytes(),
-                signed_event_serialized.as_slice(),
-                priority,
-                expires_at,
-            ],
-        );
-        match insert_result {
-            Ok(num_inserted) => Ok(num_inserted),
-            Err(e) => {
-                debug!("Ignoring error inserting event: {:?}", e);
-                Ok(0)
-            }
-        }
+    ) -> Result<Vec<Signed<Event>>> {
+        self.run(move |connection| {
+            query_events(
+                connection,
+                EVENTS_BY_ADDRESS,
+                [verifying_key.as_bytes(), name.as_bytes()],
+            )
+        })
+        .await
     }
 
-    pub async fn delete_stale_events(&self, event: &Signed<Event>) -> anyhow::Result<usize> {
-        let verifying_key = event.verifying_key();
-        let name = event.inner.name();
-        let expires_at = event.inner.expires_at();
-        let priority = event.inner.priority();
-
-        let database_guard = self.connection.lock().await;
-
-        let num_deleted = match expires_at {
-            Some(expires_at) => database_guard.execute(
-                "DELETE FROM events WHERE verifying_key = ? AND name = ? AND expires_at < ? AND priority < ?",
-                params![
-                    encode_verifying_key(&verifying_key).as_bytes(),
-                    name.as_str().as_bytes(),
-                    expires_at,
-                    priority,
-                ],
-            )?,
-            None => database_guard.execute(
-                "DELETE FROM events WHERE verifying_key = ? AND name = ? AND priority < ?",
-                params![
-                    encode_verifying_key(&verifying_key).as_bytes(),
-                    name.as_str().as_bytes(),
-                    priority,
-                ],
-            )?,
+    async fn history_by_key_and_name(
+        &self,
+        verifying_key: String,
+        name: String,
+    ) -> Result<Vec<Signed<Event>>> {
+        self.run(move |connection| {
+            query_events(
+                connection,
+                "SELECT signed_event, priority FROM events WHERE verifying_key = ?1 AND name = ?2
+                 UNION ALL
+                 SELECT signed_event, priority FROM history WHERE verifying_key = ?1 AND name = ?2
+                 ORDER BY priority",
+                [verifying_key.as_bytes(), name.as_bytes()],
+            )
+        })
+        .await
+    }
+
+    async fn events_by_namespace(
+        &self,
+        name: &str,
+        keys: Option<Vec<String>>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+        order: NamespaceOrder,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<Signed<Event>>>> {
+        let (condition, params) = namespace_condition(name, keys, min_priority, since);
+        let order_column = match order {
+            NamespaceOrder::Priority => "top_priority",
+            NamespaceOrder::Recency => "last_id",
         };
-        Ok(num_deleted)
+        // SQLite treats a negative limit as none
+        let limit = limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+        let sql = format!(
+            "WITH keyspaces AS (
+                SELECT verifying_key, MAX(priority) AS top_priority, MAX(id) AS last_id
+                FROM events WHERE {condition}
+                GROUP BY verifying_key
+                ORDER BY {order_column} DESC, verifying_key
+                LIMIT {limit}
+            )
+            SELECT events.verifying_key, events.signed_event
+            FROM events JOIN keyspaces USING (verifying_key)
+            WHERE {condition}
+            ORDER BY keyspaces.{order_column} DESC, verifying_key, events.id"
+        );
+        self.run(move |connection| {
+            let mut stmt = connection.prepare(&sql)?;
+            let rows = stmt
+                .query_map(params_from_iter(params), |row| {
+                    Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut groups = Vec::new();
+            for (_, group) in &rows.iter().chunk_by(|(verifying_key, _)| verifying_key) {
+                groups.push(
+                    group
+                        .map(|(_, signed_event)| Signed::from_bytes(signed_event))
+                        .collect::<Result<Vec<_>>>()?,
+                );
+            }
+            Ok(groups)
+        })
+        .await
     }
 
-    pub async fn is_stale_event(&self, event: &Signed<Event>) -> anyhow::Result<bool> {
-        let verifying_key = event.verifying_key();
-        let name = event.inner.name();
-        let expires_at = event.inner.expires_at();
-        let priority = event.inner.priority();
+    async fn namespace_count(
+        &self,
+        name: &str,
+        keys: Option<Vec<String>>,
+        min_priority: Option<u64>,
+        since: Option<u64>,
+    ) -> Result<usize> {
+        let (condition, params) = namespace_condition(name, keys, min_priority, since);
+        let sql = format!("SELECT COUNT(DISTINCT verifying_key) FROM events WHERE {condition}");
+        self.run(move |connection| {
+            Ok(connection.query_row(&sql, params_from_iter(params), |row| row.get(0))?)
+        })
+        .await
+    }
 
-        let database_guard = self.connection.lock().await;
-        let mut stmt = database_guard.prepare(
-            "SELECT COUNT(*) FROM events WHERE verifying_key = ? AND name = ? AND expires_at >= ? AND priority >= ?",
-        )?;
-        let count: usize = stmt.query_row(
-            params![
-                encode_verifying_key(&verifying_key).as_bytes(),
-                name.as_str().as_bytes(),
-                expires_at,
-                priority,
-            ],
-            |row| row.get(0),
-        )?;
-        Ok(count > 0)
+    async fn set_peer_last_hash(&self, peer_url: &str, hash: StateHash) -> Result<()> {
+        let peer_url = peer_url.to_string();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO peers (url, last_hash) VALUES (?, ?)
+                 ON CONFLICT(url) DO UPDATE SET last_hash = excluded.last_hash",
+                params![peer_url, hash.hash.as_bytes()],
+            )?;
+            Ok(())
+        })
+        .await
     }
 
-    pub async fn set_peer_last_hash(&self, peer_url: &str, hash: StateHash) -> anyhow::Result<()> {
-        let database_guard = self.connection.lock().await;
-        database_guard.execute(
-            "INSERT INTO peers (url, last_hash) VALUES (?, ?)
-             ON CONFLICT(url) DO UPDATE SET last_hash = excluded.last_hash",
-            params![peer_url, hash.hash.as_bytes()],
-        )?;
-        Ok(())
-    }
-
-    pub async fn get_peer_last_hash(&self, peer_url: &str) -> Option<StateHash> {
-        let database_guard = self.connection.lock().await;
-        let mut stmt = database_guard
-            .prepare("SELECT last_hash FROM peers WHERE url = ?")
-            .unwrap();
-        let hash = stmt
-            .query_map([peer_url], |row| {
-                let hash_bytes: [u8; 32] = row.get(0)?;
-                Ok(blake3::Hash::from_bytes(hash_bytes))
+    async fn get_peer_last_hash(&self, peer_url: &str) -> Option<StateHash> {
+        let peer_url = peer_url.to_string();
+        let hash_bytes = self
+            .run(move |connection| {
+                let hash_bytes = connection
+                    .query_row(
+                        "SELECT last_hash FROM peers WHERE url = ?",
+                        [peer_url],
+                        |row| row.get::<_, [u8; 32]>(0),
+                    )
+                    .optional()?;
+                Ok(hash_bytes)
             })
-            .ok()?
-            .at_most_one()
-            .ok()??
-            .ok()?;
-        Some(StateHash { hash })
+            .await
+            .ok()??;
+        Some(StateHash {
+            hash: blake3::Hash::from_bytes(hash_bytes),
+        })
+    }
+
+    async fn enqueue_event(&self, signed_event: &Signed<Event>) -> Result<()> {
+        let signed_event_serialized = signed_event.to_bytes();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO outbox (signed_event) VALUES (?)",
+                params![signed_event_serialized.as_slice()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn queued_events(&self) -> Result<Vec<(i64, Signed<Event>)>> {
+        self.run(|connection| {
+            let mut stmt = connection.prepare("SELECT id, signed_event FROM outbox ORDER BY id")?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows.iter()
+                .map(|(id, serialized)| Ok((*id, Signed::from_bytes(serialized)?)))
+                .collect()
+        })
+        .await
+    }
+
+    async fn dequeue_event(&self, id: i64) -> Result<()> {
+        self.run(move |connection| {
+            connection.execute("DELETE FROM outbox WHERE id = ?", (id,))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn discard_queued_event(&self, id: i64) -> Result<()> {
+        self.run(move |connection| {
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            transaction.execute(
+                "DELETE FROM events WHERE signed_event IN (SELECT signed_event FROM outbox WHERE id = ?)",
+                (id,),
+            )?;
+            transaction.execute("DELETE FROM outbox WHERE id = ?", (id,))?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_synced_at(&self, verifying_key: String, synced_at: u64) -> Result<()> {
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO synced (verifying_key, synced_at) VALUES (?, ?)
+                 ON CONFLICT(verifying_key) DO UPDATE SET synced_at = excluded.synced_at",
+                params![verifying_key.as_bytes(), synced_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_synced_at(&self, verifying_key: String) -> Result<Option<u64>> {
+        self.run(move |connection| {
+            let synced_at = connection
+                .query_row(
+                    "SELECT synced_at FROM synced WHERE verifying_key = ?",
+                    [verifying_key.as_bytes()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(synced_at)
+        })
+        .await
     }
 }
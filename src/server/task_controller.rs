@@ -1,22 +1,35 @@
+use std::sync::Arc;
+
 use tracing::warn;
 
 use crate::connectors::connection::Connection;
 
-use super::{data_controller::DataController, tasks};
+use super::{data_controller::DataController, metrics::Metrics, tasks};
 
 #[derive(bon::Builder)]
 pub struct TaskController {
     controller: DataController,
     #[builder(default)]
-    peer_connections: Vec<Connection>,
+    peer_connections: Arc<Vec<Connection>>,
+    /// Minimum stamp work required of replicated events
+    min_stamp_work: Option<u32>,
+    metrics: Metrics,
 }
 
 impl TaskController {
     pub async fn run_tasks(&self) -> anyhow::Result<()> {
-        tasks::gc_expired::run(&self.controller).await?;
+        tasks::gc_expired::run(&self.controller, &self.metrics).await?;
+        tasks::gc_history::run(&self.controller, &self.metrics).await?;
 
-        for connection in &self.peer_connections {
-            if let Err(e) = tasks::sync::run(&self.controller, connection).await {
+        for connection in self.peer_connections.iter() {
+            if let Err(e) = tasks::sync::run(
+                &self.controller,
+                connection,
+                self.min_stamp_work,
+                &self.metrics,
+            )
+            .await
+            {
                 warn!(
                     "Failed to synchronize with connection {}: {:?}",
                     connection.url(),
@@ -1,15 +1,13 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{
+    server::{data_controller::DataController, metrics::Metrics},
+    time::unix_timestamp,
+};
 
-use crate::server::data_controller::DataController;
-
-pub async fn run(controller: &DataController) -> anyhow::Result<()> {
-    let now = SystemTime::now();
-    let since_epoch = now
-        .duration_since(UNIX_EPOCH)
-        .expect("Error finding current epoch for expiry cleanup");
-    let unix_timestamp = since_epoch.as_secs();
+pub async fn run(controller: &DataController, metrics: &Metrics) -> anyhow::Result<()> {
+    let unix_timestamp = unix_timestamp();
 
     let num_events_deleted = controller.delete_expired_events(unix_timestamp).await?;
+    metrics.record_gc("expired", num_events_deleted);
     if num_events_deleted > 0 {
         tracing::debug!("Deleted {} expired events", num_events_deleted);
     }
@@ -0,0 +1,18 @@
+use crate::{
+    server::{data_controller::DataController, metrics::Metrics},
+    time::unix_timestamp,
+};
+
+pub async fn run(controller: &DataController, metrics: &Metrics) -> anyhow::Result<()> {
+    let unix_timestamp = unix_timestamp();
+
+    let num_events_deleted = controller.delete_old_history(unix_timestamp).await?;
+    metrics.record_gc("history", num_events_deleted);
+    if num_events_deleted > 0 {
+        tracing::debug!(
+            "Deleted {} events past the history retention",
+            num_events_deleted
+        );
+    }
+    Ok(())
+}
@@ -1,2 +1,3 @@
 pub mod gc_expired;
+pub mod gc_history;
 pub mod sync;
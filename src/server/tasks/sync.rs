@@ -1,11 +1,34 @@
+use std::time::Instant;
+
 use bincode::config::standard;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
-    api::StateHash, connectors::connection::Connection, server::data_controller::DataController,
+    api::StateHash,
+    client::Event,
+    connectors::connection::Connection,
+    crypto::Signed,
+    server::{data_controller::DataController, metrics::Metrics},
 };
 
-pub async fn run(controller: &DataController, connection: &Connection) -> anyhow::Result<()> {
+pub async fn run(
+    controller: &DataController,
+    connection: &Connection,
+    min_stamp_work: Option<u32>,
+    metrics: &Metrics,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let result = sync(controller, connection, min_stamp_work, metrics).await;
+    metrics.record_sync(connection.url(), started.elapsed(), result.is_ok());
+    result
+}
+
+async fn sync(
+    controller: &DataController,
+    connection: &Connection,
+    min_stamp_work: Option<u32>,
+    metrics: &Metrics,
+) -> anyhow::Result<()> {
     let last_sync_hash = controller.get_peer_last_hash(connection.url()).await;
     let other_state = connection.state_hash().await?;
     if last_sync_hash
@@ -16,22 +39,69 @@ pub async fn run(controller: &DataController, connection: &Connection) -> anyhow
         return Ok(());
     }
 
-    let other_events = connection.sync_events().await?;
+    // A node that has nothing yet bootstraps from a snapshot instead of the event list
+    let bootstrap = last_sync_hash.is_none() && controller.event_count().await? == 0;
+    let (other_events, events_hash) = if bootstrap {
+        match connection.snapshot(&other_state).await {
+            Ok(snapshot) => (snapshot.events, snapshot.state_hash),
+            Err(e) => {
+                debug!(
+                    "Falling back to incremental sync with {}: {:?}",
+                    connection.url(),
+                    e
+                );
+                sync_events(connection).await?
+            }
+        }
+    } else {
+        sync_events(connection).await?
+    };
     debug!(
         "Importing {} events from {}",
-        other_events.events.len(),
+        other_events.len(),
         connection.url()
     );
 
-    let serialized_events = bincode::encode_to_vec(&other_events.events, standard())?;
-    let events_hash = StateHash {
-        hash: blake3::hash(&serialized_events),
-    };
+    let (verified_events, rejected_events): (Vec<_>, Vec<_>) = other_events
+        .into_iter()
+        .partition(|event| event.verified_keyspace().is_some());
+    metrics.record_rejected("signature", rejected_events.len());
+    if !rejected_events.is_empty() {
+        warn!(
+            "Rejected {} events with invalid signatures from {}",
+            rejected_events.len(),
+            connection.url()
+        );
+    }
 
-    controller.insert_events(other_events.events).await?;
+    let (verified_events, unstamped_events): (Vec<_>, Vec<_>) =
+        verified_events.into_iter().partition(|event| {
+            min_stamp_work.is_none_or(|min_stamp_work| event.meets_stamp_work(min_stamp_work))
+        });
+    metrics.record_rejected("stamp_work", unstamped_events.len());
+    if !unstamped_events.is_empty() {
+        warn!(
+            "Rejected {} events without enough proof of work from {}",
+            unstamped_events.len(),
+            connection.url()
+        );
+    }
+
+    let inserted = controller.insert_events(verified_events).await?;
+    metrics.record_ingested("peer", inserted);
     controller
         .set_peer_last_hash(connection.url(), events_hash)
         .await?;
 
     Ok(())
 }
+
+/// The peer's events with the hash to remember them by
+async fn sync_events(connection: &Connection) -> anyhow::Result<(Vec<Signed<Event>>, StateHash)> {
+    let other_events = connection.sync_events().await?;
+    let serialized_events = bincode::encode_to_vec(&other_events.events, standard())?;
+    let events_hash = StateHash {
+        hash: blake3::hash(&serialized_events),
+    };
+    Ok((other_events.events, events_hash))
+}
@@ -0,0 +1,181 @@
+use baybridge::{
+    client::{Actions, Event, SetEvent, content::CHUNK_SIZE},
+    configuration::Configuration,
+    connectors::{connection::Connection, local::LocalConnection},
+    crypto::{CryptoKey, delegation::Delegation},
+    models::{Name, Value},
+    server::{
+        archive::{self, ArchiveFilter, ArchiveSummary},
+        node::Node,
+    },
+    time::unix_timestamp,
+};
+
+/// A node holding a small value from each of two keyspaces and a content tree from the first
+async fn populated_node(base_dir: &std::path::Path) -> (Node, CryptoKey) {
+    let node = Node::in_memory().unwrap();
+    let connection = LocalConnection::new(node.clone());
+    let alice = Actions::with_identity(
+        Configuration::new(
+            base_dir.to_path_buf(),
+            vec![Connection::Local(connection.clone())],
+        ),
+        CryptoKey::generate(),
+    );
+    let bob_key = CryptoKey::generate();
+    let bob = Actions::with_identity(
+        Configuration::new(base_dir.to_path_buf(), vec![Connection::Local(connection)]),
+        bob_key.clone(),
+    );
+
+    let pointer = alice.set_content(&vec![7; 2 * CHUNK_SIZE]).await.unwrap();
+    for (actions, name, value) in [
+        (&alice, "file", pointer.to_value()),
+        (&alice, "greeting", Value::new(b"hello".to_vec())),
+        (&bob, "greeting", Value::new(b"hi".to_vec())),
+    ] {
+        actions
+            .set()
+            .name(Name::new(name.to_string()))
+            .value(value)
+            .call()
+            .await
+            .unwrap();
+    }
+    (node, bob_key)
+}
+
+#[tokio::test]
+async fn imports_exported_archives() {
+    let base_dir = tempfile::tempdir().unwrap();
+    let (source, _) = populated_node(base_dir.path()).await;
+    let mut archive = Vec::new();
+    let exported = archive::export(&source, &ArchiveFilter::default(), &mut archive)
+        .await
+        .unwrap();
+    // The two identical chunks share one leaf block under the root
+    assert_eq!(
+        exported,
+        ArchiveSummary {
+            events: 3,
+            blocks: 2,
+            rejected_events: 0,
+        }
+    );
+
+    let target = Node::in_memory().unwrap();
+    let imported = archive::import(&target, archive.as_slice()).await.unwrap();
+    assert_eq!(imported, exported);
+    assert_eq!(
+        target.controller().current_state_hash().await.unwrap(),
+        source.controller().current_state_hash().await.unwrap()
+    );
+    assert_eq!(
+        target.immutable_controller().usage().await.unwrap(),
+        source.immutable_controller().usage().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn exports_only_matching_events_and_their_blocks() {
+    let base_dir = tempfile::tempdir().unwrap();
+    let (source, bob) = populated_node(base_dir.path()).await;
+    let filter = ArchiveFilter {
+        keys: vec![bob.verifying()],
+        ..Default::default()
+    };
+    let summary = archive::export(&source, &filter, &mut Vec::new())
+        .await
+        .unwrap();
+    assert_eq!((summary.events, summary.blocks), (1, 0));
+
+    let filter = ArchiveFilter {
+        namespaces: vec!["file".to_string()],
+        ..Default::default()
+    };
+    let summary = archive::export(&source, &filter, &mut Vec::new())
+        .await
+        .unwrap();
+    assert_eq!((summary.events, summary.blocks), (1, 2));
+}
+
+#[tokio::test]
+async fn refuses_corrupt_archives() {
+    let base_dir = tempfile::tempdir().unwrap();
+    let (source, _) = populated_node(base_dir.path()).await;
+    let mut archive = Vec::new();
+    archive::export(&source, &ArchiveFilter::default(), &mut archive)
+        .await
+        .unwrap();
+
+    let target = Node::in_memory().unwrap();
+    let truncated = &archive[..archive.len() - 1];
+    assert!(archive::import(&target, truncated).await.is_err());
+    assert!(
+        archive::import(&target, &b"not an archive"[..])
+            .await
+            .is_err()
+    );
+    // The archive ends with the trailer's checksum
+    let mut tampered = archive.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(archive::import(&target, tampered.as_slice()).await.is_err());
+}
+
+#[tokio::test]
+async fn keeps_events_read_before_corruption() {
+    let base_dir = tempfile::tempdir().unwrap();
+    let (source, _) = populated_node(base_dir.path()).await;
+    let mut archive = Vec::new();
+    archive::export(&source, &ArchiveFilter::default(), &mut archive)
+        .await
+        .unwrap();
+
+    // Far fewer events than fill a batch, followed by a trailer that does not match
+    let mut tampered = archive.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    let target = Node::in_memory().unwrap();
+    assert!(archive::import(&target, tampered.as_slice()).await.is_err());
+    assert_eq!(
+        target.controller().current_state_hash().await.unwrap(),
+        source.controller().current_state_hash().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn imports_events_from_since_expired_delegations() {
+    let mut owner = CryptoKey::generate();
+    let mut delegate = CryptoKey::generate();
+    let now = unix_timestamp();
+    let delegation = owner.sign(Delegation {
+        delegate: delegate.verifying().to_bytes(),
+        name_prefix: None,
+        expires_at: Some(now - 60),
+    });
+    let event = delegate
+        .sign(Event::Set(SetEvent::plain(
+            Name::new("greeting".to_string()),
+            Value::new(b"hello".to_vec()),
+            now - 120,
+            None,
+        )))
+        .with_delegation(delegation);
+    let source = Node::in_memory().unwrap();
+    source
+        .controller()
+        .insert_events(vec![event])
+        .await
+        .unwrap();
+    let mut archive = Vec::new();
+    archive::export(&source, &ArchiveFilter::default(), &mut archive)
+        .await
+        .unwrap();
+
+    let target = Node::in_memory().unwrap();
+    let imported = archive::import(&target, archive.as_slice()).await.unwrap();
+    assert_eq!(imported.rejected_events, 0);
+    assert_eq!(
+        target.controller().current_state_hash().await.unwrap(),
+        source.controller().current_state_hash().await.unwrap()
+    );
+}
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use baybridge::server::{conformance, memory_store::MemoryStore, sqlite_store::SqliteStore};
+
+#[tokio::test]
+async fn sqlite_store_conforms() {
+    conformance::run(|history_retention| {
+        SqliteStore::in_memory()
+            .unwrap()
+            .with_history_retention(history_retention)
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sqlite_file_store_conforms() {
+    let dir = tempfile::tempdir().unwrap();
+    let count = AtomicUsize::new(0);
+    conformance::run(|history_retention| {
+        let path = dir
+            .path()
+            .join(format!("{}.sqlite", count.fetch_add(1, Ordering::Relaxed)));
+        SqliteStore::new(&path)
+            .unwrap()
+            .with_history_retention(history_retention)
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn memory_store_conforms() {
+    conformance::run(|history_retention| {
+        MemoryStore::new().with_history_retention(history_retention)
+    })
+    .await;
+}
+
+#[test]
+fn concurrent_opens_migrate_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("shared.sqlite");
+    let openers = (0..4)
+        .map(|_| {
+            let path = path.clone();
+            std::thread::spawn(move || SqliteStore::new(&path).map(|_| ()))
+        })
+        .collect::<Vec<_>>();
+    for opener in openers {
+        opener.join().unwrap().unwrap();
+    }
+}
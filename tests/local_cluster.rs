@@ -0,0 +1,299 @@
+use baybridge::{
+    api::{MAX_KEYS_PER_QUERY, NamespaceQuery},
+    client::Actions,
+    configuration::Configuration,
+    connectors::{
+        connection::Connection,
+        http::HttpConnection,
+        local::{LocalCluster, LocalConnection},
+    },
+    crypto::{CryptoKey, encode::encode_verifying_key},
+    models::{Name, Value},
+    server::node::Node,
+    time::unix_timestamp,
+};
+
+#[tokio::test]
+async fn reads_writes_synced_to_another_node() {
+    let cluster = LocalCluster::new(2).unwrap();
+    let identity = CryptoKey::generate();
+    let keyspace = encode_verifying_key(&identity.verifying());
+    let writer_dir = tempfile::tempdir().unwrap();
+    let reader_dir = tempfile::tempdir().unwrap();
+    let writer = Actions::with_identity(
+        Configuration::new(writer_dir.path().to_path_buf(), vec![cluster.connection(0)]),
+        identity.clone(),
+    );
+    let reader = Actions::new(Configuration::new(
+        reader_dir.path().to_path_buf(),
+        vec![cluster.connection(1)],
+    ));
+    let name = Name::new("greeting".to_string());
+
+    writer
+        .set()
+        .name(name.clone())
+        .value(Value::new(b"hello".to_vec()))
+        .call()
+        .await
+        .unwrap();
+    assert!(
+        reader
+            .get()
+            .verifying_key(&keyspace)
+            .name(&name)
+            .call()
+            .await
+            .is_err()
+    );
+
+    cluster.sync(1, 0).await.unwrap();
+    let value = reader
+        .get()
+        .verifying_key(&keyspace)
+        .name(&name)
+        .call()
+        .await
+        .unwrap();
+    assert_eq!(value.as_bytes(), b"hello");
+}
+
+#[tokio::test]
+async fn fails_writes_the_node_rejects() {
+    // More stamp work than clients mint, so every write is refused
+    let node = Node::in_memory().unwrap().with_min_stamp_work(Some(64));
+    let connection = Connection::Local(LocalConnection::new(node));
+    let dir = tempfile::tempdir().unwrap();
+    let actions = Actions::with_identity(
+        Configuration::new(dir.path().to_path_buf(), vec![connection]),
+        CryptoKey::generate(),
+    );
+
+    let result = actions
+        .set()
+        .name(Name::new("greeting".to_string()))
+        .value(Value::new(b"hello".to_vec()))
+        .call()
+        .await;
+    assert!(result.is_err());
+    assert_eq!(actions.flush().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn limits_keys_per_namespace_query() {
+    let connection = LocalConnection::new(Node::in_memory().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let actions = Actions::with_identity(
+        Configuration::new(
+            dir.path().to_path_buf(),
+            vec![Connection::Local(connection.clone())],
+        ),
+        CryptoKey::generate(),
+    );
+    let keys = (0..=MAX_KEYS_PER_QUERY)
+        .map(|_| CryptoKey::generate().verifying())
+        .collect::<Vec<_>>();
+
+    let allowed = actions
+        .namespace()
+        .name("greeting")
+        .keys(keys[..MAX_KEYS_PER_QUERY].to_vec())
+        .call()
+        .await;
+    assert!(allowed.is_ok());
+    let query = NamespaceQuery {
+        keys: Some(
+            keys.iter()
+                .map(encode_verifying_key)
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        ..Default::default()
+    };
+    assert!(connection.namespace("greeting", &query).await.is_err());
+    assert!(
+        actions
+            .namespace()
+            .name("greeting")
+            .keys(keys)
+            .call()
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn filters_namespaces_by_received_time() {
+    let connection = Connection::Local(LocalConnection::new(Node::in_memory().unwrap()));
+    let dir = tempfile::tempdir().unwrap();
+    let actions = Actions::with_identity(
+        Configuration::new(dir.path().to_path_buf(), vec![connection]),
+        CryptoKey::generate(),
+    );
+    let before = unix_timestamp();
+    actions
+        .set()
+        .name(Name::new("greeting".to_string()))
+        .value(Value::new(b"hello".to_vec()))
+        .call()
+        .await
+        .unwrap();
+    let after = unix_timestamp() + 1;
+
+    for (since, expected) in [(before, 1), (after, 0)] {
+        let namespace = actions
+            .namespace()
+            .name("greeting")
+            .since(since)
+            .call()
+            .await
+            .unwrap();
+        assert_eq!(namespace.mapping.len(), expected);
+        let count = actions
+            .namespace_count()
+            .name("greeting")
+            .since(since)
+            .call()
+            .await
+            .unwrap();
+        assert_eq!(count, expected);
+    }
+}
+
+#[tokio::test]
+async fn fails_deletes_that_cannot_outrank_the_value() {
+    let connection = Connection::Local(LocalConnection::new(Node::in_memory().unwrap()));
+    let dir = tempfile::tempdir().unwrap();
+    let actions = Actions::with_identity(
+        Configuration::new(dir.path().to_path_buf(), vec![connection]),
+        CryptoKey::generate(),
+    );
+    let name = Name::new("greeting".to_string());
+
+    actions
+        .set()
+        .name(name.clone())
+        .value(Value::new(b"hello".to_vec()))
+        .priority(u64::MAX)
+        .call()
+        .await
+        .unwrap();
+    assert!(actions.delete(name).await.is_err());
+}
+
+#[tokio::test]
+async fn fails_conditional_writes_while_offline() {
+    // Nothing listens on port 1, so no server is reachable
+    let connection = Connection::Http(HttpConnection::new(
+        url::Url::parse("http://127.0.0.1:1").unwrap(),
+    ));
+    let dir = tempfile::tempdir().unwrap();
+    let actions = Actions::with_identity(
+        Configuration::new(dir.path().to_path_buf(), vec![connection]),
+        CryptoKey::generate(),
+    );
+    let name = Name::new("counter".to_string());
+
+    let result = actions
+        .cas(name.clone(), |_| Ok(Value::new(b"1".to_vec())))
+        .await;
+    assert!(result.is_err());
+    actions
+        .set()
+        .name(name)
+        .value(Value::new(b"1".to_vec()))
+        .call()
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn applies_conditional_writes_once_across_out_of_sync_nodes() {
+    let cluster = LocalCluster::new(2).unwrap();
+    let identity = CryptoKey::generate();
+    let keyspace = encode_verifying_key(&identity.verifying());
+    let first_dir = tempfile::tempdir().unwrap();
+    let both_dir = tempfile::tempdir().unwrap();
+    let name = Name::new("counter".to_string());
+
+    // Only the first node holds the current value, so the second refuses a write replacing it
+    Actions::with_identity(
+        Configuration::new(first_dir.path().to_path_buf(), vec![cluster.connection(0)]),
+        identity.clone(),
+    )
+    .set()
+    .name(name.clone())
+    .value(Value::new(b"1".to_vec()))
+    .call()
+    .await
+    .unwrap();
+    let actions = Actions::with_identity(
+        Configuration::new(both_dir.path().to_path_buf(), cluster.connections()),
+        identity,
+    );
+    let mut updates = 0;
+    let value = actions
+        .cas(name.clone(), |current| {
+            updates += 1;
+            let current: u64 = String::from_utf8(current.unwrap().as_bytes().to_vec())
+                .unwrap()
+                .parse()
+                .unwrap();
+            Ok(Value::new((current + 1).to_string().into_bytes()))
+        })
+        .await
+        .unwrap();
+    assert_eq!(value.as_bytes(), b"2");
+    assert_eq!(updates, 1);
+
+    cluster.sync_all().await.unwrap();
+    let value = actions
+        .get()
+        .verifying_key(&keyspace)
+        .name(&name)
+        .call()
+        .await
+        .unwrap();
+    assert_eq!(value.as_bytes(), b"2");
+}
+
+#[tokio::test]
+async fn discards_queued_writes_the_node_rejects() {
+    let dir = tempfile::tempdir().unwrap();
+    let identity = CryptoKey::generate();
+    let keyspace = encode_verifying_key(&identity.verifying());
+    let name = Name::new("greeting".to_string());
+    let offline = Connection::Http(HttpConnection::new(
+        url::Url::parse("http://127.0.0.1:1").unwrap(),
+    ));
+    Actions::with_identity(
+        Configuration::new(dir.path().to_path_buf(), vec![offline]),
+        identity.clone(),
+    )
+    .set()
+    .name(name.clone())
+    .value(Value::new(b"hello".to_vec()))
+    .call()
+    .await
+    .unwrap();
+
+    // The same replica, now reaching a node that refuses the queued write
+    let node = Node::in_memory().unwrap().with_min_stamp_work(Some(64));
+    let actions = Actions::with_identity(
+        Configuration::new(
+            dir.path().to_path_buf(),
+            vec![Connection::Local(LocalConnection::new(node))],
+        ),
+        identity,
+    );
+    assert_eq!(actions.flush().await.unwrap(), 0);
+    assert!(
+        actions
+            .get()
+            .verifying_key(&keyspace)
+            .name(&name)
+            .call()
+            .await
+            .is_err()
+    );
+}